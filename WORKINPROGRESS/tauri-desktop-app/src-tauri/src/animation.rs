@@ -0,0 +1,1106 @@
+//! Animated GIF and APNG sources.
+//!
+//! `cwebp` only ever read the first frame, so animations silently became
+//! stills. Animated sources are now decoded frame by frame and written as
+//! animated WebP; the other output formats, and the vision model, get one
+//! representative frame.
+//!
+//! Frames are kept as the source stores them: a sub-rectangle of the
+//! canvas, drawn over or in place of what is there, and disposed of in one
+//! of three ways before the next frame. WebP has the first two disposals,
+//! leaving the frame and clearing its rectangle, so those are copied as
+//! they are. WebP cannot restore the previous canvas; the frame after one
+//! that does covers the restored area itself.
+//!
+//! Pixels are only decoded when they are needed: still outputs stop at the
+//! representative frame, which is picked from the delays alone.
+
+use std::io::Cursor;
+
+use image::error::{DecodingError, ImageFormatHint};
+use image::imageops::{self, FilterType};
+use image::metadata::Orientation;
+use image::{DynamicImage, GrayAlphaImage, GrayImage, ImageError, ImageFormat, ImageResult};
+use image::{RgbImage, RgbaImage};
+
+use crate::convert::{ConvertError, ConvertOptions, OutputFormat, WEBP_MAX_DIMENSION};
+
+/// GIF delays below this are played back at [`GIF_DEFAULT_DELAY_MS`] by
+/// every browser, so they are converted to it rather than copied.
+const GIF_MIN_DELAY_MS: u32 = 20;
+const GIF_DEFAULT_DELAY_MS: u32 = 100;
+
+/// Largest loop count a WebP can store.
+const MAX_LOOP_COUNT: u32 = 0xFFFF;
+/// Largest frame duration a WebP can store.
+const MAX_DURATION_MS: u32 = 0xFF_FFFF;
+
+/// What happens to a frame's rectangle before the next frame is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposal {
+    /// The frame stays on the canvas.
+    Keep,
+    /// The rectangle is cleared to transparent.
+    Background,
+    /// The rectangle goes back to what it was before the frame.
+    Previous,
+}
+
+pub struct Frame {
+    /// The pixels the frame draws, at `left`, `top` on the canvas.
+    pub image: RgbaImage,
+    pub left: u32,
+    pub top: u32,
+    pub delay_ms: u32,
+    pub disposal: Disposal,
+    /// Whether the frame is alpha-blended onto the canvas rather than
+    /// replacing the pixels under it.
+    pub blend: bool,
+}
+
+impl Frame {
+    fn rect(&self) -> Rect {
+        Rect {
+            left: self.left,
+            top: self.top,
+            width: self.image.width(),
+            height: self.image.height(),
+        }
+    }
+}
+
+/// A decoded animation.
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<Frame>,
+    /// Number of times the animation is played, with 0 meaning forever, as
+    /// in WebP.
+    pub loop_count: u32,
+}
+
+impl Animation {
+    /// Decodes every frame of `data` if it is an animated GIF or APNG with
+    /// more than one frame. Returns `None` for still images, which go
+    /// through the normal decoder.
+    pub fn decode(data: &[u8], format: ImageFormat) -> ImageResult<Option<Self>> {
+        match frame_delays(data, format)? {
+            Some(delays_ms) if delays_ms.len() > 1 => {
+                decode_frames(data, format, usize::MAX).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The representative frame of `data`, if it is an animated GIF or APNG
+    /// with more than one frame, decoding no further than that frame.
+    pub fn decode_representative(
+        data: &[u8],
+        format: ImageFormat,
+    ) -> ImageResult<Option<RgbaImage>> {
+        let index = match frame_delays(data, format)? {
+            Some(delays_ms) if delays_ms.len() > 1 => representative_index(&delays_ms),
+            _ => return Ok(None),
+        };
+        let animation = decode_frames(data, format, index + 1)?;
+        Ok(animation.canvases().last())
+    }
+
+    /// The canvas as shown at the representative frame.
+    pub fn representative(&self) -> RgbaImage {
+        let delays: Vec<u32> = self.frames.iter().map(|frame| frame.delay_ms).collect();
+        let index = representative_index(&delays);
+        self.canvases()
+            .nth(index)
+            .unwrap_or_else(|| RgbaImage::new(self.width, self.height))
+    }
+
+    /// Plays the animation, yielding the canvas as shown after each frame.
+    pub fn canvases(&self) -> impl Iterator<Item = RgbaImage> + '_ {
+        let mut canvas = RgbaImage::new(self.width, self.height);
+        // What to put back over the last frame before drawing the next.
+        let mut undo: Option<(Rect, RgbaImage)> = None;
+        self.frames.iter().map(move |frame| {
+            if let Some((rect, patch)) = undo.take() {
+                imageops::replace(&mut canvas, &patch, rect.left.into(), rect.top.into());
+            }
+            let rect = frame.rect();
+            undo = match frame.disposal {
+                Disposal::Keep => None,
+                Disposal::Background => Some((rect, RgbaImage::new(rect.width, rect.height))),
+                Disposal::Previous => Some((rect, rect.crop(&canvas))),
+            };
+            let (x, y) = (frame.left.into(), frame.top.into());
+            if frame.blend {
+                imageops::overlay(&mut canvas, &frame.image, x, y);
+            } else {
+                imageops::replace(&mut canvas, &frame.image, x, y);
+            }
+            canvas.clone()
+        })
+    }
+
+    /// Turns the canvas and every frame upright.
+    pub fn orient(self, orientation: Orientation) -> Self {
+        let (width, height) = (self.width, self.height);
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let rect = frame.rect().orient(orientation, width, height);
+                let mut image = DynamicImage::ImageRgba8(frame.image);
+                image.apply_orientation(orientation);
+                Frame {
+                    image: image.into_rgba8(),
+                    left: rect.left,
+                    top: rect.top,
+                    ..frame
+                }
+            })
+            .collect();
+        let swapped = matches!(
+            orientation,
+            Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH
+        );
+        let (width, height) = if swapped {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        Self {
+            width,
+            height,
+            frames,
+            ..self
+        }
+    }
+
+    /// Applies `f` to the pixels of every frame, such as the colour
+    /// handling the still images get. `f` must keep the dimensions.
+    pub fn map_frames(self, f: impl Fn(DynamicImage) -> DynamicImage) -> Self {
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| Frame {
+                image: f(DynamicImage::ImageRgba8(frame.image)).to_rgba8(),
+                ..frame
+            })
+            .collect();
+        Self { frames, ..self }
+    }
+
+    /// Encodes the animation as an animated WebP of `width`x`height`.
+    ///
+    /// At the source's size each frame is written as its own rectangle,
+    /// with its blending and disposal. Scaled down, the canvases are
+    /// resized and each frame becomes the scaled rectangle plus the reach
+    /// of the resampling filter, so no seams show where frames meet.
+    pub fn encode_webp(
+        &self,
+        width: u32,
+        height: u32,
+        options: &ConvertOptions,
+    ) -> Result<Vec<u8>, ConvertError> {
+        let error = |reason: String| ConvertError::Encode {
+            format: OutputFormat::Webp,
+            reason,
+        };
+        if width == 0 || height == 0 || width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+            return Err(error(format!(
+                "{width}x{height} is outside the WebP size limits"
+            )));
+        }
+        let scaled = (width, height) != (self.width, self.height);
+        let scale = |rect: Rect| rect.scale((self.width, self.height), (width, height));
+        let canvas = Rect {
+            left: 0,
+            top: 0,
+            width,
+            height,
+        };
+
+        let mut frames = Vec::new();
+        let mut has_alpha = false;
+        // The area the last frame left for this one to redraw, when WebP
+        // cannot dispose of it the way the source does.
+        let mut pending: Option<Rect> = None;
+        for (frame, shown) in self.frames.iter().zip(self.canvases()) {
+            let bounds = if scaled {
+                scale(frame.rect())
+            } else {
+                frame.rect()
+            };
+            let rect = pending
+                .map_or(bounds, |pending| bounds.union(pending))
+                .even()
+                .intersect(canvas);
+            let own = !scaled && rect == frame.rect();
+            let image = if own {
+                frame.image.clone()
+            } else if scaled {
+                rect.crop(&imageops::resize(
+                    &shown,
+                    width,
+                    height,
+                    FilterType::Lanczos3,
+                ))
+            } else {
+                rect.crop(&shown)
+            };
+            has_alpha |= image.pixels().any(|px| px[3] < 255);
+            frames.push(WebpFrame {
+                data: encode_still(&image, options).map_err(error)?,
+                rect,
+                duration_ms: frame.delay_ms.min(MAX_DURATION_MS),
+                blend: own && frame.blend,
+                dispose: frame.disposal == Disposal::Background,
+            });
+            pending = match frame.disposal {
+                Disposal::Keep => None,
+                Disposal::Background if own => None,
+                _ => Some(rect),
+            };
+        }
+        has_alpha |= frames.first().is_some_and(|frame| frame.rect != canvas);
+        Ok(mux(width, height, self.loop_count, has_alpha, &frames))
+    }
+}
+
+/// A sub-rectangle of the canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rect {
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn right(self) -> u32 {
+        self.left + self.width
+    }
+
+    fn bottom(self) -> u32 {
+        self.top + self.height
+    }
+
+    fn from_edges(left: u32, top: u32, right: u32, bottom: u32) -> Self {
+        Self {
+            left,
+            top,
+            width: right.saturating_sub(left),
+            height: bottom.saturating_sub(top),
+        }
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self::from_edges(
+            self.left.min(other.left),
+            self.top.min(other.top),
+            self.right().max(other.right()),
+            self.bottom().max(other.bottom()),
+        )
+    }
+
+    fn intersect(self, other: Self) -> Self {
+        Self::from_edges(
+            self.left.max(other.left),
+            self.top.max(other.top),
+            self.right().min(other.right()),
+            self.bottom().min(other.bottom()),
+        )
+    }
+
+    /// Grown left and up to even offsets, the only ones WebP can store.
+    fn even(self) -> Self {
+        Self::from_edges(self.left & !1, self.top & !1, self.right(), self.bottom())
+    }
+
+    /// The rectangle of a canvas of `from` scaled to `to`, grown by the
+    /// reach of Lanczos3: 3 pixels of the smaller of the two images.
+    fn scale(self, from: (u32, u32), to: (u32, u32)) -> Self {
+        let edges = |start: u32, end: u32, from: u32, to: u32| {
+            let factor = f64::from(to) / f64::from(from);
+            let reach = (3.0 * factor.max(1.0)).ceil() + 1.0;
+            let start = (f64::from(start) * factor - reach).floor().max(0.0);
+            let end = (f64::from(end) * factor + reach).ceil().min(f64::from(to));
+            (start as u32, end as u32)
+        };
+        let (left, right) = edges(self.left, self.right(), from.0, to.0);
+        let (top, bottom) = edges(self.top, self.bottom(), from.1, to.1);
+        Self::from_edges(left, top, right, bottom)
+    }
+
+    /// Where the rectangle ends up once a canvas of `width`x`height` is
+    /// turned by `orientation`, as [`DynamicImage::apply_orientation`]
+    /// turns it.
+    fn orient(self, orientation: Orientation, width: u32, height: u32) -> Self {
+        let flipped_x = width - self.right();
+        let flipped_y = height - self.bottom();
+        let (left, top) = match orientation {
+            Orientation::NoTransforms => (self.left, self.top),
+            Orientation::Rotate90 => (flipped_y, self.left),
+            Orientation::Rotate180 => (flipped_x, flipped_y),
+            Orientation::Rotate270 => (self.top, flipped_x),
+            Orientation::FlipHorizontal => (flipped_x, self.top),
+            Orientation::FlipVertical => (self.left, flipped_y),
+            Orientation::Rotate90FlipH => (self.top, self.left),
+            Orientation::Rotate270FlipH => (flipped_y, flipped_x),
+        };
+        let (width, height) = match orientation {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (self.height, self.width),
+            _ => (self.width, self.height),
+        };
+        Self {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    fn crop(self, image: &RgbaImage) -> RgbaImage {
+        imageops::crop_imm(image, self.left, self.top, self.width, self.height).to_image()
+    }
+}
+
+/// The delay of each frame of `data` if it is a GIF or an APNG, read
+/// without decoding any pixels.
+fn frame_delays(data: &[u8], format: ImageFormat) -> ImageResult<Option<Vec<u32>>> {
+    match format {
+        ImageFormat::Gif => {
+            let mut options = gif::DecodeOptions::new();
+            options.skip_frame_decoding(true);
+            let mut decoder = options
+                .read_info(Cursor::new(data))
+                .map_err(|e| decoding(format, e))?;
+            let mut delays_ms = Vec::new();
+            while let Some(frame) = decoder.read_next_frame().map_err(|e| decoding(format, e))? {
+                delays_ms.push(gif_delay(frame.delay));
+            }
+            Ok(Some(delays_ms))
+        }
+        ImageFormat::Png => Ok(apng_delays(data)),
+        _ => Ok(None),
+    }
+}
+
+/// The frame that stands for the whole animation: the one shown the
+/// longest, or the middle one if all are shown equally long. The first
+/// frame is often a blank or a title card.
+fn representative_index(delays_ms: &[u32]) -> usize {
+    let longest = delays_ms.iter().max();
+    if delays_ms.iter().all(|delay| Some(delay) == longest) {
+        delays_ms.len() / 2
+    } else {
+        delays_ms
+            .iter()
+            .position(|delay| Some(delay) == longest)
+            .unwrap_or(0)
+    }
+}
+
+/// Decodes the first `limit` frames of a GIF or APNG.
+fn decode_frames(data: &[u8], format: ImageFormat, limit: usize) -> ImageResult<Animation> {
+    match format {
+        ImageFormat::Gif => decode_gif(data, limit),
+        _ => decode_apng(data, limit),
+    }
+}
+
+fn decoding(
+    format: ImageFormat,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), e))
+}
+
+fn decode_gif(data: &[u8], limit: usize) -> ImageResult<Animation> {
+    let error = |e: gif::DecodingError| decoding(ImageFormat::Gif, e);
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(Cursor::new(data)).map_err(error)?;
+    let (width, height) = (u32::from(decoder.width()), u32::from(decoder.height()));
+    let mut frames = Vec::new();
+    while frames.len() < limit {
+        let Some(frame) = decoder.read_next_frame().map_err(error)? else {
+            break;
+        };
+        let image = RgbaImage::from_raw(
+            frame.width.into(),
+            frame.height.into(),
+            frame.buffer.to_vec(),
+        )
+        .ok_or_else(|| decoding(ImageFormat::Gif, "frame data is truncated"))?;
+        frames.push(clip(
+            Frame {
+                image,
+                left: frame.left.into(),
+                top: frame.top.into(),
+                delay_ms: gif_delay(frame.delay),
+                disposal: match frame.dispose {
+                    gif::DisposalMethod::Any | gif::DisposalMethod::Keep => Disposal::Keep,
+                    gif::DisposalMethod::Background => Disposal::Background,
+                    gif::DisposalMethod::Previous => Disposal::Previous,
+                },
+                blend: true,
+            },
+            width,
+            height,
+        ));
+    }
+    Ok(Animation {
+        width,
+        height,
+        frames,
+        loop_count: gif_loop_count(decoder.repeat()),
+    })
+}
+
+fn decode_apng(data: &[u8], limit: usize) -> ImageResult<Animation> {
+    let error = |e: png::DecodingError| decoding(ImageFormat::Png, e);
+    let mut decoder = png::Decoder::new(Cursor::new(data));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(error)?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    let Some(control) = reader.info().animation_control else {
+        return Err(decoding(ImageFormat::Png, "no acTL chunk"));
+    };
+    if reader.info().frame_control.is_none() {
+        // The IDAT image is only for viewers that do not play APNGs.
+        reader.next_frame_info().map_err(error)?;
+    }
+    let size = reader
+        .output_buffer_size()
+        .ok_or_else(|| error(png::DecodingError::LimitsExceeded))?;
+    let mut buf = vec![0; size];
+    let mut frames = Vec::new();
+    while frames.len() < limit.min(control.num_frames as usize) {
+        let output = reader.next_frame(&mut buf).map_err(error)?;
+        let control = *reader
+            .info()
+            .frame_control()
+            .ok_or_else(|| decoding(ImageFormat::Png, "frame without fcTL"))?;
+        let pixels = buf[..output.buffer_size()].to_vec();
+        let (w, h) = (output.width, output.height);
+        let image = match output.color_type {
+            png::ColorType::Grayscale => GrayImage::from_raw(w, h, pixels).map(DynamicImage::from),
+            png::ColorType::GrayscaleAlpha => {
+                GrayAlphaImage::from_raw(w, h, pixels).map(DynamicImage::from)
+            }
+            png::ColorType::Rgb => RgbImage::from_raw(w, h, pixels).map(DynamicImage::from),
+            _ => RgbaImage::from_raw(w, h, pixels).map(DynamicImage::from),
+        }
+        .ok_or_else(|| decoding(ImageFormat::Png, "frame data is truncated"))?;
+        frames.push(clip(
+            Frame {
+                image: image.into_rgba8(),
+                left: control.x_offset,
+                top: control.y_offset,
+                delay_ms: apng_delay(control.delay_num, control.delay_den),
+                disposal: match control.dispose_op {
+                    png::DisposeOp::None => Disposal::Keep,
+                    png::DisposeOp::Background => Disposal::Background,
+                    // The first frame has nothing to go back to.
+                    png::DisposeOp::Previous if frames.is_empty() => Disposal::Background,
+                    png::DisposeOp::Previous => Disposal::Previous,
+                },
+                blend: control.blend_op == png::BlendOp::Over,
+            },
+            width,
+            height,
+        ));
+    }
+    Ok(Animation {
+        width,
+        height,
+        frames,
+        // The play count already means the same as in WebP.
+        loop_count: control.num_plays.min(MAX_LOOP_COUNT),
+    })
+}
+
+/// Cuts off the parts of `frame` outside the canvas, which GIFs allow. A
+/// frame entirely outside becomes a transparent pixel, which changes
+/// nothing.
+fn clip(frame: Frame, width: u32, height: u32) -> Frame {
+    let canvas = Rect {
+        left: 0,
+        top: 0,
+        width,
+        height,
+    };
+    let rect = frame.rect().intersect(canvas);
+    if rect == frame.rect() {
+        return frame;
+    }
+    if rect.width == 0 || rect.height == 0 {
+        return Frame {
+            image: RgbaImage::new(1, 1),
+            left: 0,
+            top: 0,
+            disposal: Disposal::Keep,
+            blend: true,
+            ..frame
+        };
+    }
+    let inside = Rect {
+        left: rect.left - frame.left,
+        top: rect.top - frame.top,
+        ..rect
+    };
+    Frame {
+        image: inside.crop(&frame.image),
+        left: rect.left,
+        top: rect.top,
+        ..frame
+    }
+}
+
+fn gif_delay(centiseconds: u16) -> u32 {
+    match u32::from(centiseconds) * 10 {
+        delay if delay < GIF_MIN_DELAY_MS => GIF_DEFAULT_DELAY_MS,
+        delay => delay,
+    }
+}
+
+/// The loop count from a GIF's `NETSCAPE2.0` extension, in WebP terms. A
+/// GIF without one plays once; one with a count of `n` repeats `n` times
+/// after the first play.
+fn gif_loop_count(repeat: gif::Repeat) -> u32 {
+    match repeat {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(n) => (u32::from(n) + 1).min(MAX_LOOP_COUNT),
+    }
+}
+
+/// A delay of `numerator / denominator` seconds, where a denominator of 0
+/// means hundredths.
+fn apng_delay(numerator: u16, denominator: u16) -> u32 {
+    let denominator = if denominator == 0 { 100 } else { denominator };
+    u32::from(numerator) * 1000 / u32::from(denominator)
+}
+
+/// The chunks of a PNG file, as type and data, up to `IEND` or the first
+/// truncated chunk.
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = data.strip_prefix(b"\x89PNG\r\n\x1a\n").unwrap_or(&[]);
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let kind = rest.get(4..8)?;
+        let body = rest.get(8..8usize.checked_add(len)?)?;
+        // Past the data and its CRC.
+        rest = rest.get(12 + len..)?;
+        Some((kind, body)).filter(|(kind, _)| *kind != b"IEND")
+    })
+}
+
+/// The delays from an APNG's `fcTL` chunks, or `None` for PNGs without an
+/// `acTL` chunk.
+fn apng_delays(data: &[u8]) -> Option<Vec<u32>> {
+    let mut animated = false;
+    let mut delays_ms = Vec::new();
+    for (kind, body) in png_chunks(data) {
+        match kind {
+            b"acTL" => animated = true,
+            b"fcTL" if body.len() >= 26 => delays_ms.push(apng_delay(
+                u16::from_be_bytes([body[20], body[21]]),
+                u16::from_be_bytes([body[22], body[23]]),
+            )),
+            _ => {}
+        }
+    }
+    animated.then_some(delays_ms)
+}
+
+/// A frame of an animated WebP being written.
+struct WebpFrame {
+    /// The `ALPH`, `VP8 ` and `VP8L` chunks of the frame's image.
+    data: Vec<u8>,
+    rect: Rect,
+    duration_ms: u32,
+    blend: bool,
+    dispose: bool,
+}
+
+/// Encodes `image` as a still WebP and returns the chunks that hold it.
+fn encode_still(image: &RgbaImage, options: &ConvertOptions) -> Result<Vec<u8>, String> {
+    let encoder = webp::Encoder::from_rgba(image, image.width(), image.height());
+    let webp = encoder
+        .encode_simple(options.lossless, options.quality.min(100) as f32)
+        .map_err(|e| format!("{e:?}"))?;
+    let mut data = Vec::new();
+    let mut rest = webp.get(12..).unwrap_or(&[]);
+    while rest.len() >= 8 {
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let end = (8 + len + len % 2).min(rest.len());
+        if matches!(&rest[..4], b"ALPH" | b"VP8 " | b"VP8L") {
+            data.extend_from_slice(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    Ok(data)
+}
+
+fn push_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Writes an animated WebP file: a `VP8X` header, an `ANIM` chunk and one
+/// `ANMF` chunk per frame. The background is transparent, which is what
+/// frames disposed of are cleared to.
+fn mux(width: u32, height: u32, loop_count: u32, has_alpha: bool, frames: &[WebpFrame]) -> Vec<u8> {
+    const ANIMATION: u8 = 0x02;
+    const ALPHA: u8 = 0x10;
+    const DISPOSE: u8 = 0x01;
+    const NO_BLEND: u8 = 0x02;
+
+    let mut chunks = Vec::new();
+    let mut vp8x = vec![
+        if has_alpha {
+            ANIMATION | ALPHA
+        } else {
+            ANIMATION
+        },
+        0,
+        0,
+        0,
+    ];
+    push_u24(&mut vp8x, width - 1);
+    push_u24(&mut vp8x, height - 1);
+    push_chunk(&mut chunks, b"VP8X", &vp8x);
+
+    let mut anim = vec![0; 4];
+    anim.extend_from_slice(&(loop_count as u16).to_le_bytes());
+    push_chunk(&mut chunks, b"ANIM", &anim);
+
+    for frame in frames {
+        let mut anmf = Vec::with_capacity(16 + frame.data.len());
+        push_u24(&mut anmf, frame.rect.left / 2);
+        push_u24(&mut anmf, frame.rect.top / 2);
+        push_u24(&mut anmf, frame.rect.width - 1);
+        push_u24(&mut anmf, frame.rect.height - 1);
+        push_u24(&mut anmf, frame.duration_ms);
+        let mut flags = 0;
+        if frame.dispose {
+            flags |= DISPOSE;
+        }
+        if !frame.blend {
+            flags |= NO_BLEND;
+        }
+        anmf.push(flags);
+        anmf.extend_from_slice(&frame.data);
+        push_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    let mut webp = Vec::with_capacity(12 + chunks.len());
+    webp.extend_from_slice(b"RIFF");
+    webp.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+    webp.extend_from_slice(b"WEBP");
+    webp.extend_from_slice(&chunks);
+    webp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+    const CLEAR: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+    fn rect(left: u32, top: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    /// A GIF whose frames each fill `rect` with a palette colour, except for
+    /// their top-left pixel, which is transparent.
+    fn gif(
+        size: (u16, u16),
+        frames: &[(Rect, u8, u16, gif::DisposalMethod)],
+        repeat: Option<gif::Repeat>,
+    ) -> Vec<u8> {
+        // Transparent, red, green, blue.
+        let palette = [0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let mut data = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut data, size.0, size.1, &palette).unwrap();
+        if let Some(repeat) = repeat {
+            encoder.set_repeat(repeat).unwrap();
+        }
+        for &(rect, colour, delay, dispose) in frames {
+            let mut buffer = vec![colour; (rect.width * rect.height) as usize];
+            buffer[0] = 0;
+            encoder
+                .write_frame(&gif::Frame {
+                    left: rect.left as u16,
+                    top: rect.top as u16,
+                    width: rect.width as u16,
+                    height: rect.height as u16,
+                    delay,
+                    dispose,
+                    transparent: Some(0),
+                    buffer: buffer.into(),
+                    ..gif::Frame::default()
+                })
+                .unwrap();
+        }
+        drop(encoder);
+        data
+    }
+
+    /// Frames with every disposal, one at an odd offset and one too short
+    /// for browsers.
+    fn disposals_gif() -> Vec<u8> {
+        use gif::DisposalMethod::{Background, Keep, Previous};
+        gif(
+            (10, 10),
+            &[
+                (rect(0, 0, 10, 10), 1, 10, Keep),
+                (rect(2, 2, 4, 4), 3, 1, Background),
+                (rect(3, 3, 3, 3), 2, 50, Previous),
+                (rect(6, 0, 4, 4), 3, 20, Keep),
+            ],
+            Some(gif::Repeat::Infinite),
+        )
+    }
+
+    type ApngFrame = (Rect, Rgba<u8>, (u16, u16), png::DisposeOp, png::BlendOp);
+
+    /// An APNG whose frames each fill `rect` with one colour.
+    fn apng(size: (u32, u32), frames: &[ApngFrame], plays: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, size.0, size.1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frames.len() as u32, plays).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        for &(rect, colour, (numerator, denominator), dispose, blend) in frames {
+            writer.set_frame_dimension(rect.width, rect.height).unwrap();
+            writer.set_frame_position(rect.left, rect.top).unwrap();
+            writer.set_frame_delay(numerator, denominator).unwrap();
+            writer.set_dispose_op(dispose).unwrap();
+            writer.set_blend_op(blend).unwrap();
+            let image = RgbaImage::from_pixel(rect.width, rect.height, colour);
+            writer.write_image_data(&image).unwrap();
+        }
+        writer.finish().unwrap();
+        data
+    }
+
+    fn disposals_apng(plays: u32) -> Vec<u8> {
+        use png::{BlendOp, DisposeOp};
+        apng(
+            (6, 4),
+            &[
+                (
+                    rect(0, 0, 6, 4),
+                    RED,
+                    (1, 10),
+                    DisposeOp::None,
+                    BlendOp::Source,
+                ),
+                (
+                    rect(2, 0, 2, 2),
+                    Rgba([0, 0, 255, 128]),
+                    (1, 3),
+                    DisposeOp::Background,
+                    BlendOp::Over,
+                ),
+                (
+                    rect(0, 2, 2, 2),
+                    GREEN,
+                    (25, 0),
+                    DisposeOp::Previous,
+                    BlendOp::Source,
+                ),
+                (
+                    rect(4, 2, 2, 2),
+                    BLUE,
+                    (1, 10),
+                    DisposeOp::None,
+                    BlendOp::Over,
+                ),
+            ],
+            plays,
+        )
+    }
+
+    fn decode(data: &[u8], format: ImageFormat) -> Animation {
+        Animation::decode(data, format).unwrap().unwrap()
+    }
+
+    /// Whether the pixels are the same within rounding, with all fully
+    /// transparent pixels alike.
+    fn same(a: &RgbaImage, b: &RgbaImage) -> bool {
+        a.dimensions() == b.dimensions()
+            && a.pixels().zip(b.pixels()).all(|(a, b)| {
+                (a[3] == 0 && b[3] == 0) || a.0.iter().zip(b.0).all(|(&a, b)| a.abs_diff(b) <= 1)
+            })
+    }
+
+    /// The canvases and end times libwebp plays `webp` with, and its loop
+    /// count.
+    fn play_webp(webp: &[u8]) -> (Vec<RgbaImage>, Vec<i32>, u32) {
+        let animation = webp::AnimDecoder::new(webp).decode().unwrap();
+        let (canvases, times) = (0..animation.len())
+            .map(|i| {
+                let frame = animation.get_frame(i).unwrap();
+                let image =
+                    RgbaImage::from_raw(frame.width(), frame.height(), frame.get_image().to_vec());
+                (image.unwrap(), frame.get_time_ms())
+            })
+            .unzip();
+        (canvases, times, animation.loop_count)
+    }
+
+    /// The rectangle and flags of each `ANMF` chunk of `webp`.
+    fn anmf_frames(webp: &[u8]) -> Vec<(Rect, u8)> {
+        let u24 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], 0]);
+        let mut frames = Vec::new();
+        let mut rest = &webp[12..];
+        while rest.len() >= 8 {
+            let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            if &rest[..4] == b"ANMF" {
+                let body = &rest[8..];
+                let rect = rect(
+                    u24(&body[0..]) * 2,
+                    u24(&body[3..]) * 2,
+                    u24(&body[6..]) + 1,
+                    u24(&body[9..]) + 1,
+                );
+                frames.push((rect, body[15]));
+            }
+            rest = &rest[8 + len + len % 2..];
+        }
+        frames
+    }
+
+    fn lossless() -> ConvertOptions {
+        ConvertOptions {
+            lossless: true,
+            ..ConvertOptions::default()
+        }
+    }
+
+    #[test]
+    fn reads_loop_counts() {
+        let frames = [
+            (rect(0, 0, 2, 2), 1, 10, gif::DisposalMethod::Keep),
+            (rect(0, 0, 2, 2), 2, 10, gif::DisposalMethod::Keep),
+        ];
+        let repeats = [
+            (None, 1),
+            (Some(gif::Repeat::Finite(2)), 3),
+            (Some(gif::Repeat::Infinite), 0),
+        ];
+        for (repeat, loop_count) in repeats {
+            let data = gif((2, 2), &frames, repeat);
+            let animation = decode(&data, ImageFormat::Gif);
+            assert_eq!(animation.loop_count, loop_count, "{repeat:?}");
+            let webp = animation.encode_webp(2, 2, &lossless()).unwrap();
+            assert_eq!(play_webp(&webp).2, loop_count, "{repeat:?}");
+        }
+        for plays in [0, 3] {
+            let animation = decode(&disposals_apng(plays), ImageFormat::Png);
+            assert_eq!(animation.loop_count, plays);
+            let webp = animation.encode_webp(6, 4, &lossless()).unwrap();
+            assert_eq!(play_webp(&webp).2, plays);
+        }
+    }
+
+    #[test]
+    fn ignores_actl_outside_its_chunk() {
+        // A still PNG with the bytes of an acTL chunk inside a text chunk.
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        let mut writer = encoder.write_header().unwrap();
+        let text = b"Comment\0\0\0\0\x08acTL\0\0\0\x02\0\0\0\0";
+        writer
+            .write_chunk(png::chunk::ChunkType(*b"tEXt"), text)
+            .unwrap();
+        writer.write_image_data(&[0; 16]).unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(apng_delays(&data), None);
+        assert!(Animation::decode(&data, ImageFormat::Png)
+            .unwrap()
+            .is_none());
+        assert!(Animation::decode_representative(&data, ImageFormat::Png)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn keeps_frame_delays() {
+        let animation = decode(&disposals_gif(), ImageFormat::Gif);
+        let delays: Vec<u32> = animation.frames.iter().map(|f| f.delay_ms).collect();
+        // 10 ms is shown at 100 ms by browsers.
+        assert_eq!(delays, [100, 100, 500, 200]);
+        let webp = animation.encode_webp(10, 10, &lossless()).unwrap();
+        assert_eq!(play_webp(&webp).1, [100, 200, 700, 900]);
+
+        let animation = decode(&disposals_apng(0), ImageFormat::Png);
+        let delays: Vec<u32> = animation.frames.iter().map(|f| f.delay_ms).collect();
+        assert_eq!(delays, [100, 333, 250, 100]);
+        let webp = animation.encode_webp(6, 4, &lossless()).unwrap();
+        assert_eq!(play_webp(&webp).1, [100, 433, 683, 783]);
+    }
+
+    #[test]
+    fn plays_gif_disposal() {
+        let animation = decode(&disposals_gif(), ImageFormat::Gif);
+        let disposals: Vec<Disposal> = animation.frames.iter().map(|f| f.disposal).collect();
+        assert_eq!(
+            disposals,
+            [
+                Disposal::Keep,
+                Disposal::Background,
+                Disposal::Previous,
+                Disposal::Keep
+            ]
+        );
+        let canvases: Vec<RgbaImage> = animation.canvases().collect();
+        // Transparent pixels of a frame show what is under them.
+        assert_eq!(*canvases[1].get_pixel(2, 2), RED);
+        assert_eq!(*canvases[1].get_pixel(3, 3), BLUE);
+        // The blue square was cleared before the green one was drawn.
+        assert_eq!(*canvases[2].get_pixel(2, 2), CLEAR);
+        assert_eq!(*canvases[2].get_pixel(3, 3), CLEAR);
+        assert_eq!(*canvases[2].get_pixel(4, 4), GREEN);
+        // And the green one was taken back off.
+        assert_eq!(*canvases[3].get_pixel(4, 4), CLEAR);
+        assert_eq!(*canvases[3].get_pixel(1, 0), RED);
+        assert_eq!(*canvases[3].get_pixel(7, 1), BLUE);
+    }
+
+    #[test]
+    fn writes_disposal_to_webp() {
+        let animation = decode(&disposals_gif(), ImageFormat::Gif);
+        let webp = animation.encode_webp(10, 10, &lossless()).unwrap();
+        assert_eq!(
+            anmf_frames(&webp),
+            [
+                (rect(0, 0, 10, 10), 0),
+                // Drawn over what is there and then cleared, as in the GIF.
+                (rect(2, 2, 4, 4), 0x01),
+                // At an odd offset, so moved to an even one and drawn with
+                // what is under it.
+                (rect(2, 2, 4, 4), 0x02),
+                // Also redraws where the last frame was, as WebP cannot
+                // restore it.
+                (rect(2, 0, 8, 6), 0x02),
+            ]
+        );
+        let (played, _, _) = play_webp(&webp);
+        for (i, (played, expected)) in played.iter().zip(animation.canvases()).enumerate() {
+            assert!(same(played, &expected), "frame {i}");
+        }
+
+        let animation = decode(&disposals_apng(0), ImageFormat::Png);
+        let blends: Vec<bool> = animation.frames.iter().map(|f| f.blend).collect();
+        assert_eq!(blends, [false, true, false, true]);
+        let webp = animation.encode_webp(6, 4, &lossless()).unwrap();
+        let (played, _, _) = play_webp(&webp);
+        let canvases: Vec<RgbaImage> = animation.canvases().collect();
+        assert_eq!(played.len(), canvases.len());
+        for (i, (played, expected)) in played.iter().zip(&canvases).enumerate() {
+            assert!(same(played, expected), "frame {i}");
+        }
+        // Half-transparent blue over red, then cleared.
+        let mixed = canvases[1].get_pixel(2, 0);
+        assert!(mixed[0] > 100 && mixed[2] > 100, "{mixed:?}");
+        assert_eq!(*canvases[2].get_pixel(2, 0), CLEAR);
+        assert_eq!(*canvases[3].get_pixel(0, 2), RED);
+    }
+
+    #[test]
+    fn scales_without_seams() {
+        let animation = decode(&disposals_gif(), ImageFormat::Gif);
+        for (width, height) in [(5, 5), (7, 4), (20, 20)] {
+            let webp = animation.encode_webp(width, height, &lossless()).unwrap();
+            let (played, _, _) = play_webp(&webp);
+            for (i, (played, canvas)) in played.iter().zip(animation.canvases()).enumerate() {
+                let expected = imageops::resize(&canvas, width, height, FilterType::Lanczos3);
+                assert!(same(played, &expected), "{width}x{height} frame {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn orients_frames_with_the_canvas() {
+        let orientations = [
+            Orientation::NoTransforms,
+            Orientation::Rotate90,
+            Orientation::Rotate180,
+            Orientation::Rotate270,
+            Orientation::FlipHorizontal,
+            Orientation::FlipVertical,
+            Orientation::Rotate90FlipH,
+            Orientation::Rotate270FlipH,
+        ];
+        let data = gif(
+            (7, 5),
+            &[
+                (rect(0, 0, 7, 5), 1, 10, gif::DisposalMethod::Keep),
+                (rect(1, 2, 4, 2), 2, 10, gif::DisposalMethod::Background),
+                (rect(5, 0, 2, 3), 3, 10, gif::DisposalMethod::Keep),
+            ],
+            None,
+        );
+        let canvases: Vec<RgbaImage> = decode(&data, ImageFormat::Gif).canvases().collect();
+        for orientation in orientations {
+            let oriented = decode(&data, ImageFormat::Gif).orient(orientation);
+            for (canvas, played) in canvases.iter().zip(oriented.canvases()) {
+                let mut expected = DynamicImage::ImageRgba8(canvas.clone());
+                expected.apply_orientation(orientation);
+                assert_eq!(played, expected.into_rgba8(), "{orientation:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_only_what_is_needed() {
+        let data = disposals_gif();
+        let animation = decode(&data, ImageFormat::Gif);
+        // The longest frame.
+        let third = animation.canvases().nth(2).unwrap();
+        assert_eq!(animation.representative(), third);
+        let representative = Animation::decode_representative(&data, ImageFormat::Gif).unwrap();
+        assert_eq!(representative, Some(third));
+
+        let still = gif(
+            (2, 2),
+            &[(rect(0, 0, 2, 2), 1, 0, gif::DisposalMethod::Keep)],
+            None,
+        );
+        assert!(Animation::decode(&still, ImageFormat::Gif)
+            .unwrap()
+            .is_none());
+        assert!(Animation::decode_representative(&still, ImageFormat::Gif)
+            .unwrap()
+            .is_none());
+    }
+}
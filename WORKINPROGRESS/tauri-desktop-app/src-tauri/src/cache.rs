@@ -0,0 +1,106 @@
+//! Cache of AI descriptions, keyed by the SHA-256 of the source file.
+//!
+//! Keying by content rather than path means a description survives the
+//! file being converted, renamed or moved, and is reused when the same
+//! image shows up in another folder.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedDescription {
+    /// Sanitized SEO file name, without extension.
+    pub slug: String,
+    /// The fields below are empty for descriptions cached before the model
+    /// was asked for them.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub title: String,
+    /// One sentence for the `alt` attribute.
+    #[serde(default)]
+    pub alt: String,
+    #[serde(default)]
+    pub caption: String,
+}
+
+impl CachedDescription {
+    /// Alt text, falling back to the words of the slug for descriptions
+    /// cached without one.
+    pub fn alt_text(&self) -> String {
+        if self.alt.is_empty() {
+            self.slug.replace('-', " ")
+        } else {
+            self.alt.clone()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DescriptionCache {
+    path: PathBuf,
+    entries: HashMap<String, CachedDescription>,
+}
+
+impl DescriptionCache {
+    /// Loads the cache stored at `path`. A missing file is an empty cache.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let entries = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// Writes the cache back to the file it was loaded from, replacing it
+    /// atomically so a crash never leaves a truncated cache behind.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = self.path.with_extension("json.part");
+        fs::write(&temp, serde_json::to_vec(&self.entries)?)?;
+        fs::rename(&temp, &self.path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&CachedDescription> {
+        self.entries.get(hash)
+    }
+
+    pub fn insert(&mut self, hash: String, description: CachedDescription) {
+        self.entries.insert(hash, description);
+    }
+}
+
+/// Hex SHA-256 of the file at `path`.
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
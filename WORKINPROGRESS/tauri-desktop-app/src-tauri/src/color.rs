@@ -0,0 +1,351 @@
+//! ICC colour management.
+//!
+//! Browsers treat untagged images as sRGB, and many places the outputs end
+//! up in (CMS thumbnails, social previews) drop embedded profiles. Sources
+//! tagged with a wider gamut, such as Display P3 or Adobe RGB, therefore
+//! have their pixels transformed to sRGB by default. A job can instead keep
+//! the pixels as they are and embed the source profile.
+
+use image::{DynamicImage, ImageBuffer};
+use moxcms::{
+    ColorProfile, DataColorSpace, Layout, ProfileText, ToneReprCurve, TransformOptions, Xyzd,
+};
+use serde::{Deserialize, Serialize};
+
+/// How sources with an embedded ICC profile are handled, per job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ColorMode {
+    /// Transform the pixels to sRGB and embed no profile.
+    #[default]
+    Srgb,
+    /// Keep the pixels and embed the source profile, as far as the metadata
+    /// policy keeps ICC profiles.
+    Preserve,
+}
+
+/// The colour space of one source, for the job report.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColorReport {
+    /// Description of the embedded profile, or `None` for an untagged
+    /// source, which is taken to be sRGB.
+    pub source: Option<String>,
+    /// The pixels were transformed to sRGB.
+    pub converted: bool,
+}
+
+/// An image after [`manage`].
+pub struct Managed {
+    pub image: DynamicImage,
+    pub report: ColorReport,
+    /// The source profile still describes the pixels and should be embedded
+    /// in the outputs.
+    pub embed_profile: bool,
+}
+
+/// Applies `mode` to `img`, decoded from a source with the ICC profile
+/// `icc`.
+pub fn manage(img: DynamicImage, icc: Option<&[u8]>, mode: ColorMode) -> Managed {
+    let Some(icc) = icc else {
+        return Managed {
+            image: img,
+            report: ColorReport::default(),
+            embed_profile: false,
+        };
+    };
+    let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+        // A profile that cannot be read cannot be trusted to describe the
+        // pixels either.
+        return Managed {
+            image: img,
+            report: ColorReport {
+                source: Some("unreadable ICC profile".to_string()),
+                converted: false,
+            },
+            embed_profile: false,
+        };
+    };
+    let name = describe(&profile);
+    // The outputs are always RGB, so only RGB profiles can be carried over.
+    let embeddable = profile.color_space == DataColorSpace::Rgb && img.color().has_color();
+    let unchanged = |image, embed_profile| Managed {
+        image,
+        report: ColorReport {
+            source: Some(name.clone()),
+            converted: false,
+        },
+        embed_profile,
+    };
+    if is_srgb(&profile) {
+        return unchanged(img, mode == ColorMode::Preserve && embeddable);
+    }
+    if mode == ColorMode::Preserve && embeddable {
+        return unchanged(img, true);
+    }
+    match to_srgb(&img, &profile) {
+        Some(image) => Managed {
+            image,
+            report: ColorReport {
+                source: Some(name.clone()),
+                converted: true,
+            },
+            embed_profile: false,
+        },
+        // Embedding the profile is the next best way to keep the colours.
+        None => unchanged(img, embeddable),
+    }
+}
+
+/// Largest difference from the sRGB colorants, in D50 XYZ, for a profile
+/// still to count as sRGB. Vendors' sRGB profiles differ by rounding and by
+/// how they adapted the primaries to D50.
+const COLORANT_TOLERANCE: f64 = 0.003;
+/// Largest difference from the sRGB tone curve, in linear light. Curves
+/// stored as tables are a little off from the exact formula; a plain 2.2
+/// gamma is up to 0.004 off.
+const TRC_TOLERANCE: f32 = 0.002;
+
+/// Whether `profile` is sRGB: the sRGB primaries and white point, and the
+/// sRGB tone curve on every channel. This is decided from the colorimetry,
+/// since profile names are only labels: renamed copies of sRGB are common,
+/// and so are wide-gamut profiles with "sRGB" somewhere in their name.
+fn is_srgb(profile: &ColorProfile) -> bool {
+    if profile.color_space != DataColorSpace::Rgb {
+        return false;
+    }
+    let srgb = ColorProfile::new_srgb();
+    let close = |a: &Xyzd, b: &Xyzd| {
+        [a.x - b.x, a.y - b.y, a.z - b.z]
+            .iter()
+            .all(|d| d.abs() <= COLORANT_TOLERANCE)
+    };
+    let colorants = [
+        (&profile.red_colorant, &srgb.red_colorant),
+        (&profile.green_colorant, &srgb.green_colorant),
+        (&profile.blue_colorant, &srgb.blue_colorant),
+    ];
+    if !colorants.iter().all(|(a, b)| close(a, b)) {
+        return false;
+    }
+    let linearize = |trc: Option<&ToneReprCurve>| trc?.make_linear_evaluator().ok();
+    let Some(reference) = linearize(srgb.red_trc.as_ref()) else {
+        return false;
+    };
+    [&profile.red_trc, &profile.green_trc, &profile.blue_trc]
+        .into_iter()
+        .all(|trc| {
+            linearize(trc.as_ref()).is_some_and(|curve| {
+                (0..=64).all(|i| {
+                    let value = i as f32 / 64.0;
+                    let difference = curve.evaluate_value(value) - reference.evaluate_value(value);
+                    difference.abs() <= TRC_TOLERANCE
+                })
+            })
+        })
+}
+
+/// The profile's description, or its colour space if it has none.
+fn describe(profile: &ColorProfile) -> String {
+    let text = match &profile.description {
+        Some(ProfileText::PlainString(text)) => text.clone(),
+        Some(ProfileText::Localizable(texts)) => texts
+            .iter()
+            .find(|text| text.language == "en")
+            .or(texts.first())
+            .map(|text| text.value.clone())
+            .unwrap_or_default(),
+        Some(ProfileText::Description(text)) => text.ascii_string.clone(),
+        None => String::new(),
+    };
+    let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if text.is_empty() {
+        format!("{:?} ICC profile", profile.color_space)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Transforms `img` from `profile` to sRGB, keeping its alpha channel and
+/// bit depth. Returns `None` for profiles that cannot describe the image,
+/// such as CMYK profiles on JPEGs the decoder already turned into RGB.
+fn to_srgb(img: &DynamicImage, profile: &ColorProfile) -> Option<DynamicImage> {
+    let gray = match profile.color_space {
+        DataColorSpace::Rgb => false,
+        DataColorSpace::Gray if !img.color().has_color() => true,
+        _ => return None,
+    };
+    let alpha = img.color().has_alpha();
+    let (src_layout, dst_layout) = match (gray, alpha) {
+        (false, false) => (Layout::Rgb, Layout::Rgb),
+        (false, true) => (Layout::Rgba, Layout::Rgba),
+        (true, false) => (Layout::Gray, Layout::Rgb),
+        (true, true) => (Layout::GrayAlpha, Layout::Rgba),
+    };
+    let (width, height) = (img.width(), img.height());
+    let samples = width as usize * height as usize * if alpha { 4 } else { 3 };
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+
+    if img.color().bytes_per_pixel() > img.color().channel_count() {
+        let src = match src_layout {
+            Layout::Rgb => img.to_rgb16().into_raw(),
+            Layout::Rgba => img.to_rgba16().into_raw(),
+            Layout::Gray => img.to_luma16().into_raw(),
+            _ => img.to_luma_alpha16().into_raw(),
+        };
+        let transform = profile
+            .create_transform_16bit(src_layout, &srgb, dst_layout, options)
+            .ok()?;
+        let mut dst = vec![0; samples];
+        transform.transform(&src, &mut dst).ok()?;
+        Some(if alpha {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, dst)?)
+        } else {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, dst)?)
+        })
+    } else {
+        let src = match src_layout {
+            Layout::Rgb => img.to_rgb8().into_raw(),
+            Layout::Rgba => img.to_rgba8().into_raw(),
+            Layout::Gray => img.to_luma8().into_raw(),
+            _ => img.to_luma_alpha8().into_raw(),
+        };
+        let transform = profile
+            .create_transform_8bit(src_layout, &srgb, dst_layout, options)
+            .ok()?;
+        let mut dst = vec![0; samples];
+        transform.transform(&src, &mut dst).ok()?;
+        Some(if alpha {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, dst)?)
+        } else {
+            DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, dst)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, Rgb, RgbImage};
+    use moxcms::{LocalizableString, ToneReprCurve};
+
+    fn renamed(mut profile: ColorProfile, name: &str) -> ColorProfile {
+        profile.description = Some(ProfileText::Localizable(vec![LocalizableString::new(
+            "en".to_string(),
+            "US".to_string(),
+            name.to_string(),
+        )]));
+        profile
+    }
+
+    fn icc(profile: &ColorProfile) -> Vec<u8> {
+        profile.encode().unwrap()
+    }
+
+    fn orange() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([230, 120, 40])))
+    }
+
+    #[test]
+    fn recognises_srgb_by_its_colorimetry() {
+        let parse = |profile: &ColorProfile| ColorProfile::new_from_slice(&icc(profile)).unwrap();
+        assert!(is_srgb(&parse(&ColorProfile::new_srgb())));
+        // Renamed copies are still sRGB.
+        let camera = renamed(ColorProfile::new_srgb(), "Camera RGB");
+        assert!(is_srgb(&parse(&camera)));
+        // So are ones with the curve stored as a table.
+        let mut table = ColorProfile::new_srgb();
+        let curve = ToneReprCurve::Lut(
+            (0..1024)
+                .map(|i| {
+                    let v = f64::from(i) / 1023.0;
+                    let linear = if v <= 0.04045 {
+                        v / 12.92
+                    } else {
+                        ((v + 0.055) / 1.055).powf(2.4)
+                    };
+                    (linear * 65535.0).round() as u16
+                })
+                .collect(),
+        );
+        table.red_trc = Some(curve.clone());
+        table.green_trc = Some(curve.clone());
+        table.blue_trc = Some(curve);
+        assert!(is_srgb(&parse(&table)));
+
+        // Wide gamuts are not, whatever they are called.
+        let p3 = renamed(ColorProfile::new_display_p3(), "sRGB wide");
+        assert!(!is_srgb(&parse(&p3)));
+        assert!(!is_srgb(&parse(&ColorProfile::new_adobe_rgb())));
+        // Nor are the sRGB primaries with another tone curve.
+        let mut gamma = ColorProfile::new_srgb();
+        let curve = moxcms::curve_from_gamma(2.2);
+        gamma.red_trc = Some(curve.clone());
+        gamma.green_trc = Some(curve.clone());
+        gamma.blue_trc = Some(curve);
+        assert!(!is_srgb(&parse(&gamma)));
+        assert!(!is_srgb(&ColorProfile::new_gray_with_gamma(2.2)));
+    }
+
+    #[test]
+    fn converts_wide_gamut_sources() {
+        for profile in [
+            ColorProfile::new_display_p3(),
+            ColorProfile::new_adobe_rgb(),
+        ] {
+            let name = describe(&profile);
+            let managed = manage(orange(), Some(&icc(&profile)), ColorMode::Srgb);
+            assert_eq!(
+                managed.report,
+                ColorReport {
+                    source: Some(name.clone()),
+                    converted: true,
+                }
+            );
+            assert!(!managed.embed_profile);
+            // The same colour is more saturated in sRGB terms.
+            let px = managed.image.to_rgb8().get_pixel(0, 0).0;
+            assert!(px[0] > 230 && px[2] < 40, "{name}: {px:?}");
+
+            let kept = manage(orange(), Some(&icc(&profile)), ColorMode::Preserve);
+            assert!(!kept.report.converted && kept.embed_profile);
+            assert_eq!(kept.image, orange());
+        }
+    }
+
+    #[test]
+    fn leaves_srgb_sources_alone() {
+        let profile = renamed(ColorProfile::new_srgb(), "Camera RGB");
+        let managed = manage(orange(), Some(&icc(&profile)), ColorMode::Srgb);
+        assert_eq!(
+            managed.report,
+            ColorReport {
+                source: Some("Camera RGB".to_string()),
+                converted: false,
+            }
+        );
+        assert!(!managed.embed_profile);
+        assert_eq!(managed.image, orange());
+        let preserved = manage(orange(), Some(&icc(&profile)), ColorMode::Preserve);
+        assert!(preserved.embed_profile);
+    }
+
+    #[test]
+    fn handles_untagged_gray_and_unreadable_sources() {
+        let untagged = manage(orange(), None, ColorMode::Srgb);
+        assert_eq!(untagged.report, ColorReport::default());
+        assert_eq!(untagged.image, orange());
+
+        let broken = manage(orange(), Some(b"not a profile"), ColorMode::Preserve);
+        assert!(!broken.report.converted && !broken.embed_profile);
+
+        // Gray profiles cannot be embedded in RGB outputs, so the pixels are
+        // converted even when preserving.
+        let gray = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])));
+        let profile = ColorProfile::new_gray_with_gamma(1.8);
+        let managed = manage(gray, Some(&icc(&profile)), ColorMode::Preserve);
+        assert!(managed.report.converted && !managed.embed_profile);
+        assert!(managed.image.color().has_color());
+    }
+}
@@ -0,0 +1,667 @@
+//! Native image conversion.
+//!
+//! Decodes the source formats the pipeline picks up (see [`crate::input`])
+//! and encodes them to WebP, AVIF or JPEG in-process, so the app no longer
+//! depends on `cwebp` being installed. SVG sources take their own route
+//! through [`crate::svg`].
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::color::{self, ColorMode, ColorReport};
+use crate::input::{self, Frames, Page, Source, SourceFormat, TiffPages};
+use crate::jpeg::{self, JpegOptions};
+use crate::metadata::{self, Blocks, MetadataError, MetadataField, MetadataOptions};
+use crate::recompress::{self, PngOptions};
+use crate::svg::{self, SvgOptions};
+
+/// Largest width or height a WebP image can have.
+pub const WEBP_MAX_DIMENSION: u32 = 16383;
+
+/// Output formats this build can encode.
+pub fn available_encoders() -> Vec<OutputFormat> {
+    let mut encoders = vec![OutputFormat::Webp, OutputFormat::Jpeg, OutputFormat::Png];
+    if cfg!(feature = "avif") {
+        encoders.push(OutputFormat::Avif);
+    }
+    encoders
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConvertError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("failed to decode image: {0}")]
+    Decode(#[from] image::ImageError),
+    /// The source is not in a format this build can decode.
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    #[error("{format} encoding failed: {reason}")]
+    Encode {
+        format: OutputFormat,
+        reason: String,
+    },
+    #[error("could not write metadata: {0}")]
+    Metadata(#[from] MetadataError),
+    #[error("failed to render SVG: {0}")]
+    Render(String),
+}
+
+impl ConvertError {
+    /// True if the source is not in a format this build can decode, as
+    /// opposed to a damaged file or a failure while converting it.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            ConvertError::Unsupported(_) | ConvertError::Decode(image::ImageError::Unsupported(_))
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFormat {
+    Webp,
+    Avif,
+    Jpeg,
+    Png,
+    /// The optimized copy of an SVG source. Raster sources cannot be
+    /// written as SVG.
+    Svg,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Svg => "svg",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Svg => "image/svg+xml",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Webp => "WebP",
+            OutputFormat::Avif => "AVIF",
+            OutputFormat::Jpeg => "JPEG",
+            OutputFormat::Png => "PNG",
+            OutputFormat::Svg => "SVG",
+        })
+    }
+}
+
+/// AVIF encoder settings, separate from the WebP ones because the two
+/// quality scales do not line up.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AvifOptions {
+    /// Quality, 1-100.
+    pub quality: u8,
+    /// Encoder speed, 1 (slowest, smallest files) to 10 (fastest).
+    pub speed: u8,
+}
+
+impl Default for AvifOptions {
+    fn default() -> Self {
+        Self {
+            quality: 80,
+            speed: 6,
+        }
+    }
+}
+
+/// Encoder settings taken from the job's command arguments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConvertOptions {
+    /// WebP lossy quality, 0-100. Ignored when `lossless` is set.
+    pub quality: u32,
+    /// WebP lossless mode.
+    pub lossless: bool,
+    /// Formats written for every source, all sharing one base name.
+    pub formats: Vec<OutputFormat>,
+    pub avif: AvifOptions,
+    pub jpeg: JpegOptions,
+    pub png: PngOptions,
+    /// Bounding box the full-size output is scaled down to fit
+    /// (`resize.max_width`/`resize.max_height` in `config.yaml`).
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Extra widths written as `name-640w.ext` for `srcset`. Widths at or
+    /// above the full-size output's are skipped; images are never upscaled.
+    pub widths: Vec<u32>,
+    pub metadata: MetadataOptions,
+    pub color: ColorMode,
+    pub tiff_pages: TiffPages,
+    pub svg: SvgOptions,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            quality: 85,
+            lossless: false,
+            formats: vec![OutputFormat::Webp],
+            avif: AvifOptions::default(),
+            jpeg: JpegOptions::default(),
+            png: PngOptions::default(),
+            max_width: None,
+            max_height: None,
+            widths: Vec::new(),
+            metadata: MetadataOptions::default(),
+            color: ColorMode::default(),
+            tiff_pages: TiffPages::default(),
+            svg: SvgOptions::default(),
+        }
+    }
+}
+
+/// One size an image is written in, for every output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputSize {
+    /// Ladder width this size was requested as, or `None` for the
+    /// full-size output.
+    pub ladder: Option<u32>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Sizes a `width`x`height` source is written in: the full-size output,
+/// capped to the bounding box, then each smaller ladder width in ascending
+/// order.
+pub fn output_sizes(width: u32, height: u32, options: &ConvertOptions) -> Vec<OutputSize> {
+    let max_width = options.max_width.unwrap_or(u32::MAX).max(1);
+    let max_height = options.max_height.unwrap_or(u32::MAX).max(1);
+    let (width, height) = fit(width, height, max_width, max_height);
+    let mut sizes = vec![OutputSize {
+        ladder: None,
+        width,
+        height,
+    }];
+
+    let mut widths = options.widths.clone();
+    widths.sort_unstable();
+    widths.dedup();
+    for ladder in widths.into_iter().filter(|&w| w > 0 && w < width) {
+        let (width, height) = fit(width, height, ladder, u32::MAX);
+        sizes.push(OutputSize {
+            ladder: Some(ladder),
+            width,
+            height,
+        });
+    }
+    sizes
+}
+
+/// Largest size with the aspect ratio of `width`x`height` that fits in
+/// `max_width`x`max_height` without upscaling. Rounds like
+/// [`DynamicImage::resize`].
+fn fit(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+    let ratio = f64::min(
+        f64::from(max_width) / f64::from(width),
+        f64::from(max_height) / f64::from(height),
+    );
+    let scale = |n: u32| ((f64::from(n) * ratio).round() as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// An image encoded in one format at one size.
+pub struct Encoded {
+    pub format: OutputFormat,
+    /// Page number for the second and later pages of a multi-page TIFF.
+    pub page: Option<u32>,
+    pub size: OutputSize,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything [`convert`] produced for one source.
+pub struct Conversion {
+    pub outputs: Vec<Encoded>,
+    /// Source metadata left out of the outputs by the metadata policy.
+    pub metadata_removed: BTreeSet<MetadataField>,
+    pub color: ColorReport,
+    /// Number of frames in the animated WebP outputs, if the source was
+    /// animated.
+    pub frames: Option<usize>,
+    /// Bytes the PNG output saved over a PNG source: the source's size
+    /// minus that of the full-size PNG output. `None` unless both are PNGs
+    /// and the output was not scaled down.
+    pub png_saved: Option<u64>,
+}
+
+/// Decodes `path` upright and in sRGB, with its EXIF orientation and ICC
+/// profile applied. Animations are decoded as their representative frame
+/// and multi-page TIFFs as their first page.
+pub fn decode(path: &Path) -> Result<DynamicImage, ConvertError> {
+    let Source {
+        mut image,
+        orientation,
+        metadata,
+        ..
+    } = input::read(path, TiffPages::First, Frames::Still)?;
+    image.apply_orientation(orientation);
+    Ok(color::manage(image, metadata.icc.as_deref(), ColorMode::Srgb).image)
+}
+
+/// Encodes `img` to an in-memory bitstream in `format`.
+pub fn encode(
+    img: &DynamicImage,
+    format: OutputFormat,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    match format {
+        OutputFormat::Webp => encode_webp(img, options),
+        OutputFormat::Avif => encode_avif(img, &options.avif),
+        OutputFormat::Jpeg => jpeg::encode(img, &options.jpeg),
+        OutputFormat::Png => recompress::encode(img, &options.png, false),
+        OutputFormat::Svg => Err(ConvertError::Encode {
+            format,
+            reason: "only SVG sources can be written as SVG".to_string(),
+        }),
+    }
+}
+
+/// Encodes `img` to an in-memory WebP bitstream.
+pub fn encode_webp(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let (width, height) = (img.width(), img.height());
+    if width == 0 || height == 0 || width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(ConvertError::Encode {
+            format: OutputFormat::Webp,
+            reason: format!("{width}x{height} is outside the WebP size limits"),
+        });
+    }
+
+    let memory = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+        encode_with(&encoder, options)?
+    } else {
+        let rgb = img.to_rgb8();
+        let encoder = webp::Encoder::from_rgb(&rgb, width, height);
+        encode_with(&encoder, options)?
+    };
+    Ok(memory)
+}
+
+fn encode_with(encoder: &webp::Encoder, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let quality = options.quality.min(100) as f32;
+    encoder
+        .encode_simple(options.lossless, quality)
+        .map(|memory| memory.to_vec())
+        .map_err(|e| ConvertError::Encode {
+            format: OutputFormat::Webp,
+            reason: format!("{e:?}"),
+        })
+}
+
+/// Encodes `img` to an in-memory AVIF file.
+#[cfg(feature = "avif")]
+pub fn encode_avif(img: &DynamicImage, options: &AvifOptions) -> Result<Vec<u8>, ConvertError> {
+    use image::codecs::avif::AvifEncoder;
+
+    // The encoder handles 8-bit RGB(A) best; anything else is converted
+    // first so grayscale and 16-bit sources come out the same as the WebP.
+    let img = if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+    let mut avif = Vec::new();
+    let encoder = AvifEncoder::new_with_speed_quality(
+        &mut avif,
+        options.speed.clamp(1, 10),
+        options.quality.clamp(1, 100),
+    );
+    img.write_with_encoder(encoder)
+        .map_err(|e| ConvertError::Encode {
+            format: OutputFormat::Avif,
+            reason: e.to_string(),
+        })?;
+    Ok(avif)
+}
+
+#[cfg(not(feature = "avif"))]
+pub fn encode_avif(_img: &DynamicImage, _options: &AvifOptions) -> Result<Vec<u8>, ConvertError> {
+    Err(ConvertError::Encode {
+        format: OutputFormat::Avif,
+        reason: "this build was compiled without AVIF support".to_string(),
+    })
+}
+
+/// Decodes `src` once and encodes it to each of `options.formats` in each
+/// of its [`output_sizes`], upright. Animated sources become animated
+/// WebPs, and each requested page of a multi-page TIFF gets its own set of
+/// outputs. The source metadata the job's policy keeps is carried over to
+/// every output that can hold it.
+pub fn convert(src: &Path, options: &ConvertOptions) -> Result<Conversion, ConvertError> {
+    let source_format = input::detect_file(src)?;
+    if source_format == Some(SourceFormat::Svg) {
+        return svg::convert(src, options);
+    }
+    let source_len = fs::metadata(src)?.len();
+    // Only WebP can hold an animation; the other formats get the
+    // representative frame.
+    let frames = if options.formats.contains(&OutputFormat::Webp) {
+        Frames::All
+    } else {
+        Frames::Still
+    };
+    let Source {
+        image: mut img,
+        orientation,
+        metadata: mut source,
+        animation,
+        pages,
+    } = input::read(src, options.tiff_pages, frames)?;
+    // Phone cameras store the sensor's pixels and only record the rotation;
+    // applied here, before resizing, as most outputs lose the tag.
+    img.apply_orientation(orientation);
+    let managed = color::manage(img, source.icc.as_deref(), options.color);
+    let img = managed.image;
+    // Further pages are always converted to sRGB: every output gets the
+    // same metadata blocks, and those hold the first page's profile.
+    let pages: Vec<DynamicImage> = pages
+        .into_iter()
+        .map(|Page { image, icc }| color::manage(image, icc.as_deref(), ColorMode::Srgb).image)
+        .collect();
+    let animation = animation.map(|animation| {
+        animation
+            .orient(orientation)
+            .map_frames(|frame| color::manage(frame, source.icc.as_deref(), options.color).image)
+    });
+    if !managed.embed_profile {
+        source.icc = None;
+    }
+    let (blocks, mut metadata_removed) = options.metadata.apply(source);
+    if blocks.iptc.is_some() && !options.formats.contains(&OutputFormat::Jpeg) {
+        // Only JPEG has a place for IPTC-IIM datasets.
+        metadata_removed.insert(MetadataField::Iptc);
+    }
+    let page_blocks = Blocks {
+        icc: None,
+        ..blocks.clone()
+    };
+    let mut outputs = Vec::new();
+    let mut png_saved = None;
+    let numbered = pages
+        .iter()
+        .enumerate()
+        .map(|(i, page)| (Some(i as u32 + 2), page));
+    for (page, img) in std::iter::once((None, &img)).chain(numbered) {
+        let (animation, blocks) = match page {
+            None => (animation.as_ref(), &blocks),
+            Some(_) => (None, &page_blocks),
+        };
+        for size in output_sizes(img.width(), img.height(), options) {
+            let unscaled = (size.width, size.height) == (img.width(), img.height());
+            let resized = if unscaled {
+                img.clone()
+            } else {
+                img.resize_exact(size.width, size.height, FilterType::Lanczos3)
+            };
+            for &format in &options.formats {
+                let mut bytes = match (animation, format) {
+                    (Some(animation), OutputFormat::Webp) => {
+                        animation.encode_webp(size.width, size.height, options)?
+                    }
+                    (_, OutputFormat::Png) => {
+                        recompress::encode(&resized, &options.png, blocks.icc.is_some())?
+                    }
+                    _ => encode(&resized, format, options)?,
+                };
+                if !blocks.is_empty() {
+                    if let Some(embedded) = metadata::embed(&bytes, blocks)? {
+                        bytes = embedded;
+                    }
+                }
+                let same_image = page.is_none() && size.ladder.is_none() && unscaled;
+                if format == OutputFormat::Png
+                    && source_format == Some(SourceFormat::Png)
+                    && same_image
+                {
+                    png_saved = Some(source_len.saturating_sub(bytes.len() as u64));
+                }
+                outputs.push(Encoded {
+                    format,
+                    page,
+                    size,
+                    bytes,
+                });
+            }
+        }
+    }
+    Ok(Conversion {
+        outputs,
+        metadata_removed,
+        color: managed.report,
+        frames: animation.map(|animation| animation.frames.len()),
+        png_saved,
+    })
+}
+
+/// Re-encodes `src` as a JPEG no larger than `max_size` on either side, for
+/// showing it to the vision model: JPEG is accepted by every model, unlike
+/// some of the source and output formats.
+pub fn preview_jpeg(src: &Path, max_size: u32) -> Result<Vec<u8>, ConvertError> {
+    let img = decode(src)?;
+    let img = if img.width() > max_size || img.height() > max_size {
+        img.thumbnail(max_size, max_size)
+    } else {
+        img
+    };
+    let options = JpegOptions {
+        quality: 85,
+        progressive: false,
+        trellis: false,
+        ..JpegOptions::default()
+    };
+    jpeg::encode(&img, &options)
+}
+
+/// Suffix of in-progress output files. Hidden files ending in it are safe
+/// to delete once the job that wrote them has stopped.
+const TEMP_SUFFIX: &str = ".part";
+
+/// Writes `bytes` to a hidden `.stem{suffix}.part` file in `dir` and
+/// returns its path. The file only becomes visible under its real name
+/// once it is moved onto a name from [`claim_names`], so a cancelled or
+/// crashed job never leaves a half-written image behind under a `.webp`
+/// name.
+pub fn write_temp(dir: &Path, stem: &str, suffix: &str, bytes: &[u8]) -> io::Result<PathBuf> {
+    let (path, mut file) =
+        create_unique(dir, &format!(".{stem}"), &format!("{suffix}{TEMP_SUFFIX}"))?;
+    if let Err(e) = file.write_all(bytes).and_then(|_| file.sync_all()) {
+        drop(file);
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// Returns true for temp files created by [`write_temp`].
+pub fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.') && name.ends_with(TEMP_SUFFIX))
+}
+
+/// What follows the stem in an output's file name: `.ext` for the
+/// full-size output, `-640w.ext` for a width ladder variant, each preceded
+/// by `-page2` and so on for the further pages of a multi-page TIFF.
+pub fn name_suffix(format: OutputFormat, page: Option<u32>, ladder: Option<u32>) -> String {
+    let page = page.map(|page| format!("-page{page}")).unwrap_or_default();
+    match ladder {
+        Some(width) => format!("{page}-{width}w.{}", format.extension()),
+        None => format!("{page}.{}", format.extension()),
+    }
+}
+
+/// `stem{suffix}` for `counter == 0`, otherwise `stem-counter{suffix}`.
+pub fn numbered_name(stem: &str, suffix: &str, counter: u32) -> String {
+    if counter == 0 {
+        format!("{stem}{suffix}")
+    } else {
+        format!("{stem}-{counter}{suffix}")
+    }
+}
+
+/// Creates empty placeholders `dir/stem-N{suffix}` for every suffix in
+/// `suffixes`, using the lowest `N` for which all of them are free, and
+/// returns their paths, like the `mv -n` loop in the shell script.
+/// Claiming the names with `create_new` means concurrent workers never
+/// overwrite each other's files; moving a file onto a placeholder then
+/// replaces it atomically.
+///
+/// `vacating` counts as free and gets no placeholder: it is the original,
+/// which is moved to the backup folder before its outputs take their names,
+/// so that the JPEG of a JPEG keeps the original's name.
+pub fn claim_names(
+    dir: &Path,
+    stem: &str,
+    suffixes: &[&str],
+    vacating: Option<&Path>,
+) -> io::Result<Vec<PathBuf>> {
+    let mut counter = 0u32;
+    'counters: loop {
+        let mut claimed = Vec::with_capacity(suffixes.len());
+        for suffix in suffixes {
+            let path = dir.join(numbered_name(stem, suffix, counter));
+            if Some(path.as_path()) == vacating {
+                claimed.push(path);
+                continue;
+            }
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => claimed.push(path),
+                Err(e) => {
+                    for path in claimed
+                        .iter()
+                        .filter(|path| Some(path.as_path()) != vacating)
+                    {
+                        let _ = fs::remove_file(path);
+                    }
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(e);
+                    }
+                    counter += 1;
+                    continue 'counters;
+                }
+            }
+        }
+        return Ok(claimed);
+    }
+}
+
+fn create_unique(dir: &Path, stem: &str, suffix: &str) -> io::Result<(PathBuf, fs::File)> {
+    let mut counter = 0u32;
+    loop {
+        let path = dir.join(numbered_name(stem, suffix, counter));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => counter += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(width: u32, height: u32, options: &ConvertOptions) -> Vec<(Option<u32>, u32, u32)> {
+        output_sizes(width, height, options)
+            .into_iter()
+            .map(|size| (size.ladder, size.width, size.height))
+            .collect()
+    }
+
+    #[test]
+    fn ladder_never_upscales() {
+        let options = ConvertOptions {
+            widths: vec![1920, 320, 640, 640, 0, 1000],
+            ..ConvertOptions::default()
+        };
+        assert_eq!(
+            sizes(1000, 500, &options),
+            [
+                (None, 1000, 500),
+                (Some(320), 320, 160),
+                (Some(640), 640, 320),
+            ]
+        );
+        assert_eq!(sizes(300, 200, &options), [(None, 300, 200)]);
+    }
+
+    #[test]
+    fn full_size_fits_the_bounding_box() {
+        let options = ConvertOptions {
+            max_width: Some(800),
+            max_height: Some(800),
+            widths: vec![400, 800],
+            ..ConvertOptions::default()
+        };
+        // Portrait: the height is the limit, and the ladder is measured
+        // against the capped width.
+        assert_eq!(
+            sizes(1200, 1600, &options),
+            [(None, 600, 800), (Some(400), 400, 533)]
+        );
+        assert_eq!(
+            sizes(4000, 3000, &options),
+            [(None, 800, 600), (Some(400), 400, 300)]
+        );
+        // Thin images keep at least one pixel.
+        let options = ConvertOptions {
+            max_width: Some(100),
+            ..ConvertOptions::default()
+        };
+        assert_eq!(sizes(10_000, 10, &options), [(None, 100, 1)]);
+    }
+
+    #[test]
+    fn names_variants() {
+        assert_eq!(name_suffix(OutputFormat::Webp, None, None), ".webp");
+        assert_eq!(
+            name_suffix(OutputFormat::Webp, None, Some(640)),
+            "-640w.webp"
+        );
+        assert_eq!(
+            name_suffix(OutputFormat::Avif, Some(2), Some(640)),
+            "-page2-640w.avif"
+        );
+        assert_eq!(numbered_name("photo", "-640w.webp", 0), "photo-640w.webp");
+        assert_eq!(numbered_name("photo", "-640w.webp", 2), "photo-2-640w.webp");
+    }
+}
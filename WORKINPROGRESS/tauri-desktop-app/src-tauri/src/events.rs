@@ -0,0 +1,234 @@
+//! Typed progress events emitted to the frontend while a job runs.
+//!
+//! Every event is sent twice: as a serialized [`JobEvent`] tagged with its
+//! job ID on the [`JOB_EVENT`] channel, and as a plain text line on the
+//! legacy `log` channel so the existing log viewer keeps working.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tauri::Manager;
+
+use crate::color::ColorReport;
+use crate::input::FormatMismatch;
+use crate::jobs::JobStatus;
+use crate::metadata::MetadataField;
+use crate::ollama::OllamaError;
+use crate::pipeline::{ConversionSummary, OutputVariant};
+
+/// Name of the Tauri event carrying serialized [`JobEvent`]s.
+pub const JOB_EVENT: &str = "job-event";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    Convert,
+    Rename,
+    Export,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum JobEvent {
+    StatusChanged {
+        status: JobStatus,
+    },
+    JobStarted {
+        root: PathBuf,
+        backup_dir: PathBuf,
+        total_files: usize,
+    },
+    PhaseChanged {
+        phase: Phase,
+    },
+    /// The file's extension does not match its contents; it is converted
+    /// as the format its contents were detected as.
+    FormatMismatch(FormatMismatch),
+    FileStarted {
+        path: PathBuf,
+    },
+    FileConverted {
+        source: PathBuf,
+        outputs: Vec<OutputVariant>,
+        bytes_in: u64,
+        /// Combined size of all outputs.
+        bytes_out: u64,
+        /// Source metadata the policy left out of the outputs.
+        metadata_removed: Vec<MetadataField>,
+        color: ColorReport,
+        /// Frame count of the animated WebP outputs.
+        frames: Option<usize>,
+        /// Bytes the PNG output saved over the source PNG; see
+        /// [`crate::convert::Conversion::png_saved`].
+        png_saved: Option<u64>,
+    },
+    FileRenamed {
+        from: Vec<PathBuf>,
+        to: Vec<PathBuf>,
+    },
+    FileFailed {
+        path: PathBuf,
+        reason: String,
+    },
+    /// The file was picked up but cannot be decoded by this build.
+    FileUnsupported {
+        path: PathBuf,
+        reason: String,
+    },
+    /// The model could not name this file; it keeps its current name.
+    RenameFailed {
+        path: PathBuf,
+        error: OllamaError,
+    },
+    /// Ollama cannot be used, so Phase 2 only applies cached names.
+    AiUnavailable {
+        error: OllamaError,
+    },
+    /// HTML snippets were written for `files` images; `path` is the
+    /// combined file.
+    SnippetsExported {
+        path: PathBuf,
+        files: usize,
+    },
+    JobFinished(ConversionSummary),
+    JobFailed {
+        reason: String,
+    },
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload<'a> {
+    job_id: &'a str,
+    #[serde(flatten)]
+    event: &'a JobEvent,
+}
+
+impl fmt::Display for JobEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobEvent::StatusChanged { status } => write!(f, "Job status: {status:?}"),
+            JobEvent::JobStarted {
+                root,
+                backup_dir,
+                total_files,
+            } => write!(
+                f,
+                "Target Directory: {}. Found {total_files} images. Originals will be backed up in: {}",
+                root.display(),
+                backup_dir.display()
+            ),
+            JobEvent::PhaseChanged {
+                phase: Phase::Convert,
+            } => f.write_str("Phase 1: Converting images..."),
+            JobEvent::PhaseChanged {
+                phase: Phase::Rename,
+            } => f.write_str("Phase 2: Renaming files with AI-generated SEO keywords..."),
+            JobEvent::PhaseChanged {
+                phase: Phase::Export,
+            } => f.write_str("Phase 3: Writing HTML snippets..."),
+            JobEvent::FormatMismatch(mismatch) => write!(f, "Warning: {mismatch}"),
+            JobEvent::FileStarted { path } => write!(f, "Processing: {}", path.display()),
+            JobEvent::FileConverted {
+                source,
+                outputs,
+                metadata_removed,
+                color,
+                frames,
+                png_saved,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Converted: {} -> {}",
+                    source.display(),
+                    display_paths(outputs.iter().map(|output| &output.path))
+                )?;
+                if let Some(frames) = frames {
+                    write!(f, " (animated, {frames} frames)")?;
+                }
+                if let Some(saved) = png_saved {
+                    write!(f, " (PNG optimization saved {saved} bytes over the source)")?;
+                }
+                if let (Some(source), true) = (&color.source, color.converted) {
+                    write!(f, " ({source} converted to sRGB)")?;
+                }
+                if !metadata_removed.is_empty() {
+                    let labels: Vec<&str> =
+                        metadata_removed.iter().map(|field| field.label()).collect();
+                    write!(f, " (removed {})", labels.join(", "))?;
+                }
+                Ok(())
+            }
+            JobEvent::FileRenamed { from, to } => write!(
+                f,
+                "Renamed: {} -> {}",
+                display_paths(from),
+                display_paths(to)
+            ),
+            JobEvent::FileFailed { path, reason } => {
+                write!(f, "Warning: '{}' failed: {reason}", path.display())
+            }
+            JobEvent::FileUnsupported { path, reason } => {
+                write!(f, "Warning: '{}' is {reason}", path.display())
+            }
+            JobEvent::RenameFailed { path, error } => {
+                write!(f, "Warning: Could not rename '{}': {error}", path.display())
+            }
+            JobEvent::AiUnavailable { error } => {
+                write!(f, "Warning: AI renaming unavailable: {error}")
+            }
+            JobEvent::SnippetsExported { path, files } => write!(
+                f,
+                "HTML snippets for {files} images written to: {}",
+                path.display()
+            ),
+            JobEvent::JobFinished(summary) => {
+                write!(
+                    f,
+                    "All tasks finished: {} converted, {} renamed, {} failed",
+                    summary.converted,
+                    summary.renamed,
+                    summary.failed + summary.rename_failed
+                )?;
+                if summary.unsupported > 0 {
+                    write!(f, ", {} unsupported", summary.unsupported)?;
+                }
+                if !summary.format_mismatches.is_empty() {
+                    write!(
+                        f,
+                        ", {} with mismatched extensions",
+                        summary.format_mismatches.len()
+                    )?;
+                }
+                f.write_str(".")
+            }
+            JobEvent::JobFailed { reason } => write!(f, "Error: {reason}"),
+        }
+    }
+}
+
+fn display_paths<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> String {
+    paths
+        .into_iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Sends `event` to every window on both the typed and the `log` channel.
+pub fn emit(app: &tauri::AppHandle, job_id: &str, event: JobEvent) {
+    let _ = app.emit_all("log", event.to_string());
+    let _ = app.emit_all(
+        JOB_EVENT,
+        Payload {
+            job_id,
+            event: &event,
+        },
+    );
+}
@@ -0,0 +1,498 @@
+//! Tag-level EXIF rewriting.
+//!
+//! Parses a TIFF-structured EXIF block into its IFDs, drops the tags the
+//! metadata policy does not keep and writes what is left as a new, compact
+//! block. Tag values are copied byte for byte in the block's own byte order;
+//! only the offsets between IFDs are recomputed.
+//!
+//! The thumbnail IFD is always dropped: it still shows the image as it was
+//! before conversion, and would leak whatever was cropped or rotated away.
+
+use std::collections::BTreeSet;
+
+use crate::metadata::MetadataField;
+
+const ORIENTATION: u16 = 0x0112;
+const ARTIST: u16 = 0x013B;
+const COPYRIGHT: u16 = 0x8298;
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+const INTEROP_IFD: u16 = 0xA005;
+const MAKER_NOTE: u16 = 0x927C;
+const IMAGE_UNIQUE_ID: u16 = 0xA420;
+const CAMERA_OWNER_NAME: u16 = 0xA430;
+const BODY_SERIAL_NUMBER: u16 = 0xA431;
+const LENS_SERIAL_NUMBER: u16 = 0xA435;
+/// DNG's `CameraSerialNumber`, found in IFD0.
+const CAMERA_SERIAL_NUMBER: u16 = 0xC62F;
+
+/// IFD nesting deeper than this only occurs in malicious files.
+const MAX_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// The byte order of a TIFF structure starting with `header`, or `None`
+    /// if it has no TIFF header.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        match header.get(..4)? {
+            b"II*\0" => Some(ByteOrder::Little),
+            b"MM\0*" => Some(ByteOrder::Big),
+            _ => None,
+        }
+    }
+
+    pub fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn put_u16(self, out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        });
+    }
+
+    fn put_u32(self, out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        });
+    }
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: Value,
+}
+
+enum Value {
+    Data(Vec<u8>),
+    Ifd(Vec<Entry>),
+}
+
+/// A parsed EXIF block.
+pub struct Exif {
+    order: ByteOrder,
+    ifd0: Vec<Entry>,
+}
+
+impl Exif {
+    /// Parses `block`, or returns `None` if it is not a well-formed TIFF
+    /// structure.
+    pub fn parse(block: &[u8]) -> Option<Self> {
+        let order = ByteOrder::detect(block)?;
+        let offset = order.u32(block.get(4..8)?) as usize;
+        let ifd0 = parse_ifd(block, order, offset, 0)?;
+        Some(Self { order, ifd0 })
+    }
+
+    /// Drops every tag whose field `keep` rejects. Returns the fields that
+    /// were present and dropped.
+    pub fn retain(&mut self, keep: impl Fn(MetadataField) -> bool) -> BTreeSet<MetadataField> {
+        let mut removed = BTreeSet::new();
+        retain_ifd(&mut self.ifd0, Ifd::Zero, &keep, &mut removed);
+        removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ifd0.is_empty()
+    }
+
+    /// Sets the orientation tag, if present, to 1 (upright), for images
+    /// whose pixels have already been rotated.
+    pub fn reset_orientation(&mut self) {
+        for entry in &mut self.ifd0 {
+            if entry.tag == ORIENTATION && entry.kind == SHORT && entry.count == 1 {
+                let mut value = Vec::with_capacity(4);
+                self.order.put_u16(&mut value, 1);
+                value.resize(4, 0);
+                entry.value = Value::Data(value);
+            }
+        }
+    }
+
+    /// Serializes the block.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(match self.order {
+            ByteOrder::Little => b"II*\0",
+            ByteOrder::Big => b"MM\0*",
+        });
+        self.order.put_u32(&mut out, 8);
+        write_ifd(&mut out, &self.ifd0, self.order);
+        out
+    }
+}
+
+pub const SHORT: u16 = 3;
+
+pub fn type_size(kind: u16) -> Option<usize> {
+    Some(match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => return None,
+    })
+}
+
+fn parse_ifd(block: &[u8], order: ByteOrder, offset: usize, depth: usize) -> Option<Vec<Entry>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let count = usize::from(order.u16(block.get(offset..offset + 2)?));
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let raw = block.get(offset + 2 + i * 12..offset + 14 + i * 12)?;
+        let tag = order.u16(&raw[0..2]);
+        let kind = order.u16(&raw[2..4]);
+        let count = order.u32(&raw[4..8]);
+        // Unknown types cannot be relocated safely; leave them out.
+        let Some(size) = type_size(kind).and_then(|size| size.checked_mul(count as usize)) else {
+            continue;
+        };
+        let value = if matches!(tag, EXIF_IFD | GPS_IFD | INTEROP_IFD) {
+            let child = order.u32(&raw[8..12]) as usize;
+            Value::Ifd(parse_ifd(block, order, child, depth + 1)?)
+        } else if size <= 4 {
+            Value::Data(raw[8..12].to_vec())
+        } else {
+            let start = order.u32(&raw[8..12]) as usize;
+            Value::Data(block.get(start..start.checked_add(size)?)?.to_vec())
+        };
+        entries.push(Entry {
+            tag,
+            kind,
+            count,
+            value,
+        });
+    }
+    Some(entries)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Ifd {
+    Zero,
+    Exif,
+    Gps,
+    Interop,
+}
+
+/// The policy field a tag belongs to.
+fn field(ifd: Ifd, tag: u16) -> MetadataField {
+    match (ifd, tag) {
+        (Ifd::Gps, _) | (Ifd::Zero, GPS_IFD) => MetadataField::Gps,
+        (Ifd::Zero, ARTIST | COPYRIGHT) => MetadataField::Copyright,
+        (Ifd::Zero, CAMERA_SERIAL_NUMBER)
+        | (
+            Ifd::Exif,
+            IMAGE_UNIQUE_ID | CAMERA_OWNER_NAME | BODY_SERIAL_NUMBER | LENS_SERIAL_NUMBER,
+        ) => MetadataField::SerialNumbers,
+        (Ifd::Exif, MAKER_NOTE) => MetadataField::MakerNotes,
+        _ => MetadataField::Exif,
+    }
+}
+
+fn retain_ifd(
+    entries: &mut Vec<Entry>,
+    ifd: Ifd,
+    keep: &impl Fn(MetadataField) -> bool,
+    removed: &mut BTreeSet<MetadataField>,
+) {
+    entries.retain_mut(|entry| {
+        let field = field(ifd, entry.tag);
+        // The Exif sub-IFD pointer is structure, not data: it stays as long
+        // as anything inside it does.
+        if entry.tag != EXIF_IFD && !keep(field) {
+            removed.insert(field);
+            return false;
+        }
+        match &mut entry.value {
+            Value::Ifd(children) => {
+                let child_ifd = match entry.tag {
+                    EXIF_IFD => Ifd::Exif,
+                    GPS_IFD => Ifd::Gps,
+                    _ => Ifd::Interop,
+                };
+                retain_ifd(children, child_ifd, keep, removed);
+                !children.is_empty()
+            }
+            Value::Data(_) => true,
+        }
+    });
+}
+
+/// Writes `entries` as an IFD at the end of `out`, followed by their
+/// out-of-line values and sub-IFDs, and returns its offset.
+fn write_ifd(out: &mut Vec<u8>, entries: &[Entry], order: ByteOrder) -> u32 {
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+    let start = out.len();
+    let mut sorted: Vec<&Entry> = entries.iter().collect();
+    sorted.sort_by_key(|entry| entry.tag);
+
+    order.put_u16(out, sorted.len() as u16);
+    for entry in &sorted {
+        order.put_u16(out, entry.tag);
+        order.put_u16(out, entry.kind);
+        order.put_u32(out, entry.count);
+        match &entry.value {
+            Value::Data(data) if data.len() <= 4 => {
+                out.extend_from_slice(data);
+                out.resize(out.len() + 4 - data.len(), 0);
+            }
+            _ => order.put_u32(out, 0),
+        }
+    }
+    // No next IFD: the thumbnail IFD is never written.
+    order.put_u32(out, 0);
+
+    for (i, entry) in sorted.iter().enumerate() {
+        let offset = match &entry.value {
+            Value::Data(data) if data.len() <= 4 => continue,
+            Value::Data(data) => {
+                if out.len() % 2 == 1 {
+                    out.push(0);
+                }
+                let offset = out.len() as u32;
+                out.extend_from_slice(data);
+                offset
+            }
+            Value::Ifd(children) => write_ifd(out, children, order),
+        };
+        let slot = start + 2 + i * 12 + 8;
+        let mut bytes = Vec::with_capacity(4);
+        order.put_u32(&mut bytes, offset);
+        out[slot..slot + 4].copy_from_slice(&bytes);
+    }
+    start as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXIF_VERSION: u16 = 0x9000;
+    const GPS_LATITUDE_REF: u16 = 0x0001;
+
+    fn put_entry(out: &mut Vec<u8>, order: ByteOrder, tag: u16, kind: u16, count: u32) {
+        order.put_u16(out, tag);
+        order.put_u16(out, kind);
+        order.put_u32(out, count);
+    }
+
+    /// A block with out-of-line values, Exif and GPS sub-IFDs and a
+    /// thumbnail IFD:
+    ///
+    /// ```text
+    ///   8  IFD0: Orientation=6, Artist, Exif IFD, GPS IFD; next: 136
+    ///  62  "Alice\0"
+    ///  68  Exif IFD: ExifVersion, MakerNote, BodySerialNumber
+    /// 110  "MAKERNOT"
+    /// 118  GPS IFD: GPSLatitudeRef
+    /// 136  thumbnail IFD
+    /// ```
+    fn sample(order: ByteOrder) -> Vec<u8> {
+        let mut out = match order {
+            ByteOrder::Little => b"II*\0".to_vec(),
+            ByteOrder::Big => b"MM\0*".to_vec(),
+        };
+        order.put_u32(&mut out, 8);
+
+        order.put_u16(&mut out, 4);
+        put_entry(&mut out, order, ORIENTATION, SHORT, 1);
+        order.put_u16(&mut out, 6);
+        out.extend_from_slice(&[0, 0]);
+        put_entry(&mut out, order, ARTIST, 2, 6);
+        order.put_u32(&mut out, 62);
+        put_entry(&mut out, order, EXIF_IFD, 4, 1);
+        order.put_u32(&mut out, 68);
+        put_entry(&mut out, order, GPS_IFD, 4, 1);
+        order.put_u32(&mut out, 118);
+        order.put_u32(&mut out, 136);
+        assert_eq!(out.len(), 62);
+        out.extend_from_slice(b"Alice\0");
+
+        order.put_u16(&mut out, 3);
+        put_entry(&mut out, order, EXIF_VERSION, 7, 4);
+        out.extend_from_slice(b"0232");
+        put_entry(&mut out, order, MAKER_NOTE, 7, 8);
+        order.put_u32(&mut out, 110);
+        put_entry(&mut out, order, BODY_SERIAL_NUMBER, 2, 4);
+        out.extend_from_slice(b"123\0");
+        order.put_u32(&mut out, 0);
+        assert_eq!(out.len(), 110);
+        out.extend_from_slice(b"MAKERNOT");
+
+        order.put_u16(&mut out, 1);
+        put_entry(&mut out, order, GPS_LATITUDE_REF, 2, 2);
+        out.extend_from_slice(b"N\0\0\0");
+        order.put_u32(&mut out, 0);
+        assert_eq!(out.len(), 136);
+
+        order.put_u16(&mut out, 1);
+        put_entry(&mut out, order, 0x0201, 4, 1);
+        order.put_u32(&mut out, 0);
+        order.put_u32(&mut out, 0);
+        out
+    }
+
+    fn find(entries: &[Entry], tag: u16) -> Option<&Entry> {
+        entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    fn data(entries: &[Entry], tag: u16) -> Option<&[u8]> {
+        match &find(entries, tag)?.value {
+            Value::Data(data) => Some(data),
+            Value::Ifd(_) => None,
+        }
+    }
+
+    fn ifd(entries: &[Entry], tag: u16) -> Option<&[Entry]> {
+        match &find(entries, tag)?.value {
+            Value::Ifd(children) => Some(children),
+            Value::Data(_) => None,
+        }
+    }
+
+    fn tags(entries: &[Entry]) -> Vec<u16> {
+        entries.iter().map(|entry| entry.tag).collect()
+    }
+
+    #[test]
+    fn round_trips_in_both_byte_orders() {
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let exif = Exif::parse(&sample(order)).unwrap();
+            let bytes = exif.to_bytes();
+            assert_eq!(ByteOrder::detect(&bytes), Some(order));
+            // IFD0 is written right after the header, with no next IFD.
+            assert_eq!(order.u32(&bytes[4..8]), 8);
+            assert_eq!(order.u32(&bytes[8 + 2 + 4 * 12..]), 0);
+
+            let exif = Exif::parse(&bytes).unwrap();
+            assert_eq!(exif.order, order);
+            assert_eq!(tags(&exif.ifd0), [ORIENTATION, ARTIST, EXIF_IFD, GPS_IFD]);
+            assert_eq!(order.u16(data(&exif.ifd0, ORIENTATION).unwrap()), 6);
+            assert_eq!(data(&exif.ifd0, ARTIST).unwrap(), b"Alice\0");
+            let sub = ifd(&exif.ifd0, EXIF_IFD).unwrap();
+            assert_eq!(data(sub, EXIF_VERSION).unwrap(), b"0232");
+            assert_eq!(data(sub, MAKER_NOTE).unwrap(), b"MAKERNOT");
+            assert_eq!(data(sub, BODY_SERIAL_NUMBER).unwrap(), b"123\0");
+            let gps = ifd(&exif.ifd0, GPS_IFD).unwrap();
+            assert_eq!(data(gps, GPS_LATITUDE_REF).unwrap(), b"N\0\0\0");
+            // Writing is deterministic.
+            assert_eq!(exif.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn retain_drops_tags_by_field() {
+        let mut exif = Exif::parse(&sample(ByteOrder::Little)).unwrap();
+        let removed = exif.retain(|field| field == MetadataField::Exif);
+        assert_eq!(
+            removed,
+            BTreeSet::from([
+                MetadataField::Gps,
+                MetadataField::Copyright,
+                MetadataField::SerialNumbers,
+                MetadataField::MakerNotes,
+            ])
+        );
+        let exif = Exif::parse(&exif.to_bytes()).unwrap();
+        assert_eq!(tags(&exif.ifd0), [ORIENTATION, EXIF_IFD]);
+        assert_eq!(tags(ifd(&exif.ifd0, EXIF_IFD).unwrap()), [EXIF_VERSION]);
+    }
+
+    #[test]
+    fn assigns_tags_to_fields() {
+        let cases = [
+            (Ifd::Zero, GPS_IFD, MetadataField::Gps),
+            (Ifd::Gps, GPS_LATITUDE_REF, MetadataField::Gps),
+            (Ifd::Exif, MAKER_NOTE, MetadataField::MakerNotes),
+            (Ifd::Exif, BODY_SERIAL_NUMBER, MetadataField::SerialNumbers),
+            (Ifd::Exif, LENS_SERIAL_NUMBER, MetadataField::SerialNumbers),
+            (Ifd::Exif, CAMERA_OWNER_NAME, MetadataField::SerialNumbers),
+            (Ifd::Exif, IMAGE_UNIQUE_ID, MetadataField::SerialNumbers),
+            (
+                Ifd::Zero,
+                CAMERA_SERIAL_NUMBER,
+                MetadataField::SerialNumbers,
+            ),
+            (Ifd::Zero, ARTIST, MetadataField::Copyright),
+            (Ifd::Zero, COPYRIGHT, MetadataField::Copyright),
+            (Ifd::Zero, ORIENTATION, MetadataField::Exif),
+            (Ifd::Exif, EXIF_VERSION, MetadataField::Exif),
+            // Tag numbers only mean something in their own IFD.
+            (Ifd::Exif, ARTIST, MetadataField::Exif),
+            (Ifd::Zero, MAKER_NOTE, MetadataField::Exif),
+            (Ifd::Interop, BODY_SERIAL_NUMBER, MetadataField::Exif),
+        ];
+        for (ifd, tag, expected) in cases {
+            assert_eq!(field(ifd, tag), expected, "tag {tag:#06x} in {ifd:?}");
+        }
+    }
+
+    #[test]
+    fn retain_drops_empty_sub_ifds() {
+        let mut exif = Exif::parse(&sample(ByteOrder::Big)).unwrap();
+        let removed = exif.retain(|field| field == MetadataField::MakerNotes);
+        assert!(!removed.contains(&MetadataField::MakerNotes));
+        assert_eq!(tags(&exif.ifd0), [EXIF_IFD]);
+        assert_eq!(tags(ifd(&exif.ifd0, EXIF_IFD).unwrap()), [MAKER_NOTE]);
+
+        let removed = exif.retain(|_| false);
+        assert_eq!(removed, BTreeSet::from([MetadataField::MakerNotes]));
+        assert!(exif.is_empty());
+    }
+
+    #[test]
+    fn resets_orientation() {
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let mut exif = Exif::parse(&sample(order)).unwrap();
+            exif.reset_orientation();
+            let exif = Exif::parse(&exif.to_bytes()).unwrap();
+            let value = data(&exif.ifd0, ORIENTATION).unwrap();
+            assert_eq!(value.len(), 4);
+            assert_eq!(order.u16(value), 1);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let block = sample(ByteOrder::Little);
+        assert!(Exif::parse(&block[..60]).is_none());
+        assert!(Exif::parse(b"JFIF\0\0\0\x08").is_none());
+
+        // An Exif IFD pointing back at IFD0 nests without end.
+        let mut looped = block.clone();
+        let slot = 8 + 2 + 2 * 12 + 8;
+        looped[slot..slot + 4].copy_from_slice(&8u32.to_le_bytes());
+        assert!(Exif::parse(&looped).is_none());
+
+        // Values past the end of the block.
+        let mut truncated = block;
+        let slot = 8 + 2 + 12 + 8;
+        truncated[slot..slot + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(Exif::parse(&truncated).is_none());
+    }
+}
@@ -0,0 +1,82 @@
+//! Readiness report for the `ping` command.
+//!
+//! Gathers everything the UI needs to tell the user whether a job can run
+//! before they start one. Checks never fail the report; problems are
+//! returned as part of it.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::convert::{self, OutputFormat};
+use crate::input::{self, SourceFormat};
+use crate::ollama::{OllamaClient, OllamaConfig};
+
+/// The readiness check should answer quickly, so Ollama gets a short
+/// timeout and no retries.
+const OLLAMA_TIMEOUT_SECS: u64 = 3;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Health {
+    pub version: &'static str,
+    pub ollama: OllamaHealth,
+    /// Free bytes on the volume holding the target directory, if one was
+    /// given and could be checked.
+    pub free_disk_space: Option<u64>,
+    pub encoders: Vec<OutputFormat>,
+    pub decoders: Vec<SourceFormat>,
+    /// Source formats this build was compiled without, such as HEIC/HEIF
+    /// in builds without libheif. Their files are reported as unsupported.
+    pub missing_decoders: Vec<SourceFormat>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OllamaHealth {
+    pub endpoint: String,
+    pub reachable: bool,
+    /// The model jobs will use.
+    pub model: String,
+    pub model_installed: bool,
+    /// Models currently held in memory by Ollama.
+    pub loaded_models: Vec<String>,
+    pub error: Option<String>,
+}
+
+pub fn check(config: &OllamaConfig, target: Option<&Path>) -> Health {
+    Health {
+        version: env!("CARGO_PKG_VERSION"),
+        ollama: check_ollama(config),
+        free_disk_space: target.and_then(|dir| fs2::available_space(dir).ok()),
+        encoders: convert::available_encoders(),
+        decoders: input::available_decoders(),
+        missing_decoders: input::missing_decoders(),
+    }
+}
+
+fn check_ollama(config: &OllamaConfig) -> OllamaHealth {
+    let client = OllamaClient::new(OllamaConfig {
+        timeout: OLLAMA_TIMEOUT_SECS,
+        max_retries: 0,
+        ..config.clone()
+    });
+    let mut health = OllamaHealth {
+        endpoint: config.endpoint.clone(),
+        reachable: false,
+        model: config.model.clone(),
+        model_installed: false,
+        loaded_models: Vec::new(),
+        error: None,
+    };
+    let result = client.tags().and_then(|installed| {
+        health.reachable = true;
+        health.model_installed = installed.iter().any(|m| m.is_named(&config.model));
+        client.loaded_models()
+    });
+    match result {
+        Ok(loaded) => health.loaded_models = loaded,
+        Err(e) => health.error = Some(e.to_string()),
+    }
+    health
+}
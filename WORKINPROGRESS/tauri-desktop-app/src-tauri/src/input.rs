@@ -0,0 +1,724 @@
+//! Source formats.
+//!
+//! The shell script's `find` only matched jpg/jpeg/png/gif/bmp, so iPhone
+//! HEIC exports and scanner TIFFs were left out without a word, and a
+//! `.png` that was really a JPEG was handed to the wrong decoder. Sources
+//! are recognised here by their signature, not their extension, and
+//! decoded into one [`Source`] whatever their container:
+//!
+//! - JPEG, PNG, GIF, BMP and TIFF through the `image` crate, with the
+//!   further pages of a multi-page TIFF read through the `tiff` crate;
+//! - HEIC/HEIF through libheif, in builds with the `heif` feature;
+//! - CR2, NEF, ARW and DNG through the JPEG preview embedded in them (see
+//!   [`crate::raw`]);
+//! - SVG rendered with resvg (see [`crate::svg`]).
+//!
+//! Files that are picked up but whose signature is unknown, or names a
+//! format this build cannot decode, fail with [`ConvertError::Unsupported`]
+//! and are reported as such. Damaged files of a supported format fail to
+//! decode and are reported as failed.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use image::error::{DecodingError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, GrayAlphaImage, GrayImage, ImageBuffer, ImageDecoder, ImageError, ImageFormat,
+    ImageReader, RgbImage, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult};
+use tiff::tags::Tag;
+
+use crate::animation::Animation;
+use crate::convert::ConvertError;
+use crate::exif;
+use crate::metadata::{self, SourceMetadata};
+use crate::raw;
+use crate::svg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SourceFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Bmp,
+    Tiff,
+    Heif,
+    /// A camera RAW file, read through its embedded preview.
+    Raw,
+    Svg,
+}
+
+/// Source extensions, and the format each one names. Files whose signature
+/// is not recognised are still picked up by these, so they are reported
+/// instead of being skipped.
+const EXTENSIONS: &[(&str, SourceFormat)] = &[
+    ("jpg", SourceFormat::Jpeg),
+    ("jpeg", SourceFormat::Jpeg),
+    ("png", SourceFormat::Png),
+    ("gif", SourceFormat::Gif),
+    ("bmp", SourceFormat::Bmp),
+    ("tif", SourceFormat::Tiff),
+    ("tiff", SourceFormat::Tiff),
+    ("heic", SourceFormat::Heif),
+    ("heif", SourceFormat::Heif),
+    ("cr2", SourceFormat::Raw),
+    ("nef", SourceFormat::Raw),
+    ("arw", SourceFormat::Raw),
+    ("dng", SourceFormat::Raw),
+    ("svg", SourceFormat::Svg),
+];
+
+/// Bytes read from the start of a file to detect its format; enough for
+/// the first IFD of a TIFF-based RAW file.
+const SNIFF_LEN: u64 = 4096;
+
+/// ISO-BMFF major brands of HEIF images and sequences.
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+/// Sizes of the BMP info headers decoders understand.
+const BMP_HEADER_SIZES: &[u32] = &[12, 40, 52, 56, 64, 108, 124];
+
+impl SourceFormat {
+    /// The format `path` claims to be, going by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        EXTENSIONS
+            .iter()
+            .find(|(supported, _)| ext.eq_ignore_ascii_case(supported))
+            .map(|&(_, format)| format)
+    }
+
+    /// The `image` crate format that decodes this source directly.
+    fn image_format(self) -> Option<ImageFormat> {
+        match self {
+            SourceFormat::Jpeg => Some(ImageFormat::Jpeg),
+            SourceFormat::Png => Some(ImageFormat::Png),
+            SourceFormat::Gif => Some(ImageFormat::Gif),
+            SourceFormat::Bmp => Some(ImageFormat::Bmp),
+            SourceFormat::Tiff => Some(ImageFormat::Tiff),
+            SourceFormat::Heif | SourceFormat::Raw | SourceFormat::Svg => None,
+        }
+    }
+}
+
+impl fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SourceFormat::Jpeg => "JPEG",
+            SourceFormat::Png => "PNG",
+            SourceFormat::Gif => "GIF",
+            SourceFormat::Bmp => "BMP",
+            SourceFormat::Tiff => "TIFF",
+            SourceFormat::Heif => "HEIF",
+            SourceFormat::Raw => "RAW",
+            SourceFormat::Svg => "SVG",
+        })
+    }
+}
+
+/// Source formats that are picked up but that this build cannot decode, so
+/// their files are reported as unsupported.
+pub fn missing_decoders() -> Vec<SourceFormat> {
+    if cfg!(feature = "heif") {
+        Vec::new()
+    } else {
+        vec![SourceFormat::Heif]
+    }
+}
+
+/// Source formats this build can decode.
+pub fn available_decoders() -> Vec<SourceFormat> {
+    let mut decoders = vec![
+        SourceFormat::Jpeg,
+        SourceFormat::Png,
+        SourceFormat::Gif,
+        SourceFormat::Bmp,
+        SourceFormat::Tiff,
+        SourceFormat::Raw,
+        SourceFormat::Svg,
+    ];
+    if cfg!(feature = "heif") {
+        decoders.push(SourceFormat::Heif);
+    }
+    decoders
+}
+
+/// The format of a file starting with `header`, going by its signature.
+/// TIFF-based RAW files without a telltale tag are detected as TIFF.
+fn sniff(header: &[u8]) -> Option<SourceFormat> {
+    if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(SourceFormat::Jpeg)
+    } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(SourceFormat::Png)
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        Some(SourceFormat::Gif)
+    } else if is_bmp(header) {
+        Some(SourceFormat::Bmp)
+    } else if header.get(4..8) == Some(b"ftyp")
+        && header
+            .get(8..12)
+            .is_some_and(|brand| HEIF_BRANDS.iter().any(|heif| &heif[..] == brand))
+    {
+        Some(SourceFormat::Heif)
+    } else if svg::is_svg(header) {
+        Some(SourceFormat::Svg)
+    } else if raw::is_raw(header) {
+        Some(SourceFormat::Raw)
+    } else if exif::ByteOrder::detect(header).is_some() {
+        Some(SourceFormat::Tiff)
+    } else {
+        None
+    }
+}
+
+/// `BM` alone is too common a start for text files; the reserved fields
+/// and the info header size have to match too.
+fn is_bmp(header: &[u8]) -> bool {
+    header.starts_with(b"BM")
+        && header.get(6..10) == Some(&[0; 4])
+        && header.get(14..18).is_some_and(|size| {
+            BMP_HEADER_SIZES.contains(&u32::from_le_bytes([size[0], size[1], size[2], size[3]]))
+        })
+}
+
+/// The format of `path`, whose first bytes are `header`: its signature,
+/// falling back to its extension. RAW files whose first IFD does not give
+/// them away are told from a TIFF by their extension. Files with an image
+/// extension but no recognised signature are detected by the extension, so
+/// they fail with [`ConvertError::Unsupported`] rather than being skipped.
+pub fn detect(header: &[u8], path: &Path) -> Option<SourceFormat> {
+    let claimed = SourceFormat::from_path(path);
+    match sniff(header) {
+        Some(SourceFormat::Tiff) if claimed == Some(SourceFormat::Raw) => claimed,
+        Some(format) => Some(format),
+        None => claimed,
+    }
+}
+
+/// Reads the start of `path` and detects its format; `None` for files that
+/// are not images.
+pub fn detect_file(path: &Path) -> io::Result<Option<SourceFormat>> {
+    let mut header = Vec::new();
+    fs::File::open(path)?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut header)?;
+    Ok(detect(&header, path))
+}
+
+/// A source whose extension names a different format than its contents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatMismatch {
+    pub path: PathBuf,
+    /// The extension, without the dot.
+    pub extension: String,
+    /// The format the contents were detected as.
+    pub format: SourceFormat,
+}
+
+impl FormatMismatch {
+    /// The mismatch between `path`'s extension and its detected `format`,
+    /// if any. Files without an extension never mismatch.
+    pub fn check(path: &Path, format: SourceFormat) -> Option<Self> {
+        let extension = path.extension()?;
+        (SourceFormat::from_path(path) != Some(format)).then(|| Self {
+            path: path.to_path_buf(),
+            extension: extension.to_string_lossy().into_owned(),
+            format,
+        })
+    }
+}
+
+impl fmt::Display for FormatMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' has a .{} extension but is a {} file",
+            self.path.display(),
+            self.extension,
+            self.format
+        )
+    }
+}
+
+/// Which pages of a multi-page TIFF are converted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TiffPages {
+    #[default]
+    First,
+    /// Every page; the second and later ones are written as
+    /// `name-page2.ext` and so on.
+    All,
+}
+
+/// How much of an animated GIF or APNG is decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frames {
+    /// Only up to the representative frame, which becomes the image.
+    Still,
+    /// Every frame, for an animated output.
+    All,
+}
+
+/// A further page of a multi-page TIFF.
+pub struct Page {
+    /// The page's pixels, upright.
+    pub image: DynamicImage,
+    pub icc: Option<Vec<u8>>,
+}
+
+/// A decoded source.
+pub struct Source {
+    /// The image, or the representative frame of an animation, as stored:
+    /// `orientation` has not been applied yet.
+    pub image: DynamicImage,
+    pub orientation: Orientation,
+    pub metadata: SourceMetadata,
+    /// Every frame of an animation, if they were asked for.
+    pub animation: Option<Animation>,
+    /// Pages after the first, if all pages of a TIFF were requested.
+    pub pages: Vec<Page>,
+}
+
+const UNRECOGNISED: &str = "not a recognised image format";
+
+fn unsupported(reason: impl Into<String>) -> ConvertError {
+    ConvertError::Unsupported(reason.into())
+}
+
+/// A damaged file of a format that can otherwise be decoded.
+fn damaged(format: ImageFormatHint, reason: impl Into<String>) -> ConvertError {
+    ConvertError::Decode(ImageError::Decoding(DecodingError::new(
+        format,
+        reason.into(),
+    )))
+}
+
+fn tiff_error(e: tiff::TiffError) -> ConvertError {
+    ConvertError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Tiff),
+        e,
+    )))
+}
+
+/// Reads and decodes `path`. SVGs are rendered at [`svg::DECODE_SIZE`].
+pub fn read(path: &Path, pages: TiffPages, frames: Frames) -> Result<Source, ConvertError> {
+    let data = fs::read(path)?;
+    let format = detect(&data, path).ok_or_else(|| unsupported(UNRECOGNISED))?;
+    let source = decode(&data, path, format, pages, frames);
+    if sniff(&data).is_some() {
+        return source;
+    }
+    // Without a signature the format was only guessed from the extension,
+    // so a file that does not decode as that format is not a damaged one.
+    source.map_err(|e| match e {
+        ConvertError::Decode(_) => unsupported(UNRECOGNISED),
+        e => e,
+    })
+}
+
+fn decode(
+    data: &[u8],
+    path: &Path,
+    format: SourceFormat,
+    pages: TiffPages,
+    frames: Frames,
+) -> Result<Source, ConvertError> {
+    match format {
+        SourceFormat::Heif => decode_heif(data),
+        SourceFormat::Svg => Ok(Source {
+            image: svg::decode(data, path)?,
+            orientation: Orientation::NoTransforms,
+            metadata: SourceMetadata::default(),
+            animation: None,
+            pages: Vec::new(),
+        }),
+        SourceFormat::Raw => {
+            let preview = raw::preview(data)
+                .ok_or_else(|| unsupported("no embedded JPEG preview was found"))?;
+            let mut source = decode_image(&data[preview.range], ImageFormat::Jpeg, frames)?;
+            source.orientation = preview.orientation;
+            Ok(source)
+        }
+        _ => {
+            let image_format = format.image_format().expect("decoded by the image crate");
+            let mut source = decode_image(data, image_format, frames)?;
+            if format == SourceFormat::Tiff && pages == TiffPages::All {
+                source.pages = tiff_pages(data)?;
+            }
+            Ok(source)
+        }
+    }
+}
+
+fn decode_image(data: &[u8], format: ImageFormat, frames: Frames) -> Result<Source, ConvertError> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    reader.set_format(format);
+    let mut decoder = reader.into_decoder()?;
+    let metadata = SourceMetadata {
+        exif: decoder.exif_metadata()?,
+        icc: decoder.icc_profile()?,
+        xmp: metadata::read_xmp(data),
+        iptc: metadata::read_iptc(data),
+    };
+    let orientation = decoder.orientation()?;
+    let (animation, frame) = match frames {
+        Frames::All => {
+            let animation = Animation::decode(data, format)?;
+            let frame = animation.as_ref().map(Animation::representative);
+            (animation, frame)
+        }
+        Frames::Still => (None, Animation::decode_representative(data, format)?),
+    };
+    let image = match frame {
+        Some(frame) => DynamicImage::ImageRgba8(frame),
+        None => DynamicImage::from_decoder(decoder)?,
+    };
+    Ok(Source {
+        image,
+        orientation,
+        metadata,
+        animation,
+        pages: Vec::new(),
+    })
+}
+
+/// The dimensions of each page of `path` that would be converted, once
+/// its orientation is applied. Only the headers are read, except for RAW
+/// files, whose preview has to be found first.
+pub fn upright_dimensions(path: &Path, pages: TiffPages) -> Result<Vec<(u32, u32)>, ConvertError> {
+    let format = detect_file(path)?.ok_or_else(|| unsupported("not a recognised image format"))?;
+    match format {
+        SourceFormat::Heif => Ok(vec![heif_dimensions(&fs::read(path)?)?]),
+        SourceFormat::Svg => Ok(vec![svg::dimensions(path)?]),
+        SourceFormat::Raw => {
+            let preview = raw::preview(&fs::read(path)?)
+                .ok_or_else(|| unsupported("no embedded JPEG preview was found"))?;
+            Ok(vec![upright(
+                (preview.width, preview.height),
+                preview.orientation,
+            )])
+        }
+        _ => {
+            let mut reader = ImageReader::new(BufReader::new(fs::File::open(path)?));
+            reader.set_format(format.image_format().expect("decoded by the image crate"));
+            let mut decoder = reader.into_decoder()?;
+            let mut dimensions = vec![upright(decoder.dimensions(), decoder.orientation()?)];
+            if format == SourceFormat::Tiff && pages == TiffPages::All {
+                let mut tiff =
+                    TiffDecoder::new(BufReader::new(fs::File::open(path)?)).map_err(tiff_error)?;
+                while tiff.more_images() {
+                    tiff.next_image().map_err(tiff_error)?;
+                    let size = tiff.dimensions().map_err(tiff_error)?;
+                    dimensions.push(upright(size, tiff_orientation(&mut tiff)));
+                }
+            }
+            Ok(dimensions)
+        }
+    }
+}
+
+fn upright((width, height): (u32, u32), orientation: Orientation) -> (u32, u32) {
+    match orientation {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    }
+}
+
+fn tiff_orientation<R: std::io::Read + std::io::Seek>(tiff: &mut TiffDecoder<R>) -> Orientation {
+    tiff.find_tag_unsigned::<u8>(Tag::Orientation)
+        .ok()
+        .flatten()
+        .and_then(Orientation::from_exif)
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+/// Decodes the second and later pages of the TIFF file `data`, upright.
+fn tiff_pages(data: &[u8]) -> Result<Vec<Page>, ConvertError> {
+    let mut tiff = TiffDecoder::new(Cursor::new(data)).map_err(tiff_error)?;
+    let mut pages = Vec::new();
+    while tiff.more_images() {
+        tiff.next_image().map_err(tiff_error)?;
+        let number = pages.len() + 2;
+        let (width, height) = tiff.dimensions().map_err(tiff_error)?;
+        let color = tiff.colortype().map_err(tiff_error)?;
+        let pixels = tiff.read_image().map_err(tiff_error)?;
+        let image = match (color, pixels) {
+            (tiff::ColorType::Gray(8), DecodingResult::U8(p)) => {
+                GrayImage::from_raw(width, height, p).map(DynamicImage::ImageLuma8)
+            }
+            (tiff::ColorType::GrayA(8), DecodingResult::U8(p)) => {
+                GrayAlphaImage::from_raw(width, height, p).map(DynamicImage::ImageLumaA8)
+            }
+            (tiff::ColorType::RGB(8), DecodingResult::U8(p)) => {
+                RgbImage::from_raw(width, height, p).map(DynamicImage::ImageRgb8)
+            }
+            (tiff::ColorType::RGBA(8), DecodingResult::U8(p)) => {
+                RgbaImage::from_raw(width, height, p).map(DynamicImage::ImageRgba8)
+            }
+            (tiff::ColorType::Gray(16), DecodingResult::U16(p)) => {
+                ImageBuffer::from_raw(width, height, p).map(DynamicImage::ImageLuma16)
+            }
+            (tiff::ColorType::GrayA(16), DecodingResult::U16(p)) => {
+                ImageBuffer::from_raw(width, height, p).map(DynamicImage::ImageLumaA16)
+            }
+            (tiff::ColorType::RGB(16), DecodingResult::U16(p)) => {
+                ImageBuffer::from_raw(width, height, p).map(DynamicImage::ImageRgb16)
+            }
+            (tiff::ColorType::RGBA(16), DecodingResult::U16(p)) => {
+                ImageBuffer::from_raw(width, height, p).map(DynamicImage::ImageRgba16)
+            }
+            (color, _) => {
+                return Err(unsupported(format!(
+                    "TIFF page {number} has an unsupported colour type ({color:?})"
+                )))
+            }
+        };
+        let mut image = image.ok_or_else(|| {
+            damaged(
+                ImageFormatHint::Exact(ImageFormat::Tiff),
+                format!("page {number} is truncated"),
+            )
+        })?;
+        image.apply_orientation(tiff_orientation(&mut tiff));
+        let icc = tiff
+            .find_tag(Tag::IccProfile)
+            .ok()
+            .flatten()
+            .and_then(|value| value.into_u8_vec().ok());
+        pages.push(Page { image, icc });
+    }
+    Ok(pages)
+}
+
+/// Content type of the `mime` item holding a HEIF file's XMP packet.
+#[cfg(feature = "heif")]
+const XMP_CONTENT_TYPE: &str = "application/rdf+xml";
+
+#[cfg(feature = "heif")]
+fn heif_error(e: libheif_rs::HeifError) -> ConvertError {
+    ConvertError::Decode(ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Name("HEIF".to_string()),
+        e,
+    )))
+}
+
+/// Decodes the primary image of a HEIF file. libheif applies the
+/// container's rotation and mirroring itself, and the EXIF orientation of
+/// a HEIF file is informational only, so the image comes out upright.
+#[cfg(feature = "heif")]
+fn decode_heif(data: &[u8]) -> Result<Source, ConvertError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_bytes(data).map_err(heif_error)?;
+    let handle = context.primary_image_handle().map_err(heif_error)?;
+    let alpha = handle.has_alpha_channel();
+    let chroma = if alpha {
+        RgbChroma::Rgba
+    } else {
+        RgbChroma::Rgb
+    };
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .map_err(heif_error)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or_else(|| unsupported("HEIF image has no interleaved RGB plane"))?;
+    let (width, height) = (plane.width, plane.height);
+    let row = width as usize * if alpha { 4 } else { 3 };
+    let mut pixels = Vec::with_capacity(row * height as usize);
+    for line in plane.data.chunks(plane.stride).take(height as usize) {
+        pixels.extend_from_slice(&line[..row]);
+    }
+    let image = if alpha {
+        RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+    }
+    .ok_or_else(|| {
+        damaged(
+            ImageFormatHint::Name("HEIF".to_string()),
+            "image is truncated",
+        )
+    })?;
+
+    let blocks = handle.all_metadata();
+    // HEIF EXIF blocks start with the offset of the TIFF header.
+    let exif = blocks
+        .iter()
+        .filter(|block| block.item_type.0 == *b"Exif")
+        .find_map(|block| {
+            let data = &block.raw_data;
+            let offset = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+            data.get(4 + offset..).map(<[u8]>::to_vec)
+        });
+    // XMP is stored as a `mime` item holding the bare packet.
+    let xmp = blocks
+        .iter()
+        .find(|block| block.item_type.0 == *b"mime" && block.content_type == XMP_CONTENT_TYPE)
+        .map(|block| block.raw_data.clone());
+    Ok(Source {
+        image,
+        orientation: Orientation::NoTransforms,
+        metadata: SourceMetadata {
+            exif,
+            icc: handle.color_profile_raw().map(|profile| profile.data),
+            xmp,
+            iptc: None,
+        },
+        animation: None,
+        pages: Vec::new(),
+    })
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_data: &[u8]) -> Result<Source, ConvertError> {
+    Err(unsupported(
+        "this build was compiled without HEIC/HEIF support",
+    ))
+}
+
+#[cfg(feature = "heif")]
+fn heif_dimensions(data: &[u8]) -> Result<(u32, u32), ConvertError> {
+    let context = libheif_rs::HeifContext::read_from_bytes(data).map_err(heif_error)?;
+    let handle = context.primary_image_handle().map_err(heif_error)?;
+    Ok((handle.width(), handle.height()))
+}
+
+#[cfg(not(feature = "heif"))]
+fn heif_dimensions(_data: &[u8]) -> Result<(u32, u32), ConvertError> {
+    Err(unsupported(
+        "this build was compiled without HEIC/HEIF support",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bmp_header(info_size: u32) -> Vec<u8> {
+        let mut header = b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0".to_vec();
+        header.extend_from_slice(&info_size.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn sniffs_signatures() {
+        let cases: &[(&[u8], Option<SourceFormat>)] = &[
+            (b"\xFF\xD8\xFF\xE0\0\x10JFIF", Some(SourceFormat::Jpeg)),
+            (b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR", Some(SourceFormat::Png)),
+            (b"GIF87a", Some(SourceFormat::Gif)),
+            (b"GIF89a\x01\0", Some(SourceFormat::Gif)),
+            (b"\0\0\0\x18ftypheic\0\0\0\0", Some(SourceFormat::Heif)),
+            (b"\0\0\0\x18ftypmif1\0\0\0\0", Some(SourceFormat::Heif)),
+            (b"\0\0\0\x18ftypisom\0\0\0\0", None),
+            (b"\0\0\0\x18ftypavif\0\0\0\0", None),
+            (
+                b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">",
+                Some(SourceFormat::Svg),
+            ),
+            (b"<?xml version=\"1.0\"?>\n<svg>", None),
+            (b"II*\0\x08\0\0\0\0\0", Some(SourceFormat::Tiff)),
+            (b"MM\0*\0\0\0\x08\0\0", Some(SourceFormat::Tiff)),
+            (b"II*\0\x10\0\0\0CR\x02\0", Some(SourceFormat::Raw)),
+            (b"RIFF\0\0\0\0WEBPVP8 ", None),
+            (b"", None),
+        ];
+        for &(header, format) in cases {
+            assert_eq!(
+                sniff(header),
+                format,
+                "{:?}",
+                String::from_utf8_lossy(header)
+            );
+        }
+    }
+
+    #[test]
+    fn tells_bmp_from_text() {
+        assert_eq!(sniff(&bmp_header(40)), Some(SourceFormat::Bmp));
+        assert_eq!(sniff(&bmp_header(124)), Some(SourceFormat::Bmp));
+        assert_eq!(sniff(&bmp_header(41)), None);
+        assert_eq!(sniff(b"BMW owners manual, page 1 ......"), None);
+        assert_eq!(sniff(b"BM"), None);
+    }
+
+    #[test]
+    fn detects_by_signature_then_extension() {
+        let tiff = b"II*\0\x08\0\0\0\0\0";
+        let png = b"\x89PNG\r\n\x1a\n";
+        let cases = [
+            (&png[..], "upload", Some(SourceFormat::Png)),
+            (png, "fake.JPG", Some(SourceFormat::Png)),
+            (tiff, "scan.tif", Some(SourceFormat::Tiff)),
+            // A RAW without a telltale tag is told apart by its extension.
+            (tiff, "photo.NEF", Some(SourceFormat::Raw)),
+            (tiff, "scan.png", Some(SourceFormat::Tiff)),
+            // Unrecognised contents fall back on the extension, so the
+            // file is reported rather than skipped.
+            (b"garbage", "broken.jpeg", Some(SourceFormat::Jpeg)),
+            (b"garbage", "notes.txt", None),
+            (b"garbage", "notes", None),
+        ];
+        for (header, name, format) in cases {
+            assert_eq!(detect(header, Path::new(name)), format, "{name}");
+        }
+    }
+
+    #[test]
+    fn reports_extension_mismatches() {
+        let check = |name: &str, format| FormatMismatch::check(Path::new(name), format);
+        assert_eq!(check("photo.jpg", SourceFormat::Jpeg), None);
+        assert_eq!(check("photo.JPEG", SourceFormat::Jpeg), None);
+        assert_eq!(check("scan.tiff", SourceFormat::Tiff), None);
+        assert_eq!(check("photo.dng", SourceFormat::Raw), None);
+        assert_eq!(check("upload", SourceFormat::Png), None);
+
+        let mismatch = check("dir/fake.PNG", SourceFormat::Jpeg).unwrap();
+        assert_eq!(mismatch.extension, "PNG");
+        assert_eq!(mismatch.format, SourceFormat::Jpeg);
+        assert_eq!(
+            mismatch.to_string(),
+            "'dir/fake.PNG' has a .PNG extension but is a JPEG file"
+        );
+        assert!(check("photo.tif", SourceFormat::Raw).is_some());
+        assert!(check("image.webp", SourceFormat::Png).is_some());
+    }
+
+    #[test]
+    fn damaged_files_are_not_unsupported() {
+        let dir = std::env::temp_dir().join(format!("input-{}-damaged", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut jpeg = Vec::new();
+        RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 128]))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+
+        let truncated = dir.join("truncated.jpg");
+        fs::write(&truncated, &jpeg[..jpeg.len() / 3]).unwrap();
+        let e = read(&truncated, TiffPages::First, Frames::Still)
+            .err()
+            .unwrap();
+        assert!(!e.is_unsupported(), "{e}");
+
+        let text = dir.join("notes.png");
+        fs::write(&text, "not an image").unwrap();
+        let e = read(&text, TiffPages::First, Frames::Still).err().unwrap();
+        assert!(e.is_unsupported(), "{e}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
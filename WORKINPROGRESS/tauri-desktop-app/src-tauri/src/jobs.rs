@@ -0,0 +1,118 @@
+//! Registry of running jobs.
+//!
+//! Lives in Tauri managed state so `cancel_job` and friends can reach a job
+//! started by an earlier command. Each job gets a [`JobControl`] that the
+//! pipeline checks before dispatching each file.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use serde::Serialize;
+
+pub type JobId = String;
+
+/// Whether `id` has the form [`JobRegistry::register`] hands out: a
+/// lowercase, hyphenated UUID. IDs coming from the UI are checked before
+/// they become part of a journal path.
+pub fn is_valid_id(id: &str) -> bool {
+    uuid::Uuid::try_parse(id).is_ok_and(|uuid| uuid.hyphenated().to_string() == id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    Running,
+    Paused,
+    Cancelling,
+    Cancelled,
+    Completed,
+    Failed,
+}
+
+/// Flags shared between a running pipeline and the commands controlling it.
+#[derive(Debug, Default)]
+pub struct JobControl {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl JobControl {
+    /// Asks the pipeline to stop. Files already being encoded are finished
+    /// but not committed; nothing new is started. Also wakes a paused job so
+    /// it can wind down.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let _paused = self.paused.lock().unwrap();
+        self.resumed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Stops dispatching new files; files already in flight still finish.
+    /// Returns false if the job was already paused.
+    pub fn pause(&self) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        !std::mem::replace(&mut *paused, true)
+    }
+
+    /// Returns false if the job was not paused.
+    pub fn resume(&self) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        let was_paused = std::mem::replace(&mut *paused, false);
+        self.resumed.notify_all();
+        was_paused
+    }
+
+    /// Called by workers before taking the next file. Blocks while the job
+    /// is paused and returns false once it has been cancelled.
+    pub fn should_continue(&self) -> bool {
+        let paused = self.paused.lock().unwrap();
+        let _paused = self
+            .resumed
+            .wait_while(paused, |paused| *paused && !self.is_cancelled())
+            .unwrap();
+        !self.is_cancelled()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<JobId, Arc<JobControl>>>,
+}
+
+impl JobRegistry {
+    /// Registers a new job and returns its ID and control handle.
+    pub fn register(&self) -> (JobId, Arc<JobControl>) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let control = Arc::new(JobControl::default());
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::clone(&control));
+        (id, control)
+    }
+
+    /// Registers a resumed job under its existing ID. Returns `None` if a job
+    /// with that ID is already running.
+    pub fn register_as(&self, id: &str) -> Option<Arc<JobControl>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.contains_key(id) {
+            return None;
+        }
+        let control = Arc::new(JobControl::default());
+        jobs.insert(id.to_string(), Arc::clone(&control));
+        Some(control)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<JobControl>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.jobs.lock().unwrap().remove(id);
+    }
+}
@@ -0,0 +1,565 @@
+//! Crash-safe, per-job journal.
+//!
+//! Each job appends one JSON record per line to `<app data>/jobs/<id>.jsonl`
+//! and syncs it before moving on: a header with the [`JobSpec`], then one
+//! record every time a file reaches a new [`FileStage`], and a final marker
+//! when the job completes or is rolled back. Replaying the file after a
+//! crash tells the pipeline exactly which files still need work. A torn
+//! last line (the app died mid-write) is ignored and cut off when the
+//! journal is resumed.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CachedDescription;
+use crate::jobs;
+use crate::pipeline::{JobSpec, OutputVariant};
+
+const EXTENSION: &str = "jsonl";
+
+/// How far a source file got through the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "stage",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum FileStage {
+    /// The outputs are written to temp files and are about to take the
+    /// claimed names in `outputs`, once the original has moved to `backup`
+    /// so that an output with the original's name can take it. Recorded
+    /// before anything is moved, so that a resumed job can undo a commit
+    /// that was cut short.
+    Staged {
+        backup: PathBuf,
+        outputs: Vec<OutputVariant>,
+    },
+    /// An interrupted commit was undone: the original is back in place and
+    /// the file is converted again.
+    Restored,
+    /// The original is in the backup folder and the outputs still have the
+    /// original's name.
+    BackedUp {
+        outputs: Vec<OutputVariant>,
+    },
+    /// The outputs are being moved to the AI-generated names in `renamed`,
+    /// which were claimed before the first move. A resumed job finishes
+    /// the moves.
+    Renaming {
+        outputs: Vec<OutputVariant>,
+        renamed: Vec<OutputVariant>,
+        #[serde(default)]
+        description: Option<CachedDescription>,
+    },
+    /// The outputs were given their AI-generated name; the file is done.
+    Renamed {
+        outputs: Vec<OutputVariant>,
+        /// The description the name was taken from.
+        #[serde(default)]
+        description: Option<CachedDescription>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+impl FileStage {
+    /// Files written for the source so far; empty if none are in place.
+    pub fn outputs(&self) -> &[OutputVariant] {
+        match self {
+            FileStage::BackedUp { outputs }
+            | FileStage::Renaming { outputs, .. }
+            | FileStage::Renamed { outputs, .. } => outputs,
+            FileStage::Staged { .. } | FileStage::Restored | FileStage::Failed { .. } => &[],
+        }
+    }
+
+    /// Every path the job may have written for the source: its outputs and,
+    /// while they are being committed or renamed, the names claimed for
+    /// them.
+    pub fn generated(&self) -> impl Iterator<Item = &Path> {
+        let claimed = match self {
+            FileStage::Staged { outputs, .. } => outputs.as_slice(),
+            FileStage::Renaming { renamed, .. } => renamed,
+            _ => &[],
+        };
+        self.outputs()
+            .iter()
+            .chain(claimed)
+            .map(|output| output.path.as_path())
+    }
+
+    /// Whether the source was converted and its original backed up.
+    pub fn is_converted(&self) -> bool {
+        matches!(
+            self,
+            FileStage::BackedUp { .. } | FileStage::Renaming { .. } | FileStage::Renamed { .. }
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(
+    tag = "record",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+enum Record {
+    Job(JobSpec),
+    File { source: PathBuf, stage: FileStage },
+    Finished,
+    RolledBack,
+}
+
+/// A job journal opened for appending.
+#[derive(Debug)]
+pub struct Journal {
+    /// The directory holding this and every other job's journal.
+    dir: PathBuf,
+    file: Mutex<File>,
+    stages: Mutex<HashMap<PathBuf, FileStage>>,
+}
+
+/// A journal found on disk whose job never reached [`Journal::finish`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnfinishedJob {
+    pub id: String,
+    pub spec: JobSpec,
+    /// Files converted so far. Failed files and files whose commit was
+    /// interrupted are not counted.
+    pub files_done: usize,
+}
+
+/// The journal of job `id` in `dir`. Fails for anything but an ID made by
+/// the job registry, so an ID like `../x` cannot point outside `dir`.
+fn path_for(dir: &Path, id: &str) -> io::Result<PathBuf> {
+    if !jobs::is_valid_id(id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{id}' is not a valid job ID."),
+        ));
+    }
+    Ok(dir.join(format!("{id}.{EXTENSION}")))
+}
+
+impl Journal {
+    /// Starts a new journal for job `id` in `dir`.
+    pub fn create(dir: &Path, id: &str, spec: &JobSpec) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(path_for(dir, id)?)?;
+        let journal = Self {
+            dir: dir.to_path_buf(),
+            file: Mutex::new(file),
+            stages: Mutex::default(),
+        };
+        journal.append(&Record::Job(spec.clone()))?;
+        Ok(journal)
+    }
+
+    /// Reopens the journal of an interrupted job, returning its spec.
+    ///
+    /// Fails if the journal does not exist or the job already finished or
+    /// was rolled back.
+    pub fn resume(dir: &Path, id: &str) -> io::Result<(Self, JobSpec)> {
+        let path = path_for(dir, id)?;
+        let replay = replay(&path)?;
+        if replay.finished || replay.rolled_back {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Job '{id}' already finished."),
+            ));
+        }
+        Self::reopen(dir, &path, replay)
+    }
+
+    /// Reopens the journal of a finished or interrupted job so it can be
+    /// rolled back. Fails if it was already rolled back.
+    pub fn open_for_rollback(dir: &Path, id: &str) -> io::Result<(Self, JobSpec)> {
+        let path = path_for(dir, id)?;
+        let replay = replay(&path)?;
+        if replay.rolled_back {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Job '{id}' was already rolled back."),
+            ));
+        }
+        Self::reopen(dir, &path, replay)
+    }
+
+    /// Cuts off a torn last line and opens the journal for appending.
+    fn reopen(dir: &Path, path: &Path, replay: Replay) -> io::Result<(Self, JobSpec)> {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(replay.valid_len)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        let journal = Self {
+            dir: dir.to_path_buf(),
+            file: Mutex::new(file),
+            stages: Mutex::new(replay.stages),
+        };
+        Ok((journal, replay.spec))
+    }
+
+    /// Lists journals in `dir` whose jobs were interrupted.
+    pub fn list_unfinished(dir: &Path) -> io::Result<Vec<UnfinishedJob>> {
+        let mut jobs = Vec::new();
+        for path in journal_paths(dir)? {
+            let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|id| jobs::is_valid_id(id))
+            else {
+                continue;
+            };
+            // Unreadable journals are skipped rather than failing the listing.
+            if let Ok(replay) = replay(&path) {
+                if !replay.finished && !replay.rolled_back {
+                    jobs.push(UnfinishedJob {
+                        id: id.to_string(),
+                        spec: replay.spec,
+                        files_done: replay
+                            .stages
+                            .values()
+                            .filter(|stage| stage.is_converted())
+                            .count(),
+                    });
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Every file generated by the jobs journaled in `dir` that were not
+    /// rolled back, so a later job does not take them for new sources.
+    pub fn outputs_in(dir: &Path) -> io::Result<HashSet<PathBuf>> {
+        let mut outputs = HashSet::new();
+        for path in journal_paths(dir)? {
+            // Unreadable journals are skipped, as in `list_unfinished`.
+            let Ok(replay) = replay(&path) else {
+                continue;
+            };
+            if !replay.rolled_back {
+                outputs.extend(
+                    replay
+                        .stages
+                        .values()
+                        .flat_map(FileStage::generated)
+                        .map(Path::to_path_buf),
+                );
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// The directory this journal is kept in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Snapshot of the latest stage recorded for each source file.
+    pub fn stages(&self) -> HashMap<PathBuf, FileStage> {
+        self.stages.lock().unwrap().clone()
+    }
+
+    pub fn record(&self, source: &Path, stage: FileStage) -> io::Result<()> {
+        self.append(&Record::File {
+            source: source.to_path_buf(),
+            stage: stage.clone(),
+        })?;
+        self.stages
+            .lock()
+            .unwrap()
+            .insert(source.to_path_buf(), stage);
+        Ok(())
+    }
+
+    /// Marks the job as complete so it is no longer offered for resuming.
+    pub fn finish(&self) -> io::Result<()> {
+        self.append(&Record::Finished)
+    }
+
+    /// Marks the job as undone so it can be neither resumed nor rolled back
+    /// again.
+    pub fn mark_rolled_back(&self) -> io::Result<()> {
+        self.append(&Record::RolledBack)
+    }
+
+    fn append(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+/// The journal files in `dir`; none if it does not exist yet.
+fn journal_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(EXTENSION) {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+struct Replay {
+    spec: JobSpec,
+    /// Latest stage of every file.
+    stages: HashMap<PathBuf, FileStage>,
+    finished: bool,
+    rolled_back: bool,
+    /// Length in bytes of the complete, parseable records.
+    valid_len: u64,
+}
+
+/// Reads a journal back, stopping at the first torn or unparseable line.
+fn replay(path: &Path) -> io::Result<Replay> {
+    let contents = fs::read(path)?;
+    let mut spec = None;
+    let mut stages = HashMap::new();
+    let mut finished = false;
+    let mut rolled_back = false;
+    let mut valid_len = 0;
+    for line in contents.split_inclusive(|&b| b == b'\n') {
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        let Ok(record) = serde_json::from_slice::<Record>(line) else {
+            break;
+        };
+        match record {
+            Record::Job(job) => spec = Some(job),
+            Record::File { source, stage } => {
+                stages.insert(source, stage);
+            }
+            Record::Finished => finished = true,
+            Record::RolledBack => rolled_back = true,
+        }
+        valid_len += line.len() as u64;
+    }
+    let spec = spec.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Journal '{}' has no job header.", path.display()),
+        )
+    })?;
+    Ok(Replay {
+        spec,
+        stages,
+        finished,
+        rolled_back,
+        valid_len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{ConvertOptions, OutputFormat};
+
+    /// A fresh, empty directory for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("journal-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    fn spec(root: &Path) -> JobSpec {
+        JobSpec::new(
+            root.to_path_buf(),
+            ConvertOptions::default(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    fn backed_up(path: &str) -> FileStage {
+        FileStage::BackedUp {
+            outputs: vec![OutputVariant {
+                format: OutputFormat::Webp,
+                page: None,
+                path: PathBuf::from(path),
+                bytes: 100,
+                width: 8,
+                height: 8,
+                ladder: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn replays_latest_stages() {
+        let dir = test_dir("replay");
+        let journal = Journal::create(&dir, ID, &spec(Path::new("/photos"))).unwrap();
+        journal
+            .record(
+                Path::new("a.jpg"),
+                FileStage::Staged {
+                    backup: PathBuf::from("backup/a.jpg"),
+                    outputs: Vec::new(),
+                },
+            )
+            .unwrap();
+        journal
+            .record(Path::new("a.jpg"), backed_up("a.webp"))
+            .unwrap();
+        let failed = FileStage::Failed {
+            reason: "broken".into(),
+        };
+        journal.record(Path::new("b.png"), failed.clone()).unwrap();
+        let stages = journal.stages();
+        drop(journal);
+
+        let (journal, spec) = Journal::resume(&dir, ID).unwrap();
+        assert_eq!(spec.root, Path::new("/photos"));
+        assert_eq!(journal.stages(), stages);
+        assert_eq!(stages[Path::new("a.jpg")], backed_up("a.webp"));
+        assert_eq!(stages[Path::new("b.png")], failed);
+        assert_eq!(
+            Journal::outputs_in(&dir).unwrap(),
+            HashSet::from([PathBuf::from("a.webp")])
+        );
+        let unfinished = Journal::list_unfinished(&dir).unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(
+            (unfinished[0].id.as_str(), unfinished[0].files_done),
+            (ID, 1)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cuts_off_torn_line() {
+        let dir = test_dir("torn");
+        let journal = Journal::create(&dir, ID, &spec(Path::new("/photos"))).unwrap();
+        journal
+            .record(Path::new("a.jpg"), backed_up("a.webp"))
+            .unwrap();
+        drop(journal);
+        let path = path_for(&dir, ID).unwrap();
+        let valid_len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"record":"file","source":"b.jpg","st"#)
+            .unwrap();
+        drop(file);
+
+        assert_eq!(Journal::list_unfinished(&dir).unwrap()[0].files_done, 1);
+        let (journal, _) = Journal::resume(&dir, ID).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+        journal
+            .record(Path::new("b.jpg"), backed_up("b.webp"))
+            .unwrap();
+        drop(journal);
+
+        // The record after the cut is read back too.
+        let (journal, _) = Journal::resume(&dir, ID).unwrap();
+        assert_eq!(journal.stages().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stops_at_unparseable_line() {
+        let dir = test_dir("garbage");
+        let journal = Journal::create(&dir, ID, &spec(Path::new("/photos"))).unwrap();
+        journal
+            .record(Path::new("a.jpg"), backed_up("a.webp"))
+            .unwrap();
+        journal
+            .file
+            .lock()
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+        journal
+            .record(Path::new("b.jpg"), backed_up("b.webp"))
+            .unwrap();
+        drop(journal);
+
+        let (journal, _) = Journal::resume(&dir, ID).unwrap();
+        assert_eq!(
+            journal.stages().keys().collect::<Vec<_>>(),
+            [Path::new("a.jpg")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finished_and_rolled_back_jobs() {
+        let dir = test_dir("finished");
+        let journal = Journal::create(&dir, ID, &spec(Path::new("/photos"))).unwrap();
+        journal
+            .record(Path::new("a.jpg"), backed_up("a.webp"))
+            .unwrap();
+        journal.finish().unwrap();
+        drop(journal);
+
+        let err = Journal::resume(&dir, ID).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(Journal::list_unfinished(&dir).unwrap().is_empty());
+        assert_eq!(Journal::outputs_in(&dir).unwrap().len(), 1);
+
+        let (journal, _) = Journal::open_for_rollback(&dir, ID).unwrap();
+        journal.mark_rolled_back().unwrap();
+        drop(journal);
+        assert!(Journal::open_for_rollback(&dir, ID).is_err());
+        assert!(Journal::outputs_in(&dir).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_journals_without_header() {
+        let dir = test_dir("headless");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(path_for(&dir, ID).unwrap(), "{\"record\":\"finished\"}\n").unwrap();
+        let err = Journal::resume(&dir, ID).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(Journal::list_unfinished(&dir).unwrap().is_empty());
+        assert!(Journal::outputs_in(&dir).unwrap().is_empty());
+        // No journal directory yet is not an error.
+        assert!(Journal::outputs_in(&dir.join("missing"))
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_ids_the_registry_never_made() {
+        let dir = test_dir("ids");
+        let uppercase = ID.to_uppercase();
+        let braced = format!("{{{ID}}}");
+        for id in ["../job", "job", &uppercase, &braced] {
+            let err = Journal::resume(&dir, id).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{id}");
+            let err = Journal::open_for_rollback(&dir, id).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{id}");
+        }
+        assert!(Journal::create(&dir, "../job", &spec(Path::new("/photos"))).is_err());
+        // Journals under another name are not offered for resuming.
+        Journal::create(&dir, ID, &spec(Path::new("/photos"))).unwrap();
+        fs::copy(path_for(&dir, ID).unwrap(), dir.join("job.jsonl")).unwrap();
+        let unfinished = Journal::list_unfinished(&dir).unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].id, ID);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+//! JPEG output.
+//!
+//! The `image` crate only writes baseline JPEGs with the standard Huffman
+//! tables. Newsletters and marketplaces that only take JPEG get what the
+//! Python optimizer got instead, from the same library: mozjpeg, built from
+//! source by the `mozjpeg` crate (with SIMD if `nasm` is installed, without
+//! it otherwise). On top of libjpeg it adds:
+//!
+//! - progressive scans, with the scan script picked per image;
+//! - trellis quantisation, which picks each block's coefficients for the
+//!   best trade-off between size and error instead of rounding them;
+//! - Huffman tables built from the image's own statistics;
+//! - quantisation tables tuned for photos, scaled by quality like
+//!   libjpeg's.
+//!
+//! Chroma subsampling is 4:4:4, 4:2:2 or 4:2:0, and grayscale sources are
+//! written with a single component.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use image::DynamicImage;
+use mozjpeg::{ColorSpace, Compress};
+use serde::{Deserialize, Serialize};
+
+use crate::convert::{ConvertError, OutputFormat};
+
+/// Largest width or height a JPEG can have.
+pub const JPEG_MAX_DIMENSION: u32 = 65_535;
+
+/// How much colour resolution is kept relative to brightness.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChromaSubsampling {
+    /// Full colour resolution, for graphics with sharp coloured edges.
+    Yuv444,
+    /// Half the horizontal colour resolution.
+    Yuv422,
+    /// Half the colour resolution both ways, like mozjpeg and most cameras.
+    #[default]
+    Yuv420,
+}
+
+impl ChromaSubsampling {
+    /// Width and height of a chroma sample in luma pixels.
+    fn chroma_size(self) -> (u8, u8) {
+        match self {
+            ChromaSubsampling::Yuv444 => (1, 1),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv420 => (2, 2),
+        }
+    }
+}
+
+/// JPEG encoder settings.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct JpegOptions {
+    /// Quality, 1-100 (`quality.jpeg` in `config.yaml`).
+    pub quality: u8,
+    /// Write a progressive JPEG, which is usually smaller and shows a
+    /// coarse version of the image while it loads.
+    pub progressive: bool,
+    /// Use trellis quantisation. Files come out smaller at the same quality
+    /// setting, by a quarter on flat graphics, at about the same PSNR.
+    /// Turning it off gives libjpeg-turbo's output, which encodes several
+    /// times faster.
+    pub trellis: bool,
+    /// Chroma subsampling; ignored for grayscale sources.
+    pub subsampling: ChromaSubsampling,
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        Self {
+            quality: 90,
+            progressive: true,
+            trellis: true,
+            subsampling: ChromaSubsampling::default(),
+        }
+    }
+}
+
+/// Encodes `img` to an in-memory JPEG file. Transparent areas are flattened
+/// onto white, since JPEG has no alpha channel.
+pub fn encode(img: &DynamicImage, options: &JpegOptions) -> Result<Vec<u8>, ConvertError> {
+    let (width, height) = (img.width(), img.height());
+    let error = |reason: String| ConvertError::Encode {
+        format: OutputFormat::Jpeg,
+        reason,
+    };
+    if width == 0 || height == 0 || width > JPEG_MAX_DIMENSION || height > JPEG_MAX_DIMENSION {
+        return Err(error(format!(
+            "{width}x{height} is outside the JPEG size limits"
+        )));
+    }
+    let gray = !img.color().has_color();
+    let pixels = flatten(img, gray);
+
+    // libjpeg reports errors by unwinding out of the C code.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let color_space = if gray {
+            ColorSpace::JCS_GRAYSCALE
+        } else {
+            ColorSpace::JCS_RGB
+        };
+        let mut compress = Compress::new(color_space);
+        if !options.trellis {
+            compress.set_fastest_defaults();
+        }
+        compress.set_size(width as usize, height as usize);
+        compress.set_quality(f32::from(options.quality.clamp(1, 100)));
+        compress.set_optimize_coding(true);
+        if options.progressive {
+            compress.set_progressive_mode();
+        } else {
+            compress.set_optimize_scans(false);
+        }
+        if !gray {
+            let chroma = options.subsampling.chroma_size();
+            compress.set_chroma_sampling_pixel_sizes(chroma, chroma);
+        }
+        let mut started = compress.start_compress(Vec::new())?;
+        started.write_scanlines(&pixels)?;
+        started.finish()
+    }));
+    match result {
+        Ok(Ok(bytes)) => Ok(bytes),
+        Ok(Err(e)) => Err(error(e.to_string())),
+        Err(panic) => Err(error(
+            panic
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_else(|| "mozjpeg failed".to_string()),
+        )),
+    }
+}
+
+/// The samples of `img` as 8-bit gray or RGB, with any alpha flattened
+/// onto white.
+fn flatten(img: &DynamicImage, gray: bool) -> Vec<u8> {
+    if !img.color().has_alpha() {
+        return if gray {
+            img.to_luma8().into_raw()
+        } else {
+            img.to_rgb8().into_raw()
+        };
+    }
+    let channels = if gray { 1 } else { 3 };
+    let rgba = img.to_rgba8();
+    let mut out = Vec::with_capacity(rgba.len() / 4 * channels);
+    for px in rgba.pixels() {
+        let alpha = u32::from(px[3]);
+        for c in 0..channels {
+            out.push(((u32::from(px[c]) * alpha + 255 * (255 - alpha)) / 255) as u8);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+
+    /// Smooth gradients, optionally with a sharp-edged square so the high
+    /// AC coefficients carry data too.
+    fn test_image(width: u32, height: u32, square: bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let inside = x * 4 > width && x * 2 < width && y * 4 > height && y * 2 < height;
+            if square && inside {
+                Rgb([20, 40, 200])
+            } else {
+                Rgb([
+                    (x * 255 / width) as u8,
+                    (y * 255 / height) as u8,
+                    ((x + y) * 255 / (width + height)) as u8,
+                ])
+            }
+        }))
+    }
+
+    fn psnr(a: &[u8], b: &[u8]) -> f64 {
+        let mse = a
+            .iter()
+            .zip(b)
+            .map(|(&a, &b)| (f64::from(a) - f64::from(b)).powi(2))
+            .sum::<f64>()
+            / a.len() as f64;
+        10.0 * (255.0 * 255.0 / mse.max(1e-9)).log10()
+    }
+
+    fn round_trip(img: &DynamicImage, options: &JpegOptions) -> DynamicImage {
+        let bytes = encode(img, options).unwrap();
+        let decoded = image::load_from_memory_with_format(&bytes, image::ImageFormat::Jpeg)
+            .unwrap_or_else(|e| panic!("{options:?} at {}x{}: {e}", img.width(), img.height()));
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (img.width(), img.height())
+        );
+        decoded
+    }
+
+    /// Whether `jpeg` has a progressive (SOF2) frame header.
+    fn is_progressive(jpeg: &[u8]) -> bool {
+        jpeg.windows(2).any(|marker| marker == [0xFF, 0xC2])
+    }
+
+    #[test]
+    fn decodes_every_combination() {
+        let sizes = [(1, 1), (7, 9), (16, 16), (17, 33), (64, 48), (640, 480)];
+        let subsamplings = [
+            ChromaSubsampling::Yuv444,
+            ChromaSubsampling::Yuv422,
+            ChromaSubsampling::Yuv420,
+        ];
+        for (width, height) in sizes {
+            let img = test_image(width, height, false);
+            for subsampling in subsamplings {
+                for trellis in [false, true] {
+                    for progressive in [false, true] {
+                        let options = JpegOptions {
+                            quality: 90,
+                            progressive,
+                            trellis,
+                            subsampling,
+                        };
+                        let decoded = round_trip(&img, &options);
+                        let quality = psnr(img.as_bytes(), decoded.as_bytes());
+                        assert!(
+                            quality > 30.0,
+                            "{options:?} at {width}x{height}: {quality:.1} dB"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn progressive_only_when_asked() {
+        let img = test_image(64, 48, true);
+        for progressive in [false, true] {
+            let options = JpegOptions {
+                progressive,
+                ..JpegOptions::default()
+            };
+            let bytes = encode(&img, &options).unwrap();
+            assert_eq!(is_progressive(&bytes), progressive, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn trellis_makes_smaller_files() {
+        let img = test_image(256, 192, true);
+        let encoded = |trellis| {
+            let options = JpegOptions {
+                quality: 75,
+                trellis,
+                ..JpegOptions::default()
+            };
+            let bytes = encode(&img, &options).unwrap();
+            let decoded = image::load_from_memory(&bytes).unwrap();
+            (bytes.len(), psnr(img.as_bytes(), decoded.as_bytes()))
+        };
+        let (plain_size, plain_quality) = encoded(false);
+        let (trellis_size, trellis_quality) = encoded(true);
+        assert!(trellis_size < plain_size, "{trellis_size} >= {plain_size}");
+        assert!(
+            trellis_quality > plain_quality - 1.5,
+            "{trellis_quality:.1} dB, without trellis {plain_quality:.1} dB"
+        );
+    }
+
+    #[test]
+    fn grayscale_has_one_component() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(33, 17, |x, y| {
+            Luma([(x * 7 + y * 3) as u8])
+        }));
+        for progressive in [false, true] {
+            let options = JpegOptions {
+                progressive,
+                ..JpegOptions::default()
+            };
+            let decoded = round_trip(&img, &options);
+            assert_eq!(decoded.color(), image::ColorType::L8);
+            let quality = psnr(img.as_bytes(), decoded.as_bytes());
+            assert!(quality > 35.0, "{options:?}: {quality:.1} dB");
+        }
+    }
+
+    #[test]
+    fn flattens_transparency_onto_white() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([0, 0, 0, 0])));
+        let decoded = round_trip(&img, &JpegOptions::default()).to_rgb8();
+        assert!(decoded.pixels().all(|px| px.0.iter().all(|&c| c > 250)));
+    }
+
+    #[test]
+    fn quality_trades_size_for_error() {
+        let img = test_image(128, 96, true);
+        let encoded = |quality| {
+            let options = JpegOptions {
+                quality,
+                ..JpegOptions::default()
+            };
+            let bytes = encode(&img, &options).unwrap();
+            let decoded = image::load_from_memory(&bytes).unwrap();
+            (bytes.len(), psnr(img.as_bytes(), decoded.as_bytes()))
+        };
+        let (low_size, low_quality) = encoded(50);
+        let (high_size, high_quality) = encoded(95);
+        assert!(low_size < high_size);
+        assert!(low_quality < high_quality);
+    }
+
+    #[test]
+    fn rejects_oversized_images() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(JPEG_MAX_DIMENSION + 1, 1));
+        assert!(encode(&img, &JpegOptions::default()).is_err());
+    }
+}
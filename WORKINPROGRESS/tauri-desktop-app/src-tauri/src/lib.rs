@@ -1,49 +1,370 @@
-use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
+mod animation;
+mod cache;
+mod color;
+mod convert;
+pub mod events;
+mod exif;
+mod health;
+mod input;
+mod jobs;
+mod journal;
+mod jpeg;
+mod metadata;
+mod ollama;
+mod pipeline;
+mod plan;
+mod raw;
+mod recompress;
+mod rename;
+mod rollback;
+mod snippets;
+mod svg;
 
-#[tauri::command]
-async fn run_script(path: String, quality: u32, lossless: bool, app: tauri::AppHandle) -> Result<(), String> {
-    let mut cmd = Command::new("sh");
-    cmd.arg("src-tauri/resources/seo_image_processor.sh");
-    cmd.arg(&path);
-    if lossless {
-        cmd.arg("--lossless");
-    } else {
-        cmd.arg("--quality");
-        cmd.arg(quality.to_string());
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tauri::Manager;
+
+use cache::DescriptionCache;
+use convert::{ConvertOptions, OutputFormat};
+use events::JobEvent;
+use health::Health;
+use jobs::{JobControl, JobId, JobRegistry, JobStatus};
+use journal::{Journal, UnfinishedJob};
+use ollama::{OllamaClient, OllamaConfig, VisionModel};
+use pipeline::JobSpec;
+use plan::JobPlan;
+use rollback::RollbackReport;
+use snippets::SnippetOptions;
+
+fn app_data_dir(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    app.path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| "Could not resolve the app data directory.".to_string())
+}
+
+/// Directory holding the per-job journals.
+fn journal_dir(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(app_data_dir(app)?.join("jobs"))
+}
+
+fn description_cache_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(app_data_dir(app)?.join("descriptions.json"))
+}
+
+/// Ollama settings from the optional `ollama` and `model` command
+/// arguments, falling back to the defaults.
+fn ollama_config(ollama: Option<OllamaConfig>, model: Option<String>) -> OllamaConfig {
+    let mut config = ollama.unwrap_or_default();
+    if let Some(model) = model {
+        config.model = model;
     }
-    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().map_err(|e| e.to_string())?;
+    config
+}
 
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
+/// Job settings sent by the UI as the `options` argument of `start_job` and
+/// `plan_job`. Every field is optional and falls back to its default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct JobOptions {
+    #[serde(flatten)]
+    convert: ConvertOptions,
+    /// Overrides the model in `ollama`.
+    model: Option<String>,
+    ollama: Option<OllamaConfig>,
+    snippets: SnippetOptions,
+}
 
-    let app_clone = app.clone();
-    tauri::async_runtime::spawn(async move {
-        let reader = BufReader::new(stdout);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                app_clone.emit_all("log", line).unwrap();
-            }
+impl JobOptions {
+    /// Checks the options and builds the spec for a fresh job on `path`.
+    fn into_spec(self, path: &str) -> Result<JobSpec, String> {
+        let root = PathBuf::from(path);
+        if !root.is_dir() {
+            return Err(format!("Directory '{path}' not found."));
         }
-    });
+        let formats = &self.convert.formats;
+        if formats.is_empty() {
+            return Err("Select at least one output format.".to_string());
+        }
+        let available = convert::available_encoders();
+        if let Some(format) = formats.iter().find(|f| !available.contains(f)) {
+            return Err(format!("{format} output is not available in this build."));
+        }
+        let svg = &self.convert.svg;
+        if let Some(format) = svg
+            .formats
+            .iter()
+            .find(|f| !matches!(f, OutputFormat::Webp | OutputFormat::Png))
+        {
+            return Err(format!(
+                "SVGs cannot be rasterized to {format}; use WebP or PNG."
+            ));
+        }
+        if !svg.widths.is_empty() && svg.formats.is_empty() {
+            return Err("Select a format for the SVG raster copies.".to_string());
+        }
+        if let Some(fallback) = self.snippets.fallback.filter(|f| !formats.contains(f)) {
+            return Err(format!(
+                "The {fallback} fallback is not one of the selected output formats."
+            ));
+        }
+        let ollama = ollama_config(self.ollama, self.model);
+        Ok(JobSpec::new(root, self.convert, ollama, self.snippets))
+    }
+}
 
-    let app_clone = app.clone();
-    tauri::async_runtime::spawn(async move {
-        let reader = BufReader::new(stderr);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                app_clone.emit_all("log", format!("ERROR: {}", line)).unwrap();
+/// Runs `spec` on a background thread, reporting progress as job events and
+/// removing the job from the registry when it stops.
+fn spawn_job(
+    app: tauri::AppHandle,
+    id: JobId,
+    control: Arc<JobControl>,
+    spec: JobSpec,
+    journal: Journal,
+    cache_path: PathBuf,
+) {
+    std::thread::spawn(move || {
+        let emit = |event| events::emit(&app, &id, event);
+        emit(JobEvent::StatusChanged {
+            status: JobStatus::Running,
+        });
+        let result = DescriptionCache::load(&cache_path)
+            .and_then(|mut cache| pipeline::run(&spec, &journal, &mut cache, &control, &emit));
+        let status = match result {
+            Ok(_) if control.is_cancelled() => JobStatus::Cancelled,
+            Ok(_) => match journal.finish() {
+                Ok(()) => JobStatus::Completed,
+                Err(e) => {
+                    emit(JobEvent::JobFailed {
+                        reason: e.to_string(),
+                    });
+                    JobStatus::Failed
+                }
+            },
+            Err(e) => {
+                emit(JobEvent::JobFailed {
+                    reason: e.to_string(),
+                });
+                JobStatus::Failed
             }
-        }
+        };
+        app.state::<JobRegistry>().remove(&id);
+        emit(JobEvent::StatusChanged { status });
     });
+}
+
+/// Starts converting and renaming the directory in-process, without any
+/// external binaries, and returns the new job's ID. Progress is reported
+/// through [`events::JOB_EVENT`].
+#[tauri::command]
+fn start_job(
+    path: String,
+    options: JobOptions,
+    app: tauri::AppHandle,
+    jobs: tauri::State<'_, JobRegistry>,
+) -> Result<JobId, String> {
+    let spec = options.into_spec(&path)?;
+    let cache_path = description_cache_path(&app)?;
+    let (id, control) = jobs.register();
+    let journal = match Journal::create(&journal_dir(&app)?, &id, &spec) {
+        Ok(journal) => journal,
+        Err(e) => {
+            jobs.remove(&id);
+            return Err(e.to_string());
+        }
+    };
+    spawn_job(app, id.clone(), control, spec, journal, cache_path);
+    Ok(id)
+}
+
+/// Dry run: predicts what `start_job` would do with the same arguments
+/// without touching the disk.
+#[tauri::command]
+async fn plan_job(
+    path: String,
+    options: JobOptions,
+    app: tauri::AppHandle,
+) -> Result<JobPlan, String> {
+    let spec = options.into_spec(&path)?;
+    let cache_path = description_cache_path(&app)?;
+    let journal_dir = journal_dir(&app)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let cache = DescriptionCache::load(&cache_path)?;
+        let generated = Journal::outputs_in(&journal_dir)?;
+        plan::plan(&spec, &cache, &generated)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// Readiness report for the UI: backend version, Ollama status, free disk
+/// space in `path` and the available encoders.
+#[tauri::command]
+async fn ping(
+    path: Option<String>,
+    model: Option<String>,
+    ollama: Option<OllamaConfig>,
+) -> Result<Health, String> {
+    let ollama = ollama_config(ollama, model);
+    tauri::async_runtime::spawn_blocking(move || {
+        health::check(&ollama, path.as_deref().map(Path::new))
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Vision models installed in the local Ollama, for the model picker.
+#[tauri::command]
+async fn list_models(ollama: Option<OllamaConfig>) -> Result<Vec<VisionModel>, String> {
+    let client = OllamaClient::new(ollama.unwrap_or_default());
+    tauri::async_runtime::spawn_blocking(move || client.vision_models())
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Jobs whose journal shows they were interrupted, e.g. by a crash or a
+/// cancel, and can be continued with `resume_job`.
+#[tauri::command]
+fn unfinished_jobs(
+    app: tauri::AppHandle,
+    jobs: tauri::State<'_, JobRegistry>,
+) -> Result<Vec<UnfinishedJob>, String> {
+    let mut unfinished =
+        Journal::list_unfinished(&journal_dir(&app)?).map_err(|e| e.to_string())?;
+    unfinished.retain(|job| jobs.get(&job.id).is_none());
+    Ok(unfinished)
+}
+
+/// Rejects IDs the registry never handed out before they are used to find a
+/// journal on disk.
+fn check_id(id: &str) -> Result<(), String> {
+    if jobs::is_valid_id(id) {
+        Ok(())
+    } else {
+        Err(format!("'{id}' is not a valid job ID."))
+    }
+}
+
+fn running_job(jobs: &JobRegistry, id: &str) -> Result<Arc<JobControl>, String> {
+    jobs.get(id)
+        .ok_or_else(|| format!("No running job with ID '{id}'."))
+}
+
+/// Stops a running job after the files currently being encoded.
+#[tauri::command]
+fn cancel_job(
+    id: String,
+    app: tauri::AppHandle,
+    jobs: tauri::State<'_, JobRegistry>,
+) -> Result<(), String> {
+    let control = running_job(&jobs, &id)?;
+    control.cancel();
+    events::emit(
+        &app,
+        &id,
+        JobEvent::StatusChanged {
+            status: JobStatus::Cancelling,
+        },
+    );
+    Ok(())
+}
+
+/// Stops dispatching new files; files already being encoded still finish.
+#[tauri::command]
+fn pause_job(
+    id: String,
+    app: tauri::AppHandle,
+    jobs: tauri::State<'_, JobRegistry>,
+) -> Result<(), String> {
+    let control = running_job(&jobs, &id)?;
+    if control.pause() {
+        events::emit(
+            &app,
+            &id,
+            JobEvent::StatusChanged {
+                status: JobStatus::Paused,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Resumes a paused job. If no job with that ID is running, the job is
+/// restarted from its on-disk journal instead, continuing exactly where the
+/// interrupted run stopped.
+#[tauri::command]
+fn resume_job(
+    id: String,
+    app: tauri::AppHandle,
+    jobs: tauri::State<'_, JobRegistry>,
+) -> Result<(), String> {
+    if let Some(control) = jobs.get(&id) {
+        if control.resume() {
+            events::emit(
+                &app,
+                &id,
+                JobEvent::StatusChanged {
+                    status: JobStatus::Running,
+                },
+            );
+        }
+        return Ok(());
+    }
 
-    child.wait().map_err(|e| e.to_string())?;
+    check_id(&id)?;
+    let journal_dir = journal_dir(&app)?;
+    let cache_path = description_cache_path(&app)?;
+    // Registered before the journal is reopened, which cuts off its torn
+    // tail: a second resume of the same job must not touch it.
+    let control = jobs
+        .register_as(&id)
+        .ok_or_else(|| format!("Job '{id}' is already running."))?;
+    let (journal, spec) = match Journal::resume(&journal_dir, &id) {
+        Ok(resumed) => resumed,
+        Err(e) => {
+            jobs.remove(&id);
+            return Err(e.to_string());
+        }
+    };
+    spawn_job(app, id, control, spec, journal, cache_path);
     Ok(())
 }
 
+/// Undoes a job: restores the originals from its backup folder and deletes
+/// the generated files. Anything that would be overwritten is reported as a
+/// conflict instead.
+#[tauri::command]
+fn rollback_job(
+    id: String,
+    app: tauri::AppHandle,
+    jobs: tauri::State<'_, JobRegistry>,
+) -> Result<RollbackReport, String> {
+    check_id(&id)?;
+    if jobs.get(&id).is_some() {
+        return Err(format!("Job '{id}' is still running; cancel it first."));
+    }
+    let (journal, spec) =
+        Journal::open_for_rollback(&journal_dir(&app)?, &id).map_err(|e| e.to_string())?;
+    rollback::rollback(&spec, &journal).map_err(|e| e.to_string())
+}
+
 pub fn run() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![run_script])
+        .manage(JobRegistry::default())
+        .invoke_handler(tauri::generate_handler![
+            ping,
+            start_job,
+            plan_job,
+            list_models,
+            cancel_job,
+            pause_job,
+            resume_job,
+            unfinished_jobs,
+            rollback_job
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,1715 @@
+//! Native metadata writer.
+//!
+//! Replaces the `exiftool -Keywords=` call of the shell script. The AI
+//! description is embedded as XMP (`dc:subject`, `dc:title`,
+//! `dc:description` and the IPTC Core alt text), and JPEG outputs also get
+//! the IPTC-IIM keywords and caption that older tools read. Blocks are
+//! spliced into the encoded file at container level, so the image data is
+//! never re-encoded.
+//!
+//! What survives of the source's own metadata is decided per
+//! [`MetadataField`] by the job's [`MetadataOptions`]: EXIF is filtered tag
+//! by tag (see [`crate::exif`]), XMP property by property and IPTC-IIM
+//! dataset by dataset, and the ICC profile is copied as a whole. The AI
+//! description is merged into whatever XMP and IPTC an output kept.
+//!
+//! JPEG, PNG and WebP can hold metadata. AVIF outputs are left as encoded.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use resvg::usvg::roxmltree;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CachedDescription;
+use crate::convert;
+use crate::exif::Exif;
+
+/// Groups of source metadata the policy decides about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MetadataField {
+    /// The EXIF GPS IFD: where the photo was taken.
+    Gps,
+    /// Vendor-specific EXIF maker notes, which often hold serial numbers
+    /// and other device details in undocumented form.
+    MakerNotes,
+    /// Camera and lens serial numbers, owner name and unique image ID.
+    SerialNumbers,
+    /// EXIF `Artist`/`Copyright`, the XMP creator and rights properties and
+    /// the IPTC by-line, credit and copyright notice.
+    Copyright,
+    /// The embedded ICC colour profile.
+    Icc,
+    /// Every other EXIF tag: camera model, exposure, dates and so on.
+    Exif,
+    /// Every other XMP property: ratings, labels, editing history and so
+    /// on.
+    Xmp,
+    /// Every other IPTC-IIM dataset: captions, keywords, locations and so
+    /// on. Only JPEG outputs can hold them.
+    Iptc,
+}
+
+impl MetadataField {
+    pub fn label(self) -> &'static str {
+        match self {
+            MetadataField::Gps => "GPS location",
+            MetadataField::MakerNotes => "maker notes",
+            MetadataField::SerialNumbers => "serial numbers",
+            MetadataField::Copyright => "copyright",
+            MetadataField::Icc => "ICC profile",
+            MetadataField::Exif => "EXIF",
+            MetadataField::Xmp => "XMP",
+            MetadataField::Iptc => "IPTC",
+        }
+    }
+}
+
+/// Metadata settings, per job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MetadataOptions {
+    /// Write the AI keywords, title, alt text and caption into the outputs.
+    pub embed_description: bool,
+    /// Drop the source's own metadata (`image.optimization.strip_metadata`
+    /// in `config.yaml`), except for the fields in `keep`.
+    pub strip_metadata: bool,
+    /// Fields carried over even when `strip_metadata` is on.
+    pub keep: Vec<MetadataField>,
+    /// Fields always removed. Takes precedence over `keep`.
+    pub strip: Vec<MetadataField>,
+}
+
+impl Default for MetadataOptions {
+    /// Strips everything that could identify a person or device, but keeps
+    /// the rights holder and the colour profile.
+    fn default() -> Self {
+        Self {
+            embed_description: true,
+            strip_metadata: true,
+            keep: vec![MetadataField::Copyright, MetadataField::Icc],
+            strip: vec![
+                MetadataField::Gps,
+                MetadataField::MakerNotes,
+                MetadataField::SerialNumbers,
+            ],
+        }
+    }
+}
+
+impl MetadataOptions {
+    pub fn keeps(&self, field: MetadataField) -> bool {
+        if self.strip.contains(&field) {
+            false
+        } else {
+            self.keep.contains(&field) || !self.strip_metadata
+        }
+    }
+
+    /// The blocks to carry over from `source` into the outputs, and the
+    /// fields that were present in it and are left out.
+    pub fn apply(&self, source: SourceMetadata) -> (Blocks, BTreeSet<MetadataField>) {
+        let mut removed = BTreeSet::new();
+        let exif = source.exif.and_then(|block| {
+            let Some(mut exif) = Exif::parse(&block) else {
+                // Cannot be filtered, so it cannot be trusted to hold only
+                // what the policy allows.
+                removed.insert(MetadataField::Exif);
+                return None;
+            };
+            removed.extend(exif.retain(|field| self.keeps(field)));
+            // The pixels are always turned upright during conversion.
+            exif.reset_orientation();
+            (!exif.is_empty()).then(|| exif.to_bytes())
+        });
+        let icc = source.icc.filter(|_| {
+            let keep = self.keeps(MetadataField::Icc);
+            if !keep {
+                removed.insert(MetadataField::Icc);
+            }
+            keep
+        });
+        let keep_rights = self.keeps(MetadataField::Copyright);
+        let xmp = source.xmp.and_then(|packet| {
+            let keep_others = self.keeps(MetadataField::Xmp);
+            if keep_rights && keep_others {
+                return Some(packet);
+            }
+            let Some(mut xmp) = Xmp::parse(&packet) else {
+                // Cannot be filtered, so it cannot be trusted to hold only
+                // what the policy allows.
+                removed.insert(MetadataField::Xmp);
+                return None;
+            };
+            xmp.retain(|property| {
+                let (keep, field) = if property.is_any(RIGHTS_PROPERTIES) {
+                    (keep_rights, MetadataField::Copyright)
+                } else {
+                    (keep_others, MetadataField::Xmp)
+                };
+                if !keep {
+                    removed.insert(field);
+                }
+                keep
+            });
+            (!xmp.is_empty()).then(|| xmp.to_packet(None))
+        });
+        let iptc = source.iptc.and_then(|iptc| {
+            let keep_others = self.keeps(MetadataField::Iptc);
+            let Some(datasets) = iptc_parse(&iptc) else {
+                // Cannot be filtered, so it is only kept if nothing in it
+                // has to go.
+                if keep_rights && keep_others {
+                    return Some(iptc);
+                }
+                removed.insert(MetadataField::Iptc);
+                return None;
+            };
+            let mut kept = Vec::new();
+            for (record, number, value) in datasets {
+                let keep = if IPTC_ENCODING.contains(&(record, number)) {
+                    true
+                } else if record == 2 && IPTC_RIGHTS.contains(&number) {
+                    if !keep_rights {
+                        removed.insert(MetadataField::Copyright);
+                    }
+                    keep_rights
+                } else {
+                    if !keep_others {
+                        removed.insert(MetadataField::Iptc);
+                    }
+                    keep_others
+                };
+                if keep {
+                    push_dataset(&mut kept, record, number, value);
+                }
+            }
+            let encoding_only = iptc_parse(&kept)
+                .unwrap_or_default()
+                .iter()
+                .all(|&(record, number, _)| IPTC_ENCODING.contains(&(record, number)));
+            (!encoding_only).then_some(kept)
+        });
+        (
+            Blocks {
+                exif,
+                icc,
+                xmp,
+                iptc,
+            },
+            removed,
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("malformed {container} file: {reason}")]
+    Malformed {
+        container: &'static str,
+        reason: &'static str,
+    },
+    #[error("{block} metadata is too large for a {container} file")]
+    TooLarge {
+        container: &'static str,
+        block: &'static str,
+    },
+}
+
+/// Metadata found in a source file.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMetadata {
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    /// IPTC-IIM datasets, which only JPEG sources carry.
+    pub iptc: Option<Vec<u8>>,
+}
+
+/// Metadata to put into a file. Each block that is set replaces the
+/// file's existing block of that kind; blocks left `None` are kept as they
+/// are, so embedding the same blocks twice gives the same file.
+#[derive(Debug, Clone, Default)]
+pub struct Blocks {
+    /// A TIFF-structured EXIF block, as returned by the decoders.
+    pub exif: Option<Vec<u8>>,
+    /// An ICC colour profile.
+    pub icc: Option<Vec<u8>>,
+    /// A complete XMP packet.
+    pub xmp: Option<Vec<u8>>,
+    /// IPTC-IIM datasets. Only JPEG has a place for them, in a Photoshop
+    /// image resource; the file's other image resources are kept. The
+    /// other containers rely on the IPTC Core properties in the XMP packet.
+    pub iptc: Option<Vec<u8>>,
+}
+
+impl Blocks {
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc.is_none() && self.xmp.is_none() && self.iptc.is_none()
+    }
+}
+
+/// Returns `bytes` with `blocks` embedded, or `None` if the container
+/// cannot hold metadata.
+pub fn embed(bytes: &[u8], blocks: &Blocks) -> Result<Option<Vec<u8>>, MetadataError> {
+    match Container::sniff(bytes) {
+        Some(Container::Jpeg) => embed_jpeg(bytes, blocks).map(Some),
+        Some(Container::Png) => embed_png(bytes, blocks).map(Some),
+        Some(Container::Webp) => embed_webp(bytes, blocks).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The XMP packet of a JPEG, PNG or WebP file, if it has one.
+pub fn read_xmp(bytes: &[u8]) -> Option<Vec<u8>> {
+    match Container::sniff(bytes)? {
+        Container::Jpeg => jpeg_segments(bytes)
+            .ok()?
+            .0
+            .into_iter()
+            .find(|(marker, payload)| *marker == APP1 && payload.starts_with(XMP_NAMESPACE))
+            .map(|(_, payload)| payload[XMP_NAMESPACE.len()..].to_vec()),
+        Container::Png => png_chunks(bytes)
+            .ok()?
+            .into_iter()
+            .find(|(kind, data)| *kind == b"iTXt" && data.starts_with(PNG_XMP_KEYWORD))
+            .and_then(|(_, data)| png_itxt_text(data)),
+        Container::Webp => webp_chunks(bytes)
+            .ok()?
+            .into_iter()
+            .find(|(kind, _)| kind == b"XMP ")
+            .map(|(_, data)| data),
+    }
+}
+
+/// The IPTC-IIM datasets of a JPEG file, if it has any.
+pub fn read_iptc(bytes: &[u8]) -> Option<Vec<u8>> {
+    let Container::Jpeg = Container::sniff(bytes)? else {
+        return None;
+    };
+    let resources = photoshop_resources(&jpeg_segments(bytes).ok()?.0);
+    photoshop_resource_list(&resources)
+        .into_iter()
+        .find(|resource| resource.id == IPTC_RESOURCE_ID)
+        .map(|resource| resource.data.to_vec())
+}
+
+/// Embeds `description` into the file at `path`, replacing it atomically.
+/// The description is merged into the XMP packet and IPTC datasets already
+/// in the file, replacing only their title, caption, alt text and keywords.
+/// Returns the new file size.
+pub fn describe_file(path: &Path, description: &CachedDescription) -> Result<u64, MetadataError> {
+    let bytes = fs::read(path)?;
+    let xmp = match read_xmp(&bytes) {
+        Some(xmp) => merge_xmp(&xmp, description),
+        None => Xmp::default().to_packet(Some(description)),
+    };
+    let blocks = Blocks {
+        xmp: Some(xmp),
+        iptc: Some(merge_iptc(read_iptc(&bytes).as_deref(), description)),
+        ..Blocks::default()
+    };
+    let Some(embedded) = embed(&bytes, &blocks)? else {
+        return Ok(bytes.len() as u64);
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp = convert::write_temp(dir, &name, "", &embedded)?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(embedded.len() as u64)
+}
+
+enum Container {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl Container {
+    fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Container::Jpeg)
+        } else if bytes.starts_with(PNG_SIGNATURE) {
+            Some(Container::Png)
+        } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(Container::Webp)
+        } else {
+            None
+        }
+    }
+}
+
+const APP0: u8 = 0xE0;
+const APP1: u8 = 0xE1;
+const APP2: u8 = 0xE2;
+const APP13: u8 = 0xED;
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+
+/// Largest payload of a JPEG marker segment: the 16-bit length counts
+/// itself.
+const MAX_SEGMENT_LEN: usize = 0xFFFF - 2;
+
+/// Largest piece of an ICC profile per `APP2` segment, after the header
+/// and the sequence number and count bytes.
+const MAX_ICC_CHUNK_LEN: usize = MAX_SEGMENT_LEN - 14;
+
+/// A JPEG marker and its segment payload.
+type Segment<'a> = (u8, &'a [u8]);
+
+/// Splits a JPEG into its marker segments up to the start of scan, and
+/// returns them with the offset the entropy-coded data starts at.
+fn jpeg_segments(bytes: &[u8]) -> Result<(Vec<Segment<'_>>, usize), MetadataError> {
+    let malformed = |reason| MetadataError::Malformed {
+        container: "JPEG",
+        reason,
+    };
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        if bytes.get(pos) != Some(&0xFF) {
+            return Err(malformed("missing marker"));
+        }
+        while bytes.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *bytes.get(pos + 1).ok_or(malformed("truncated marker"))?;
+        if matches!(marker, 0xDA | 0xD9) {
+            return Ok((segments, pos));
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        let len = bytes
+            .get(pos + 2..pos + 4)
+            .map(|len| usize::from(u16::from_be_bytes([len[0], len[1]])))
+            .filter(|&len| len >= 2)
+            .ok_or(malformed("bad segment length"))?;
+        let payload = bytes
+            .get(pos + 4..pos + 2 + len)
+            .ok_or(malformed("truncated segment"))?;
+        segments.push((marker, payload));
+        pos += 2 + len;
+    }
+}
+
+fn embed_jpeg(bytes: &[u8], blocks: &Blocks) -> Result<Vec<u8>, MetadataError> {
+    let (segments, data_start) = jpeg_segments(bytes)?;
+    let replaced = |&(marker, payload): &Segment| match marker {
+        APP1 if payload.starts_with(EXIF_HEADER) => blocks.exif.is_some(),
+        APP1 if payload.starts_with(XMP_NAMESPACE) => blocks.xmp.is_some(),
+        APP2 if payload.starts_with(ICC_HEADER) => blocks.icc.is_some(),
+        APP13 if payload.starts_with(PHOTOSHOP_HEADER) => blocks.iptc.is_some(),
+        _ => false,
+    };
+    let mut added: Vec<(u8, Vec<u8>, &'static str)> = Vec::new();
+    if let Some(exif) = &blocks.exif {
+        added.push((APP1, [EXIF_HEADER, exif].concat(), "EXIF"));
+    }
+    if let Some(xmp) = &blocks.xmp {
+        added.push((APP1, [XMP_NAMESPACE, xmp].concat(), "XMP"));
+    }
+    if let Some(icc) = &blocks.icc {
+        // Profiles larger than one segment are split, each piece numbered.
+        let chunks: Vec<&[u8]> = icc.chunks(MAX_ICC_CHUNK_LEN).collect();
+        let count = u8::try_from(chunks.len()).map_err(|_| MetadataError::TooLarge {
+            container: "JPEG",
+            block: "ICC",
+        })?;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let payload = [ICC_HEADER, &[i as u8 + 1, count], chunk].concat();
+            added.push((APP2, payload, "ICC"));
+        }
+    }
+    if let Some(iptc) = &blocks.iptc {
+        // Only the IPTC resource is replaced; clipping paths, resolution
+        // info and the other image resources stay. Large resource blocks
+        // are split over several segments.
+        let resources = replace_iptc_resource(&photoshop_resources(&segments), iptc);
+        for chunk in resources.chunks(MAX_SEGMENT_LEN - PHOTOSHOP_HEADER.len()) {
+            added.push((APP13, [PHOTOSHOP_HEADER, chunk].concat(), "IPTC"));
+        }
+    }
+
+    let mut out =
+        Vec::with_capacity(bytes.len() + added.iter().map(|a| a.1.len() + 4).sum::<usize>());
+    out.extend_from_slice(&bytes[..2]);
+    let write_segment = |out: &mut Vec<u8>, marker: u8, payload: &[u8]| {
+        out.extend_from_slice(&[0xFF, marker]);
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(payload);
+    };
+    // JFIF requires its APP0 segment to come first.
+    let leading = segments
+        .iter()
+        .take_while(|(marker, _)| *marker == APP0)
+        .count();
+    for &(marker, payload) in &segments[..leading] {
+        write_segment(&mut out, marker, payload);
+    }
+    for (marker, payload, block) in &added {
+        if payload.len() > MAX_SEGMENT_LEN {
+            return Err(MetadataError::TooLarge {
+                container: "JPEG",
+                block,
+            });
+        }
+        write_segment(&mut out, *marker, payload);
+    }
+    for segment in &segments[leading..] {
+        if !replaced(segment) {
+            write_segment(&mut out, segment.0, segment.1);
+        }
+    }
+    out.extend_from_slice(&bytes[data_start..]);
+    Ok(out)
+}
+
+/// ID of the Photoshop image resource holding IPTC-IIM datasets.
+const IPTC_RESOURCE_ID: u16 = 0x0404;
+
+/// Wraps IPTC-IIM datasets in the Photoshop image resource JPEG readers
+/// look for them in.
+fn iptc_resource(iptc: &[u8]) -> Vec<u8> {
+    let mut resource = Vec::with_capacity(iptc.len() + 13);
+    resource.extend_from_slice(b"8BIM");
+    resource.extend_from_slice(&IPTC_RESOURCE_ID.to_be_bytes());
+    // Empty Pascal-string name, padded to an even length.
+    resource.extend_from_slice(&[0, 0]);
+    resource.extend_from_slice(&(iptc.len() as u32).to_be_bytes());
+    resource.extend_from_slice(iptc);
+    if iptc.len() % 2 == 1 {
+        resource.push(0);
+    }
+    resource
+}
+
+/// The Photoshop image resources of a JPEG, which large blocks split over
+/// several APP13 segments.
+fn photoshop_resources(segments: &[Segment]) -> Vec<u8> {
+    segments
+        .iter()
+        .filter(|(marker, payload)| *marker == APP13 && payload.starts_with(PHOTOSHOP_HEADER))
+        .flat_map(|(_, payload)| &payload[PHOTOSHOP_HEADER.len()..])
+        .copied()
+        .collect()
+}
+
+/// A Photoshop image resource.
+struct PhotoshopResource<'a> {
+    id: u16,
+    data: &'a [u8],
+    /// The whole resource, header and padding included.
+    raw: &'a [u8],
+}
+
+/// Splits Photoshop image resources. Anything after the first malformed
+/// resource is left out.
+fn photoshop_resource_list(mut resources: &[u8]) -> Vec<PhotoshopResource<'_>> {
+    let mut list = Vec::new();
+    while resources.len() >= 12 && &resources[..4] == b"8BIM" {
+        let id = u16::from_be_bytes([resources[4], resources[5]]);
+        // Pascal-string name, padded to an even length with its length byte.
+        let size_start = 6 + (usize::from(resources[6]) + 2) / 2 * 2;
+        let Some(size) = resources.get(size_start..size_start + 4) else {
+            break;
+        };
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let start = size_start + 4;
+        let Some(data) = start
+            .checked_add(size)
+            .and_then(|end| resources.get(start..end))
+        else {
+            break;
+        };
+        let end = (start + size + size % 2).min(resources.len());
+        list.push(PhotoshopResource {
+            id,
+            data,
+            raw: &resources[..end],
+        });
+        resources = &resources[end..];
+    }
+    list
+}
+
+/// `resources` with the IPTC resource replaced by one holding `iptc`, or
+/// with one added if there was none.
+fn replace_iptc_resource(resources: &[u8], iptc: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(resources.len() + iptc.len() + 13);
+    let mut replaced = false;
+    for resource in photoshop_resource_list(resources) {
+        if resource.id != IPTC_RESOURCE_ID {
+            out.extend_from_slice(resource.raw);
+        } else if !replaced {
+            out.extend_from_slice(&iptc_resource(iptc));
+            replaced = true;
+        }
+    }
+    if !replaced {
+        out.extend_from_slice(&iptc_resource(iptc));
+    }
+    out
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// Name of the profile in `iCCP` chunks; readers ignore it.
+const PNG_ICC_NAME: &[u8] = b"ICC Profile";
+
+/// A PNG chunk type and its data.
+type PngChunk<'a> = (&'a [u8], &'a [u8]);
+
+fn png_chunks(bytes: &[u8]) -> Result<Vec<PngChunk<'_>>, MetadataError> {
+    let malformed = |reason| MetadataError::Malformed {
+        container: "PNG",
+        reason,
+    };
+    let mut chunks: Vec<PngChunk> = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < bytes.len() {
+        let header = bytes
+            .get(pos..pos + 8)
+            .ok_or(malformed("truncated chunk"))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let data = bytes
+            .get(pos + 8..pos + 8 + len)
+            .ok_or(malformed("truncated chunk"))?;
+        chunks.push((&header[4..8], data));
+        pos += 12 + len;
+    }
+    if chunks.first().map(|chunk| chunk.0) != Some(b"IHDR".as_slice()) {
+        return Err(malformed("missing IHDR chunk"));
+    }
+    Ok(chunks)
+}
+
+/// The text of an `iTXt` chunk, inflated if it is compressed.
+fn png_itxt_text(data: &[u8]) -> Option<Vec<u8>> {
+    // Keyword, then the compression flag and method.
+    let keyword_end = data.iter().position(|&b| b == 0)?;
+    let compressed = *data.get(keyword_end + 1)? == 1;
+    let rest = data.get(keyword_end + 3..)?;
+    // Language tag and translated keyword, both null-terminated.
+    let language_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[language_end + 1..];
+    let translated_end = rest.iter().position(|&b| b == 0)?;
+    let text = &rest[translated_end + 1..];
+    if compressed {
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(text)
+            .read_to_end(&mut inflated)
+            .ok()?;
+        Some(inflated)
+    } else {
+        Some(text.to_vec())
+    }
+}
+
+fn embed_png(bytes: &[u8], blocks: &Blocks) -> Result<Vec<u8>, MetadataError> {
+    let chunks = png_chunks(bytes)?;
+    let replaced = |&(kind, data): &PngChunk| match kind {
+        b"eXIf" => blocks.exif.is_some(),
+        // An sRGB chunk would override the embedded profile.
+        b"iCCP" | b"sRGB" => blocks.icc.is_some(),
+        b"iTXt" => blocks.xmp.is_some() && data.starts_with(PNG_XMP_KEYWORD),
+        _ => false,
+    };
+    let mut added: Vec<(&[u8], Vec<u8>)> = Vec::new();
+    if let Some(icc) = &blocks.icc {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(icc)?;
+        // Name, then compression method 0 (zlib).
+        added.push((
+            b"iCCP",
+            [PNG_ICC_NAME, b"\0\0", &encoder.finish()?].concat(),
+        ));
+    }
+    if let Some(exif) = &blocks.exif {
+        added.push((b"eXIf", exif.clone()));
+    }
+    if let Some(xmp) = &blocks.xmp {
+        // Keyword, then uncompressed, no language tag, no translated
+        // keyword.
+        added.push((b"iTXt", [PNG_XMP_KEYWORD, b"\0\0\0\0\0", xmp].concat()));
+    }
+
+    let mut out =
+        Vec::with_capacity(bytes.len() + added.iter().map(|a| a.1.len() + 12).sum::<usize>());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let write_chunk = |out: &mut Vec<u8>, kind: &[u8], data: &[u8]| {
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        out.extend_from_slice(&crc.finalize().to_be_bytes());
+    };
+    // iCCP and eXIf must precede the image data; right after IHDR is always
+    // valid.
+    write_chunk(&mut out, chunks[0].0, chunks[0].1);
+    for (kind, data) in &added {
+        write_chunk(&mut out, kind, data);
+    }
+    for chunk in &chunks[1..] {
+        if !replaced(chunk) {
+            write_chunk(&mut out, chunk.0, chunk.1);
+        }
+    }
+    Ok(out)
+}
+
+/// `VP8X` feature flags.
+const WEBP_XMP_FLAG: u8 = 0x04;
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+const WEBP_ICC_FLAG: u8 = 0x20;
+
+/// A RIFF chunk FourCC and its data.
+type WebpChunk = ([u8; 4], Vec<u8>);
+
+fn webp_chunks(bytes: &[u8]) -> Result<Vec<WebpChunk>, MetadataError> {
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let kind: [u8; 4] = bytes[pos..pos + 4].try_into().unwrap();
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let data = bytes
+            .get(pos + 8..pos + 8 + len)
+            .ok_or(MetadataError::Malformed {
+                container: "WebP",
+                reason: "truncated chunk",
+            })?;
+        chunks.push((kind, data.to_vec()));
+        pos += 8 + len + len % 2;
+    }
+    Ok(chunks)
+}
+
+fn embed_webp(bytes: &[u8], blocks: &Blocks) -> Result<Vec<u8>, MetadataError> {
+    let malformed = |reason| MetadataError::Malformed {
+        container: "WebP",
+        reason,
+    };
+    let mut chunks = webp_chunks(bytes)?;
+
+    // Metadata needs the extended format, which starts with a VP8X chunk.
+    if chunks.first().map(|chunk| &chunk.0) != Some(b"VP8X") {
+        let (kind, data) = chunks.first().ok_or(malformed("no image data"))?;
+        let (width, height, alpha) = match kind {
+            b"VP8 " if data.len() >= 10 => (
+                u32::from(u16::from_le_bytes([data[6], data[7]]) & 0x3FFF),
+                u32::from(u16::from_le_bytes([data[8], data[9]]) & 0x3FFF),
+                false,
+            ),
+            b"VP8L" if data.len() >= 5 && data[0] == 0x2F => {
+                let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                (
+                    (bits & 0x3FFF) + 1,
+                    ((bits >> 14) & 0x3FFF) + 1,
+                    bits >> 28 & 1 == 1,
+                )
+            }
+            _ => return Err(malformed("unknown image chunk")),
+        };
+        let mut vp8x = vec![if alpha { WEBP_ALPHA_FLAG } else { 0 }, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunks.insert(0, (*b"VP8X", vp8x));
+    }
+    if chunks[0].1.len() < 10 {
+        return Err(malformed("short VP8X chunk"));
+    }
+
+    if let Some(icc) = &blocks.icc {
+        // The profile must directly follow VP8X.
+        chunks.retain(|chunk| &chunk.0 != b"ICCP");
+        chunks.insert(1, (*b"ICCP", icc.clone()));
+    }
+    if let Some(exif) = &blocks.exif {
+        chunks.retain(|chunk| &chunk.0 != b"EXIF");
+        chunks.push((*b"EXIF", exif.clone()));
+    }
+    if let Some(xmp) = &blocks.xmp {
+        chunks.retain(|chunk| &chunk.0 != b"XMP ");
+        chunks.push((*b"XMP ", xmp.clone()));
+    }
+    let has = |kind: &[u8; 4]| chunks.iter().any(|chunk| &chunk.0 == kind);
+    let mut flags = chunks[0].1[0] & !(WEBP_XMP_FLAG | WEBP_EXIF_FLAG | WEBP_ICC_FLAG);
+    for (kind, flag) in [
+        (b"ICCP", WEBP_ICC_FLAG),
+        (b"EXIF", WEBP_EXIF_FLAG),
+        (b"XMP ", WEBP_XMP_FLAG),
+    ] {
+        if has(kind) {
+            flags |= flag;
+        }
+    }
+    chunks[0].1[0] = flags;
+
+    let mut out = Vec::with_capacity(bytes.len() + 64);
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    for (kind, data) in &chunks {
+        let len = u32::try_from(data.len()).map_err(|_| MetadataError::TooLarge {
+            container: "WebP",
+            block: "chunk",
+        })?;
+        out.extend_from_slice(kind);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+    let riff_len = u32::try_from(out.len() - 8).map_err(|_| MetadataError::TooLarge {
+        container: "WebP",
+        block: "image",
+    })?;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Ok(out)
+}
+
+const RDF_NAMESPACE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const PHOTOSHOP_NAMESPACE: &str = "http://ns.adobe.com/photoshop/1.0/";
+const XMP_RIGHTS_NAMESPACE: &str = "http://ns.adobe.com/xap/1.0/rights/";
+const IPTC_CORE_NAMESPACE: &str = "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/";
+
+/// An XMP property name: namespace URI and local name.
+type PropertyName = (&'static str, &'static str);
+
+/// XMP properties that make up the [`MetadataField::Copyright`] field.
+const RIGHTS_PROPERTIES: &[PropertyName] = &[
+    (DC_NAMESPACE, "creator"),
+    (DC_NAMESPACE, "rights"),
+    (XMP_RIGHTS_NAMESPACE, "Marked"),
+    (XMP_RIGHTS_NAMESPACE, "Owner"),
+    (XMP_RIGHTS_NAMESPACE, "UsageTerms"),
+    (XMP_RIGHTS_NAMESPACE, "WebStatement"),
+];
+
+/// XMP properties the AI description sets. They are replaced when it is
+/// merged into an existing packet.
+const DESCRIPTION_PROPERTIES: &[PropertyName] = &[
+    (DC_NAMESPACE, "title"),
+    (DC_NAMESPACE, "description"),
+    (DC_NAMESPACE, "subject"),
+    (PHOTOSHOP_NAMESPACE, "Headline"),
+    (IPTC_CORE_NAMESPACE, "AltTextAccessibility"),
+];
+
+/// Namespace prefixes the packets written here declare themselves, for
+/// their structure rather than for properties.
+const XMP_STRUCTURE_PREFIXES: &[&str] = &["x", "rdf", "xml"];
+
+/// A property of an XMP packet.
+#[derive(Debug, Clone)]
+struct XmpProperty {
+    namespace: String,
+    name: String,
+    /// The property element as written in the packet. Properties written
+    /// as attributes are turned into elements.
+    xml: String,
+}
+
+impl XmpProperty {
+    fn is_any(&self, names: &[PropertyName]) -> bool {
+        names
+            .iter()
+            .any(|&(namespace, name)| self.namespace == namespace && self.name == name)
+    }
+}
+
+/// One `rdf:Description` of an XMP packet.
+#[derive(Debug, Clone)]
+struct XmpDescription {
+    /// The `rdf:about` value: the resource described, usually empty.
+    about: String,
+    /// The namespaces in scope, by prefix, which its properties may use.
+    namespaces: Vec<(Option<String>, String)>,
+    properties: Vec<XmpProperty>,
+}
+
+/// The properties of an XMP packet, read with an XML parser so they can be
+/// filtered whatever prefixes and forms the packet's writer used, and
+/// written back as a new packet.
+#[derive(Debug, Clone, Default)]
+struct Xmp {
+    descriptions: Vec<XmpDescription>,
+}
+
+impl Xmp {
+    /// Parses an XMP packet. Returns `None` if it is not well-formed UTF-8
+    /// XML with an `rdf:RDF` element.
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(packet).ok()?;
+        // Packets may start with a byte order mark and be padded for
+        // in-place editing.
+        let text = text
+            .trim_start_matches('\u{feff}')
+            .trim_end_matches(|c: char| c == '\0' || c.is_whitespace());
+        let document = roxmltree::Document::parse(text).ok()?;
+        let is_rdf = |node: &roxmltree::Node, name: &str| {
+            node.tag_name().namespace() == Some(RDF_NAMESPACE) && node.tag_name().name() == name
+        };
+        let rdf = document.descendants().find(|node| is_rdf(node, "RDF"))?;
+        let descriptions = rdf
+            .children()
+            .filter(|node| is_rdf(node, "Description"))
+            .map(|node| {
+                let mut namespaces: Vec<(Option<String>, String)> = node
+                    .namespaces()
+                    .filter(|ns| {
+                        ns.name()
+                            .is_none_or(|prefix| !XMP_STRUCTURE_PREFIXES.contains(&prefix))
+                    })
+                    .map(|ns| (ns.name().map(str::to_string), ns.uri().to_string()))
+                    .collect();
+                namespaces.sort();
+                let attributes = node.attributes().filter_map(|attribute| {
+                    let namespace = attribute.namespace()?;
+                    if namespace == RDF_NAMESPACE || namespace == roxmltree::NS_XML_URI {
+                        return None;
+                    }
+                    let name = format!("{}:{}", node.lookup_prefix(namespace)?, attribute.name());
+                    Some(XmpProperty {
+                        namespace: namespace.to_string(),
+                        name: attribute.name().to_string(),
+                        xml: format!("<{name}>{}</{name}>", xml_escape(attribute.value())),
+                    })
+                });
+                let elements = node
+                    .children()
+                    .filter(|child| child.is_element())
+                    .map(|child| XmpProperty {
+                        namespace: child.tag_name().namespace().unwrap_or_default().to_string(),
+                        name: child.tag_name().name().to_string(),
+                        xml: text[child.range()].to_string(),
+                    });
+                XmpDescription {
+                    about: node
+                        .attribute((RDF_NAMESPACE, "about"))
+                        .unwrap_or_default()
+                        .to_string(),
+                    namespaces,
+                    properties: attributes.chain(elements).collect(),
+                }
+            })
+            .collect();
+        Some(Self { descriptions })
+    }
+
+    /// Keeps only the properties `keep` returns true for.
+    fn retain(&mut self, mut keep: impl FnMut(&XmpProperty) -> bool) {
+        for description in &mut self.descriptions {
+            description.properties.retain(&mut keep);
+        }
+        self.descriptions
+            .retain(|description| !description.properties.is_empty());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.descriptions.is_empty()
+    }
+
+    /// A new XMP packet with `description` as Dublin Core and IPTC Core
+    /// properties, followed by these properties.
+    fn to_packet(&self, description: Option<&CachedDescription>) -> Vec<u8> {
+        let mut xml = String::from(concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+        ));
+        if let Some(description) = description {
+            xml.push_str(XMP_DESCRIPTION_START);
+            description_properties(&mut xml, description);
+            xml.push_str("  </rdf:Description>\n");
+        }
+        for description in &self.descriptions {
+            xml.push_str(&format!(
+                "  <rdf:Description rdf:about=\"{}\"",
+                xml_escape(&description.about)
+            ));
+            for (prefix, uri) in &description.namespaces {
+                let attribute = match prefix {
+                    Some(prefix) => format!("xmlns:{prefix}"),
+                    None => "xmlns".to_string(),
+                };
+                xml.push_str(&format!("\n    {attribute}=\"{}\"", xml_escape(uri)));
+            }
+            xml.push_str(">\n");
+            for property in &description.properties {
+                xml.push_str("   ");
+                xml.push_str(&property.xml);
+                xml.push('\n');
+            }
+            xml.push_str("  </rdf:Description>\n");
+        }
+        xml.push_str(concat!(
+            " </rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>",
+        ));
+        xml.into_bytes()
+    }
+}
+
+/// `xmp` with `description` merged in: its own description properties are
+/// replaced and everything else is kept. A packet that cannot be parsed is
+/// replaced as a whole, since it cannot be edited safely.
+fn merge_xmp(xmp: &[u8], description: &CachedDescription) -> Vec<u8> {
+    let mut xmp = Xmp::parse(xmp).unwrap_or_default();
+    xmp.retain(|property| !property.is_any(DESCRIPTION_PROPERTIES));
+    xmp.to_packet(Some(description))
+}
+
+/// Opening tag of the `rdf:Description` the AI description is written in.
+const XMP_DESCRIPTION_START: &str = concat!(
+    "  <rdf:Description rdf:about=\"\"\n",
+    "    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n",
+    "    xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"\n",
+    "    xmlns:Iptc4xmpCore=\"http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/\">\n",
+);
+
+/// Appends the description's XMP property elements to `xml`.
+fn description_properties(xml: &mut String, description: &CachedDescription) {
+    let lang_alt = |name: &str, value: &str| {
+        format!(
+            "   <{name}><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></{name}>\n",
+            xml_escape(value)
+        )
+    };
+    if !description.title.is_empty() {
+        xml.push_str(&lang_alt("dc:title", &description.title));
+        xml.push_str(&format!(
+            "   <photoshop:Headline>{}</photoshop:Headline>\n",
+            xml_escape(&description.title)
+        ));
+    }
+    if !description.caption.is_empty() {
+        xml.push_str(&lang_alt("dc:description", &description.caption));
+    }
+    xml.push_str(&lang_alt(
+        "Iptc4xmpCore:AltTextAccessibility",
+        &description.alt_text(),
+    ));
+    xml.push_str("   <dc:subject><rdf:Bag>\n");
+    for keyword in keywords(description) {
+        xml.push_str(&format!("    <rdf:li>{}</rdf:li>\n", xml_escape(&keyword)));
+    }
+    xml.push_str("   </rdf:Bag></dc:subject>\n");
+}
+
+/// An IPTC-IIM dataset: record, dataset number and value.
+type Dataset<'a> = (u8, u8, &'a [u8]);
+
+/// 1:90 Coded Character Set and 2:00 Record Version, which describe the
+/// encoding rather than the image.
+const IPTC_ENCODING: &[(u8, u8)] = &[(1, 90), (2, 0)];
+
+/// ESC % G: the 1:90 value declaring UTF-8.
+const IPTC_UTF8: &[u8] = b"\x1b%G";
+
+/// Record 2 datasets that make up the [`MetadataField::Copyright`] field:
+/// by-line, by-line title, credit, source and copyright notice.
+const IPTC_RIGHTS: &[u8] = &[80, 85, 110, 115, 116];
+
+/// Record 2 datasets the AI description sets: object name, keywords and
+/// caption.
+const IPTC_DESCRIPTION: &[u8] = &[5, 25, 120];
+
+/// Splits IPTC-IIM data into datasets. Returns `None` if it is malformed
+/// or uses extended datasets, which only hold binary data.
+fn iptc_parse(iptc: &[u8]) -> Option<Vec<Dataset<'_>>> {
+    let mut datasets = Vec::new();
+    let mut pos = 0;
+    // Some writers pad the resource with zeros.
+    while iptc
+        .get(pos..)
+        .is_some_and(|rest| rest.iter().any(|&b| b != 0))
+    {
+        let header = iptc.get(pos..pos + 5)?;
+        if header[0] != 0x1C || header[3] & 0x80 != 0 {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let value = iptc.get(pos + 5..pos + 5 + len)?;
+        datasets.push((header[1], header[2], value));
+        pos += 5 + len;
+    }
+    Some(datasets)
+}
+
+fn push_dataset(iptc: &mut Vec<u8>, record: u8, number: u8, value: &[u8]) {
+    iptc.extend_from_slice(&[0x1C, record, number]);
+    iptc.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    iptc.extend_from_slice(value);
+}
+
+/// IPTC-IIM datasets for the description, keywords, object name (title)
+/// and caption, merged into the record 2 datasets of `existing` and
+/// declared as UTF-8. Text in `existing` that was not declared as UTF-8 is
+/// read as Latin-1, as most writers use it.
+fn merge_iptc(existing: Option<&[u8]>, description: &CachedDescription) -> Vec<u8> {
+    let existing = existing.and_then(iptc_parse).unwrap_or_default();
+    let utf8 = existing
+        .iter()
+        .any(|&(record, number, value)| (record, number) == (1, 90) && value == IPTC_UTF8);
+    let mut datasets: Vec<(u8, Vec<u8>)> = existing
+        .into_iter()
+        // 2:200 and up hold a preview of the original in binary form.
+        .filter(|&(record, number, _)| {
+            record == 2 && (1..200).contains(&number) && !IPTC_DESCRIPTION.contains(&number)
+        })
+        .map(|(_, number, value)| {
+            let value = if utf8 {
+                value.to_vec()
+            } else {
+                value
+                    .iter()
+                    .map(|&b| char::from(b))
+                    .collect::<String>()
+                    .into_bytes()
+            };
+            (number, value)
+        })
+        .collect();
+    if !description.title.is_empty() {
+        datasets.push((5, truncate(&description.title, 64).as_bytes().to_vec()));
+    }
+    for keyword in keywords(description) {
+        datasets.push((25, truncate(&keyword, 64).as_bytes().to_vec()));
+    }
+    let caption = if description.caption.is_empty() {
+        description.alt_text()
+    } else {
+        description.caption.clone()
+    };
+    datasets.push((120, truncate(&caption, 2000).as_bytes().to_vec()));
+    // Datasets are listed in order within a record; sorting is stable, so
+    // repeated ones such as keywords keep theirs.
+    datasets.sort_by_key(|(number, _)| *number);
+
+    let mut iptc = Vec::new();
+    push_dataset(&mut iptc, 1, 90, IPTC_UTF8);
+    push_dataset(&mut iptc, 2, 0, &[0, 4]);
+    for (number, value) in datasets {
+        push_dataset(&mut iptc, 2, number, &value);
+    }
+    iptc
+}
+
+/// The description's keywords, or the words of its slug for descriptions
+/// cached without them.
+fn keywords(description: &CachedDescription) -> Vec<String> {
+    if description.keywords.is_empty() {
+        description.slug.split('-').map(str::to_string).collect()
+    } else {
+        description.keywords.clone()
+    }
+}
+
+/// The longest prefix of `text` that fits in `max_bytes` without splitting
+/// a character, as IIM fields have byte limits.
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    let mut end = text.len().min(max_bytes);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(format: image::ImageFormat) -> Vec<u8> {
+        let mut out = io::Cursor::new(Vec::new());
+        image::RgbImage::new(8, 8)
+            .write_to(&mut out, format)
+            .unwrap();
+        out.into_inner()
+    }
+
+    /// A lossless WebP in the simple format, with no VP8X chunk. Only the
+    /// header of the image data is valid.
+    fn simple_webp(width: u32, height: u32, alpha: bool) -> Vec<u8> {
+        let bits = (width - 1) | (height - 1) << 14 | u32::from(alpha) << 28;
+        let mut vp8l = vec![0x2F];
+        vp8l.extend_from_slice(&bits.to_le_bytes());
+        vp8l.extend_from_slice(&[1, 2, 3]);
+        let mut out = b"RIFF\0\0\0\0WEBPVP8L".to_vec();
+        out.extend_from_slice(&(vp8l.len() as u32).to_le_bytes());
+        out.extend_from_slice(&vp8l);
+        let len = (out.len() - 8) as u32;
+        out[4..8].copy_from_slice(&len.to_le_bytes());
+        out
+    }
+
+    fn blocks() -> Blocks {
+        Blocks {
+            exif: Some(b"MM\0*\0\0\0\x08\0\0\0\0\0\0".to_vec()),
+            // Odd-sized and larger than one JPEG segment.
+            icc: Some((0..150_001).map(|i| (i % 251) as u8).collect()),
+            xmp: Some(Xmp::default().to_packet(Some(&description()))),
+            iptc: Some(vec![0x1C, 2, 120, 0, 3, b'a', b'b', b'c']),
+        }
+    }
+
+    fn description() -> CachedDescription {
+        CachedDescription {
+            slug: "blue-sky".into(),
+            keywords: vec!["sky".into(), "blue".into()],
+            title: "Blue sky".into(),
+            alt: "A clear blue sky".into(),
+            caption: "A clear blue sky over the sea".into(),
+        }
+    }
+
+    #[test]
+    fn splices_jpeg_segments() {
+        let source = encoded(image::ImageFormat::Jpeg);
+        let blocks = blocks();
+        let out = embed(&source, &blocks).unwrap().unwrap();
+        image::load_from_memory(&out).unwrap();
+
+        let (segments, data_start) = jpeg_segments(&out).unwrap();
+        assert_eq!(segments[0].0, APP0);
+        let payload = |marker, header: &[u8]| -> Vec<&[u8]> {
+            segments
+                .iter()
+                .filter(|(m, payload)| *m == marker && payload.starts_with(header))
+                .map(|(_, payload)| &payload[header.len()..])
+                .collect()
+        };
+        assert_eq!(
+            payload(APP1, EXIF_HEADER),
+            [blocks.exif.as_deref().unwrap()]
+        );
+        let icc = payload(APP2, ICC_HEADER);
+        assert_eq!(icc.len(), 3);
+        for (i, chunk) in icc.iter().enumerate() {
+            assert_eq!(chunk[..2], [i as u8 + 1, 3]);
+        }
+        let icc: Vec<u8> = icc.iter().flat_map(|chunk| &chunk[2..]).copied().collect();
+        assert_eq!(Some(icc), blocks.icc);
+        assert_eq!(read_xmp(&out), blocks.xmp);
+        assert_eq!(read_iptc(&out), blocks.iptc);
+        // The entropy-coded data is copied as it is.
+        let (_, source_start) = jpeg_segments(&source).unwrap();
+        assert_eq!(out[data_start..], source[source_start..]);
+
+        // Blocks replace their own kind only, so embedding is idempotent.
+        assert_eq!(embed(&out, &blocks).unwrap().unwrap(), out);
+        let xmp_only = Blocks {
+            xmp: Some(b"<x/>".to_vec()),
+            ..Blocks::default()
+        };
+        let replaced = embed(&out, &xmp_only).unwrap().unwrap();
+        assert_eq!(read_xmp(&replaced).as_deref(), Some(b"<x/>".as_slice()));
+        assert_eq!(read_iptc(&replaced), blocks.iptc);
+    }
+
+    #[test]
+    fn rejects_oversized_jpeg_blocks() {
+        let blocks = Blocks {
+            xmp: Some(vec![b' '; MAX_SEGMENT_LEN]),
+            ..Blocks::default()
+        };
+        let result = embed(&encoded(image::ImageFormat::Jpeg), &blocks);
+        assert!(matches!(
+            result,
+            Err(MetadataError::TooLarge { block: "XMP", .. })
+        ));
+        assert!(matches!(
+            embed(b"\xFF\xD8\xFF\xE0\x00", &Blocks::default()),
+            Err(MetadataError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_iptc_split_over_segments() {
+        let iptc: Vec<u8> = (0..40u8).flat_map(|i| [0x1C, 2, 25, 0, 1, i]).collect();
+        // A named resource before the IPTC one, then the IPTC resource cut
+        // in two.
+        let mut resources = b"8BIM\x04\x0C\x03abc\0\0\0\x01x\0".to_vec();
+        resources.extend_from_slice(&iptc_resource(&iptc));
+        let (first, second) = resources.split_at(30);
+        let mut jpeg = b"\xFF\xD8".to_vec();
+        for part in [first, second] {
+            let payload = [PHOTOSHOP_HEADER, part].concat();
+            jpeg.extend_from_slice(&[0xFF, APP13]);
+            jpeg.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+            jpeg.extend_from_slice(&payload);
+        }
+        jpeg.extend_from_slice(b"\xFF\xD9");
+        assert_eq!(read_iptc(&jpeg), Some(iptc));
+    }
+
+    #[test]
+    fn splices_png_chunks() {
+        let mut source = encoded(image::ImageFormat::Png);
+        // An sRGB chunk would override the profile and has to go with it.
+        let srgb = [0, 0, 0, 1, b's', b'R', b'G', b'B', 0];
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&srgb[4..]);
+        let mut chunk = srgb.to_vec();
+        chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+        source.splice(33..33, chunk);
+
+        let blocks = blocks();
+        let out = embed(&source, &blocks).unwrap().unwrap();
+        // The decoder checks every CRC.
+        image::load_from_memory(&out).unwrap();
+        let chunks = png_chunks(&out).unwrap();
+        let kinds: Vec<&[u8]> = chunks.iter().map(|chunk| chunk.0).collect();
+        assert_eq!(kinds[..4], [b"IHDR", b"iCCP", b"eXIf", b"iTXt"]);
+        assert!(!kinds.contains(&b"sRGB".as_slice()));
+        assert_eq!(chunks[2].1, blocks.exif.as_deref().unwrap());
+        assert_eq!(read_xmp(&out), blocks.xmp);
+        assert_eq!(embed(&out, &blocks).unwrap().unwrap(), out);
+    }
+
+    #[test]
+    fn reads_compressed_png_xmp() {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"<x:xmpmeta/>").unwrap();
+        let text = [
+            PNG_XMP_KEYWORD,
+            b"\0\x01\0en\0XMP\0",
+            &encoder.finish().unwrap(),
+        ]
+        .concat();
+        assert_eq!(
+            png_itxt_text(&text).as_deref(),
+            Some(b"<x:xmpmeta/>".as_slice())
+        );
+    }
+
+    #[test]
+    fn splices_webp_chunks() {
+        let blocks = blocks();
+        let out = embed(&simple_webp(300, 200, true), &blocks)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize,
+            out.len() - 8
+        );
+        let chunks = webp_chunks(&out).unwrap();
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|chunk| &chunk.0).collect();
+        assert_eq!(kinds, [b"VP8X", b"ICCP", b"VP8L", b"EXIF", b"XMP "]);
+        let vp8x = &chunks[0].1;
+        assert_eq!(
+            vp8x[0],
+            WEBP_ICC_FLAG | WEBP_ALPHA_FLAG | WEBP_EXIF_FLAG | WEBP_XMP_FLAG
+        );
+        assert_eq!(vp8x[4..10], [43, 1, 0, 199, 0, 0]);
+        assert_eq!(Some(&chunks[1].1), blocks.icc.as_ref());
+        assert_eq!(read_xmp(&out), blocks.xmp);
+        assert_eq!(embed(&out, &blocks).unwrap().unwrap(), out);
+
+        // Dropping nothing but replacing the profile keeps the other flags.
+        let icc_only = Blocks {
+            icc: Some(vec![1, 2, 3]),
+            ..Blocks::default()
+        };
+        let out = embed(&out, &icc_only).unwrap().unwrap();
+        assert_eq!(webp_chunks(&out).unwrap()[0].1[0], vp8x[0]);
+    }
+
+    #[test]
+    fn reads_lossy_webp_dimensions() {
+        let mut vp8 = vec![0, 0, 0, 0x9D, 0x01, 0x2A];
+        vp8.extend_from_slice(&640u16.to_le_bytes());
+        vp8.extend_from_slice(&(480u16 | 0x4000).to_le_bytes());
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8 ".to_vec();
+        webp.extend_from_slice(&(vp8.len() as u32).to_le_bytes());
+        webp.extend_from_slice(&vp8);
+        let blocks = Blocks {
+            xmp: Some(b"<x/>".to_vec()),
+            ..Blocks::default()
+        };
+        let out = embed(&webp, &blocks).unwrap().unwrap();
+        let vp8x = &webp_chunks(&out).unwrap()[0].1;
+        assert_eq!(vp8x[..], [WEBP_XMP_FLAG, 0, 0, 0, 127, 2, 0, 223, 1, 0]);
+    }
+
+    #[test]
+    fn leaves_other_containers_alone() {
+        assert!(embed(b"GIF89a", &blocks()).unwrap().is_none());
+        assert!(read_xmp(b"GIF89a").is_none());
+    }
+
+    #[test]
+    fn keeps_other_photoshop_resources() {
+        // A resolution info resource, then an IPTC one.
+        let mut resources = b"8BIM\x03\xED\0\0\0\0\0\x03abc\0".to_vec();
+        resources.extend_from_slice(&iptc_resource(b"\x1C\x02\x78\0\x01x"));
+        let mut jpeg = encoded(image::ImageFormat::Jpeg);
+        let payload = [PHOTOSHOP_HEADER, &resources].concat();
+        let mut segment = vec![0xFF, APP13];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&payload);
+        jpeg.splice(2..2, segment);
+
+        let blocks = Blocks {
+            iptc: Some(b"\x1C\x02\x78\0\x03new".to_vec()),
+            ..Blocks::default()
+        };
+        let out = embed(&jpeg, &blocks).unwrap().unwrap();
+        let (segments, _) = jpeg_segments(&out).unwrap();
+        let resources = photoshop_resources(&segments);
+        let list = photoshop_resource_list(&resources);
+        let ids: Vec<u16> = list.iter().map(|resource| resource.id).collect();
+        assert_eq!(ids, [0x03ED, IPTC_RESOURCE_ID]);
+        assert_eq!(list[0].data, b"abc");
+        assert_eq!(read_iptc(&out), blocks.iptc);
+        assert_eq!(embed(&out, &blocks).unwrap().unwrap(), out);
+
+        // Resources too large for one segment are split.
+        let iptc: Vec<u8> = (0..15_000u32)
+            .flat_map(|i| [0x1C, 2, 25, 0, 1, i as u8])
+            .collect();
+        let large = Blocks {
+            iptc: Some(iptc),
+            ..Blocks::default()
+        };
+        let out = embed(&out, &large).unwrap().unwrap();
+        let (segments, _) = jpeg_segments(&out).unwrap();
+        assert_eq!(segments.iter().filter(|s| s.0 == APP13).count(), 2);
+        assert_eq!(read_iptc(&out), large.iptc);
+        let resources = photoshop_resources(&segments);
+        assert_eq!(photoshop_resource_list(&resources)[0].data, b"abc");
+    }
+
+    const RIGHTS_XMP: &str = r#"<?xpacket begin="﻿" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+    xmlns:d="http://purl.org/dc/elements/1.1/">
+<rdf:Description rdf:about="" xmlns:xr="http://ns.adobe.com/xap/1.0/rights/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/" xr:Marked="True" xmp:Rating="4">
+  <d:rights><rdf:Alt><rdf:li xml:lang="x-default"><![CDATA[(c) ACME & Co]]></rdf:li></rdf:Alt></d:rights>
+  <d:rightsHolder>Not rights</d:rightsHolder>
+  <xr:Owner>
+    <rdf:Bag><rdf:li>ACME</rdf:li></rdf:Bag>
+  </xr:Owner>
+</rdf:Description>
+<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">
+  <dc:creator><rdf:Seq><rdf:li>Jane</rdf:li></rdf:Seq></dc:creator>
+  <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Old title</rdf:li></rdf:Alt></dc:title>
+</rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+    fn properties(xmp: &Xmp) -> impl Iterator<Item = &XmpProperty> {
+        xmp.descriptions
+            .iter()
+            .flat_map(|description| &description.properties)
+    }
+
+    /// The names of the properties of `xmp`, with their namespace prefix
+    /// replaced by the namespace's last path segment.
+    fn property_names(xmp: &Xmp) -> Vec<String> {
+        properties(xmp)
+            .map(|property| {
+                let namespace = property.namespace.trim_end_matches('/');
+                let namespace = &namespace[namespace.rfind('/').unwrap() + 1..];
+                format!("{namespace}:{}", property.name)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_xmp_properties() {
+        let xmp = Xmp::parse(RIGHTS_XMP.as_bytes()).unwrap();
+        assert_eq!(
+            property_names(&xmp),
+            [
+                "rights:Marked",
+                "1.0:Rating",
+                "1.1:rights",
+                "1.1:rightsHolder",
+                "rights:Owner",
+                "1.1:creator",
+                "1.1:title",
+            ]
+        );
+        let rights: Vec<&str> = properties(&xmp)
+            .filter(|property| property.is_any(RIGHTS_PROPERTIES))
+            .map(|property| property.xml.as_str())
+            .collect();
+        assert_eq!(
+            rights,
+            [
+                "<xr:Marked>True</xr:Marked>",
+                r#"<d:rights><rdf:Alt><rdf:li xml:lang="x-default"><![CDATA[(c) ACME & Co]]></rdf:li></rdf:Alt></d:rights>"#,
+                "<xr:Owner>\n    <rdf:Bag><rdf:li>ACME</rdf:li></rdf:Bag>\n  </xr:Owner>",
+                "<dc:creator><rdf:Seq><rdf:li>Jane</rdf:li></rdf:Seq></dc:creator>",
+            ]
+        );
+        assert!(Xmp::parse(b"<x:xmpmeta><rdf:RDF>").is_none());
+        assert!(Xmp::parse(b"<x/>").is_none());
+        let empty = br#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+            <rdf:Description rdf:about=""/></rdf:RDF>"#;
+        let mut empty = Xmp::parse(empty).unwrap();
+        empty.retain(|_| true);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn filters_xmp_into_a_valid_packet() {
+        let mut xmp = Xmp::parse(RIGHTS_XMP.as_bytes()).unwrap();
+        xmp.retain(|property| property.is_any(RIGHTS_PROPERTIES));
+        let packet = xmp.to_packet(None);
+        // The properties keep working with the prefixes they were written
+        // with, and nothing else is left.
+        let filtered = Xmp::parse(&packet).unwrap();
+        assert_eq!(
+            property_names(&filtered),
+            ["rights:Marked", "1.1:rights", "rights:Owner", "1.1:creator"]
+        );
+        let text = String::from_utf8(packet).unwrap();
+        assert!(text.contains("<![CDATA[(c) ACME & Co]]>"));
+        assert!(!text.contains("Rating") && !text.contains("Not rights"));
+    }
+
+    #[test]
+    fn merges_descriptions_idempotently() {
+        let once = merge_xmp(RIGHTS_XMP.as_bytes(), &description());
+        let text = String::from_utf8(once.clone()).unwrap();
+        assert!(text.contains("xmp:Rating"));
+        assert!(text.contains("Blue sky"));
+        assert!(!text.contains("Old title"));
+        assert_eq!(merge_xmp(&once, &description()), once);
+        let merged = Xmp::parse(&once).unwrap();
+        assert_eq!(
+            properties(&merged)
+                .filter(|property| property.is_any(DESCRIPTION_PROPERTIES))
+                .count(),
+            5
+        );
+
+        // A packet that cannot be parsed is replaced.
+        let fresh = merge_xmp(b"<x:xmpmeta><rdf:RDF>", &description());
+        assert_eq!(fresh, Xmp::default().to_packet(Some(&description())));
+    }
+
+    #[test]
+    fn merges_iptc_as_utf8() {
+        let mut existing = Vec::new();
+        push_dataset(&mut existing, 2, 0, &[0, 2]);
+        push_dataset(&mut existing, 2, 25, b"old");
+        push_dataset(&mut existing, 2, 90, b"M\xfcnchen");
+        push_dataset(&mut existing, 2, 202, b"preview");
+        let merged = merge_iptc(Some(&existing), &description());
+        let datasets = iptc_parse(&merged).unwrap();
+        let numbers: Vec<(u8, u8)> = datasets.iter().map(|&(r, n, _)| (r, n)).collect();
+        assert_eq!(
+            numbers,
+            [(1, 90), (2, 0), (2, 5), (2, 25), (2, 25), (2, 90), (2, 120)]
+        );
+        assert_eq!(datasets[0].2, IPTC_UTF8);
+        assert_eq!(datasets[3].2, b"sky");
+        assert_eq!(datasets[5].2, "München".as_bytes());
+        // Already UTF-8, so not transcoded twice.
+        assert_eq!(merge_iptc(Some(&merged), &description()), merged);
+
+        assert!(iptc_parse(&[0x1C, 2, 25, 0x80, 4]).is_none());
+        assert!(iptc_parse(&[0x1C, 2, 25, 0, 4, b'a']).is_none());
+        assert_eq!(
+            iptc_parse(&[0x1C, 2, 25, 0, 1, b'a', 0, 0]).unwrap().len(),
+            1
+        );
+    }
+
+    /// A little-endian EXIF block with a tag of every field: Make and
+    /// Artist in IFD0; ExifVersion, MakerNote, BodySerialNumber and
+    /// LensSerialNumber in the Exif IFD; and a GPS IFD. Every value fits in
+    /// its entry.
+    fn exif_block() -> Vec<u8> {
+        const ASCII: u16 = 2;
+        const LONG: u16 = 4;
+        const UNDEFINED: u16 = 7;
+        let ifd = |out: &mut Vec<u8>, entries: &[(u16, u16, u32, &[u8; 4])]| {
+            out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+            for &(tag, kind, count, value) in entries {
+                out.extend_from_slice(&tag.to_le_bytes());
+                out.extend_from_slice(&kind.to_le_bytes());
+                out.extend_from_slice(&count.to_le_bytes());
+                out.extend_from_slice(value);
+            }
+            out.extend_from_slice(&[0; 4]);
+        };
+        let mut out = b"II*\0\x08\0\0\0".to_vec();
+        ifd(
+            &mut out,
+            &[
+                (0x010F, ASCII, 4, b"Cam\0"),
+                (0x013B, ASCII, 3, b"Al\0\0"),
+                (0x8769, LONG, 1, &62u32.to_le_bytes()),
+                (0x8825, LONG, 1, &116u32.to_le_bytes()),
+            ],
+        );
+        ifd(
+            &mut out,
+            &[
+                (0x9000, UNDEFINED, 4, b"0232"),
+                (0x927C, UNDEFINED, 4, b"MKNT"),
+                (0xA431, ASCII, 4, b"123\0"),
+                (0xA435, ASCII, 4, b"456\0"),
+            ],
+        );
+        ifd(&mut out, &[(0x0001, ASCII, 2, b"N\0\0\0")]);
+        out
+    }
+
+    fn source() -> SourceMetadata {
+        let mut iptc = Vec::new();
+        push_dataset(&mut iptc, 2, 0, &[0, 4]);
+        push_dataset(&mut iptc, 2, 25, b"harbour");
+        push_dataset(&mut iptc, 2, 116, b"(c) ACME");
+        SourceMetadata {
+            exif: Some(exif_block()),
+            icc: Some(b"profile".to_vec()),
+            xmp: Some(RIGHTS_XMP.as_bytes().to_vec()),
+            iptc: Some(iptc),
+        }
+    }
+
+    /// The fields `blocks` hold data of.
+    fn fields(blocks: &Blocks) -> BTreeSet<MetadataField> {
+        let mut fields = BTreeSet::new();
+        if let Some(exif) = &blocks.exif {
+            // Dropping everything reports everything that was there.
+            fields.extend(Exif::parse(exif).unwrap().retain(|_| false));
+        }
+        if blocks.icc.is_some() {
+            fields.insert(MetadataField::Icc);
+        }
+        if let Some(xmp) = &blocks.xmp {
+            let xmp = Xmp::parse(xmp).unwrap();
+            fields.extend(properties(&xmp).map(|property| {
+                if property.is_any(RIGHTS_PROPERTIES) {
+                    MetadataField::Copyright
+                } else {
+                    MetadataField::Xmp
+                }
+            }));
+        }
+        if let Some(iptc) = &blocks.iptc {
+            fields.extend(
+                iptc_parse(iptc)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|&(record, number, _)| !IPTC_ENCODING.contains(&(record, number)))
+                    .map(|(_, number, _)| {
+                        if IPTC_RIGHTS.contains(&number) {
+                            MetadataField::Copyright
+                        } else {
+                            MetadataField::Iptc
+                        }
+                    }),
+            );
+        }
+        fields
+    }
+
+    const ALL_FIELDS: [MetadataField; 8] = [
+        MetadataField::Gps,
+        MetadataField::MakerNotes,
+        MetadataField::SerialNumbers,
+        MetadataField::Copyright,
+        MetadataField::Icc,
+        MetadataField::Exif,
+        MetadataField::Xmp,
+        MetadataField::Iptc,
+    ];
+
+    #[test]
+    fn each_strip_entry_removes_only_its_field() {
+        let source = source();
+        let keep_all = MetadataOptions {
+            strip_metadata: false,
+            strip: Vec::new(),
+            ..MetadataOptions::default()
+        };
+        let (blocks, removed) = keep_all.apply(source.clone());
+        assert!(removed.is_empty(), "{removed:?}");
+        assert_eq!(fields(&blocks), BTreeSet::from(ALL_FIELDS));
+        assert_eq!(blocks.xmp, source.xmp);
+        assert_eq!(blocks.iptc, source.iptc);
+
+        for field in ALL_FIELDS {
+            let options = MetadataOptions {
+                strip: vec![field],
+                ..keep_all.clone()
+            };
+            let (blocks, removed) = options.apply(source.clone());
+            assert_eq!(removed, BTreeSet::from([field]), "stripping {field:?}");
+            let mut left = BTreeSet::from(ALL_FIELDS);
+            left.remove(&field);
+            assert_eq!(fields(&blocks), left, "stripping {field:?}");
+        }
+    }
+
+    #[test]
+    fn default_policy_keeps_rights_and_profile() {
+        let source = source();
+        let (blocks, removed) = MetadataOptions::default().apply(source.clone());
+        assert_eq!(
+            removed,
+            BTreeSet::from([
+                MetadataField::Gps,
+                MetadataField::MakerNotes,
+                MetadataField::SerialNumbers,
+                MetadataField::Exif,
+                MetadataField::Xmp,
+                MetadataField::Iptc,
+            ])
+        );
+        assert_eq!(
+            fields(&blocks),
+            BTreeSet::from([MetadataField::Copyright, MetadataField::Icc])
+        );
+        assert_eq!(blocks.icc, source.icc);
+        let xmp = Xmp::parse(blocks.xmp.as_deref().unwrap()).unwrap();
+        let rights: Vec<&str> = properties(&xmp)
+            .map(|property| property.name.as_str())
+            .collect();
+        assert_eq!(rights, ["Marked", "rights", "Owner", "creator"]);
+
+        let nothing = MetadataOptions {
+            keep: Vec::new(),
+            ..MetadataOptions::default()
+        };
+        let (blocks, removed) = nothing.apply(source);
+        assert!(blocks.is_empty());
+        assert_eq!(removed, BTreeSet::from(ALL_FIELDS));
+    }
+}
@@ -0,0 +1,357 @@
+//! Minimal blocking client for the local Ollama HTTP API.
+//!
+//! Replaces the `curl`/`jq` calls of the shell script. Only the endpoints
+//! the app needs are covered: `/api/generate` to describe an image,
+//! `/api/tags` to list installed models, `/api/show` to tell which of them
+//! accept images and `/api/ps` to list the ones loaded in memory.
+//! Transient failures (Ollama not up yet, timeouts, 5xx) are retried with
+//! exponential backoff; everything else is returned straight away as a
+//! typed [`OllamaError`].
+
+use std::error::Error as _;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Connection settings, mirroring the `ollama:` section of `config.yaml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OllamaConfig {
+    /// Base URL of the Ollama server. A full `/api/generate` URL, as found
+    /// in `config.yaml`, is accepted too.
+    pub endpoint: String,
+    pub model: String,
+    /// Per-request timeout in seconds.
+    pub timeout: u64,
+    pub max_retries: u32,
+    /// Delay before the first retry in seconds; doubled after each attempt.
+    pub retry_delay: u64,
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:11434".to_string(),
+            model: "llava".to_string(),
+            timeout: 45,
+            max_retries: 3,
+            retry_delay: 2,
+        }
+    }
+}
+
+impl OllamaConfig {
+    fn url(&self, path: &str) -> String {
+        let base = self.endpoint.trim_end_matches('/');
+        let base = base.strip_suffix("/api/generate").unwrap_or(base);
+        format!("{base}{path}")
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error, Serialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum OllamaError {
+    #[error("Ollama is not reachable at {endpoint}: {message}")]
+    Unreachable { endpoint: String, message: String },
+    #[error("Ollama did not answer within {seconds}s")]
+    Timeout { seconds: u64 },
+    #[error("model '{model}' is not installed in Ollama")]
+    ModelNotFound { model: String },
+    #[error("Ollama returned HTTP {status}: {message}")]
+    Http { status: u16, message: String },
+    #[error("unexpected response from Ollama: {message}")]
+    InvalidResponse { message: String },
+}
+
+impl OllamaError {
+    /// Whether retrying the same request may succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            OllamaError::Unreachable { .. } | OllamaError::Timeout { .. } => true,
+            OllamaError::Http { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// A model installed locally, as listed by `/api/tags`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    pub name: String,
+    /// Size on disk in bytes.
+    pub size: u64,
+    #[serde(default)]
+    pub details: ModelDetails,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelDetails {
+    #[serde(default)]
+    pub family: String,
+    #[serde(default)]
+    pub families: Option<Vec<String>>,
+    #[serde(default)]
+    pub parameter_size: String,
+}
+
+/// Model families whose members accept images, for Ollama versions that
+/// do not report capabilities in `/api/show`. Older vision models are
+/// tagged with the family of their image encoder (`clip` for LLaVA and
+/// friends, `mllama` for Llama 3.2 Vision); newer ones with a family of
+/// their own.
+const VISION_FAMILIES: &[&str] = &[
+    "clip", "mllama", "qwen25vl", "qwen2vl", "gemma3", "llama4", "mistral3",
+];
+
+impl ModelInfo {
+    /// Whether this model is the configured `model`, which may omit the
+    /// `:latest` tag.
+    pub fn is_named(&self, model: &str) -> bool {
+        self.name == model || self.name == format!("{model}:latest")
+    }
+
+    /// Guesses from the model's families whether it accepts images.
+    pub fn is_vision(&self) -> bool {
+        let details = &self.details;
+        std::iter::once(&details.family)
+            .chain(details.families.iter().flatten())
+            .any(|family| VISION_FAMILIES.contains(&family.as_str()))
+    }
+}
+
+/// An installed vision model, as listed by the `list_models` command.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VisionModel {
+    pub name: String,
+    /// Size on disk in bytes.
+    pub size: u64,
+    pub family: String,
+}
+
+#[derive(Deserialize)]
+struct TagsResponse {
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Serialize)]
+struct ShowRequest<'a> {
+    model: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ShowResponse {
+    /// What the model can do, such as `completion` and `vision`. Missing
+    /// before Ollama 0.6.4.
+    #[serde(default)]
+    capabilities: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct PsResponse {
+    models: Vec<LoadedModel>,
+}
+
+#[derive(Deserialize)]
+struct LoadedModel {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct GenerateRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    images: Vec<String>,
+    stream: bool,
+    /// Constrains the answer to a JSON value.
+    format: &'a str,
+}
+
+#[derive(Deserialize)]
+struct GenerateResponse {
+    response: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+pub struct OllamaClient {
+    config: OllamaConfig,
+    agent: ureq::Agent,
+}
+
+impl OllamaClient {
+    pub fn new(config: OllamaConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+        Self { config, agent }
+    }
+
+    /// Lists the models installed in Ollama.
+    pub fn tags(&self) -> Result<Vec<ModelInfo>, OllamaError> {
+        self.with_retries(|| {
+            let response = self
+                .agent
+                .get(&self.config.url("/api/tags"))
+                .call()
+                .map_err(|e| self.map_error(e))?;
+            let tags: TagsResponse = response.into_json().map_err(invalid_response)?;
+            Ok(tags.models)
+        })
+    }
+
+    /// Names of the models Ollama currently holds in memory.
+    pub fn loaded_models(&self) -> Result<Vec<String>, OllamaError> {
+        self.with_retries(|| {
+            let response = self
+                .agent
+                .get(&self.config.url("/api/ps"))
+                .call()
+                .map_err(|e| self.map_error(e))?;
+            let ps: PsResponse = response.into_json().map_err(invalid_response)?;
+            Ok(ps.models.into_iter().map(|model| model.name).collect())
+        })
+    }
+
+    /// The capabilities Ollama reports for `model`, or `None` if it is too
+    /// old to report them.
+    pub fn capabilities(&self, model: &str) -> Result<Option<Vec<String>>, OllamaError> {
+        self.with_retries(|| {
+            let response = self
+                .agent
+                .post(&self.config.url("/api/show"))
+                .send_json(ShowRequest { model })
+                .map_err(|e| self.map_error(e))?;
+            let show: ShowResponse = response.into_json().map_err(invalid_response)?;
+            Ok(show.capabilities)
+        })
+    }
+
+    /// Lists the installed models that can describe images, going by the
+    /// capabilities Ollama reports and falling back on their families.
+    pub fn vision_models(&self) -> Result<Vec<VisionModel>, OllamaError> {
+        let mut models = Vec::new();
+        for model in self.tags()? {
+            let vision = match self.capabilities(&model.name)? {
+                Some(capabilities) => capabilities.iter().any(|c| c == "vision"),
+                None => model.is_vision(),
+            };
+            if vision {
+                models.push(VisionModel {
+                    name: model.name,
+                    size: model.size,
+                    family: model.details.family,
+                });
+            }
+        }
+        Ok(models)
+    }
+
+    /// Sends `prompt` and the encoded image file `image` to the configured
+    /// model, with the answer constrained to JSON, and parses the answer
+    /// into `T`. The prompt still has to describe the fields the model
+    /// should fill in.
+    pub fn generate_json<T: DeserializeOwned>(
+        &self,
+        prompt: &str,
+        image: &[u8],
+    ) -> Result<T, OllamaError> {
+        let request = GenerateRequest {
+            model: &self.config.model,
+            prompt,
+            images: vec![base64::engine::general_purpose::STANDARD.encode(image)],
+            stream: false,
+            format: "json",
+        };
+        let answer = self.with_retries(|| {
+            let response = self
+                .agent
+                .post(&self.config.url("/api/generate"))
+                .send_json(&request)
+                .map_err(|e| self.map_error(e))?;
+            let generated: GenerateResponse = response.into_json().map_err(invalid_response)?;
+            Ok(generated.response)
+        })?;
+        serde_json::from_str(&answer).map_err(|e| OllamaError::InvalidResponse {
+            message: format!("answer is not the requested JSON: {e}"),
+        })
+    }
+
+    /// Runs `request` until it succeeds, fails permanently, or
+    /// `max_retries` retries have been used up.
+    fn with_retries<T>(
+        &self,
+        mut request: impl FnMut() -> Result<T, OllamaError>,
+    ) -> Result<T, OllamaError> {
+        let mut delay = Duration::from_secs(self.config.retry_delay);
+        let mut attempt = 0;
+        loop {
+            match request() {
+                Err(e) if e.is_transient() && attempt < self.config.max_retries => {
+                    attempt += 1;
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn map_error(&self, error: ureq::Error) -> OllamaError {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let message = response
+                    .into_json::<ErrorResponse>()
+                    .map(|body| body.error)
+                    .unwrap_or_default();
+                if status == 404 && message.contains("not found") {
+                    OllamaError::ModelNotFound {
+                        model: self.config.model.clone(),
+                    }
+                } else {
+                    OllamaError::Http { status, message }
+                }
+            }
+            ureq::Error::Transport(transport) => {
+                let timed_out = transport
+                    .source()
+                    .and_then(|source| source.downcast_ref::<io::Error>())
+                    .is_some_and(|e| {
+                        matches!(
+                            e.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                        )
+                    });
+                if timed_out {
+                    OllamaError::Timeout {
+                        seconds: self.config.timeout,
+                    }
+                } else {
+                    OllamaError::Unreachable {
+                        endpoint: self.config.url(""),
+                        message: transport.to_string(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn invalid_response(error: io::Error) -> OllamaError {
+    OllamaError::InvalidResponse {
+        message: error.to_string(),
+    }
+}
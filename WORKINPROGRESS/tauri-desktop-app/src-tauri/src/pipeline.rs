@@ -0,0 +1,727 @@
+//! Directory-level conversion pipeline.
+//!
+//! Native replacement for `seo_image_processor.sh`. In Phase 1 every
+//! supported image under the target directory is converted to the job's
+//! output formats next to the original, and the original is moved into a
+//! timestamped `originals_backup_*` folder that mirrors its relative path.
+//! Phase 2 (see [`crate::rename`]) then gives each output an AI-generated
+//! name.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{CachedDescription, DescriptionCache};
+use crate::color::ColorReport;
+use crate::convert::{self, ConvertOptions, OutputFormat};
+use crate::events::{JobEvent, Phase};
+use crate::input::{self, FormatMismatch, SourceFormat};
+use crate::jobs::JobControl;
+use crate::journal::{FileStage, Journal};
+use crate::metadata::MetadataField;
+use crate::ollama::OllamaConfig;
+use crate::rename;
+use crate::snippets::{self, SnippetExport, SnippetOptions};
+
+pub const BACKUP_DIR_PREFIX: &str = "originals_backup_";
+const SNIPPETS_FILE_PREFIX: &str = "picture_snippets_";
+
+/// Everything needed to run, or later resume, a job. Written as the header
+/// of the job's journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobSpec {
+    pub root: PathBuf,
+    pub backup_dir: PathBuf,
+    pub options: ConvertOptions,
+    #[serde(default)]
+    pub ollama: OllamaConfig,
+    #[serde(default)]
+    pub snippets: SnippetOptions,
+}
+
+impl JobSpec {
+    /// Creates a spec for a fresh run with a new timestamped backup folder.
+    pub fn new(
+        root: PathBuf,
+        options: ConvertOptions,
+        ollama: OllamaConfig,
+        snippets: SnippetOptions,
+    ) -> Self {
+        let backup_dir = root.join(format!(
+            "{BACKUP_DIR_PREFIX}{}",
+            chrono::Local::now().format("%Y%m%d%H%M%S")
+        ));
+        Self {
+            root,
+            backup_dir,
+            options,
+            ollama,
+            snippets,
+        }
+    }
+
+    /// The combined HTML snippet file, named after the backup folder's
+    /// timestamp so every run gets its own.
+    pub fn snippets_file(&self) -> PathBuf {
+        let stamp = self
+            .backup_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(BACKUP_DIR_PREFIX))
+            .unwrap_or_default();
+        self.root
+            .join(format!("{SNIPPETS_FILE_PREFIX}{stamp}.html"))
+    }
+}
+
+/// One file written for a source: a single format and size of its output
+/// set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputVariant {
+    pub format: OutputFormat,
+    /// Page number for the second and later pages of a multi-page TIFF.
+    #[serde(default)]
+    pub page: Option<u32>,
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Ladder width this variant was requested as, or `None` for the
+    /// full-size output.
+    pub ladder: Option<u32>,
+}
+
+impl OutputVariant {
+    /// What follows the base name in this variant's file name.
+    pub fn suffix(&self) -> String {
+        convert::name_suffix(self.format, self.page, self.ladder)
+    }
+}
+
+/// Every variant produced for one source.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileResult {
+    pub source: PathBuf,
+    pub outputs: Vec<OutputVariant>,
+    /// The AI description the outputs were named after, if they were
+    /// renamed.
+    pub description: Option<CachedDescription>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionSummary {
+    pub backup_dir: PathBuf,
+    pub converted: usize,
+    pub failed: usize,
+    /// Files that were picked up but could not be decoded.
+    pub unsupported: usize,
+    /// Files whose extension names a different format than their contents.
+    pub format_mismatches: Vec<FormatMismatch>,
+    pub renamed: usize,
+    pub rename_failed: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Final outputs of every file the job has converted, including those
+    /// converted before it was resumed.
+    pub files: Vec<FileResult>,
+    pub snippets: Option<SnippetExport>,
+    /// Number of files each kind of source metadata was removed from.
+    pub metadata_removed: BTreeMap<MetadataField, usize>,
+    /// Number of files tagged with each ICC profile. Untagged files are
+    /// not counted.
+    pub color_spaces: BTreeMap<String, usize>,
+    /// Bytes the PNG outputs saved over their PNG sources.
+    pub png_saved: u64,
+}
+
+/// Number of parallel workers: 80% of the available cores, like `MAX_JOBS`
+/// in the shell script.
+pub fn worker_count() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get() * 8 / 10)
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Recursively collects images under `root` with the format each one was
+/// detected as, skipping previous backup folders and the `generated`
+/// outputs of earlier jobs so neither originals nor outputs are ever
+/// converted twice. Files are classified by their contents, so an
+/// extensionless upload is picked up and a `.png` that is really a JPEG is
+/// read as one. Files that cannot be opened are classified by their
+/// extension and fail when converted.
+pub fn discover(
+    root: &Path,
+    generated: &HashSet<PathBuf>,
+) -> io::Result<Vec<(PathBuf, SourceFormat)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if !is_backup_dir(&path) {
+                    pending.push(path);
+                }
+            } else if file_type.is_file()
+                && !convert::is_temp_file(&path)
+                && !generated.contains(&path)
+            {
+                let format =
+                    input::detect_file(&path).unwrap_or_else(|_| SourceFormat::from_path(&path));
+                if let Some(format) = format {
+                    files.push((path, format));
+                }
+            }
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+fn is_backup_dir(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(BACKUP_DIR_PREFIX))
+}
+
+/// Removes temp outputs left behind when the app died mid-write.
+fn sweep_temp_files(root: &Path) -> io::Result<()> {
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                if !is_backup_dir(&path) {
+                    pending.push(path);
+                }
+            } else if convert::is_temp_file(&path) {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Converts every supported image under `spec.root` to the output formats
+/// in `spec.options`, moving originals into the backup folder, then renames
+/// the outputs. `on_event` is called from worker threads.
+///
+/// Every step is recorded in `journal`. Files the journal already lists as
+/// done or failed are skipped, so running a resumed journal picks up
+/// exactly where the previous run stopped.
+///
+/// Stops dispatching files while `control` is paused and for good once it is
+/// cancelled. Outputs that were still being written when the job was
+/// cancelled are deleted and their originals left in place, so a cancelled
+/// run never leaves half-written files behind.
+pub fn run(
+    spec: &JobSpec,
+    journal: &Journal,
+    cache: &mut DescriptionCache,
+    control: &JobControl,
+    on_event: &(dyn Fn(JobEvent) + Sync),
+) -> io::Result<ConversionSummary> {
+    let JobSpec {
+        root, backup_dir, ..
+    } = spec;
+    fs::create_dir_all(backup_dir)?;
+
+    let mut summary = ConversionSummary {
+        backup_dir: backup_dir.clone(),
+        ..Default::default()
+    };
+    if !journal.stages().is_empty() {
+        sweep_temp_files(root)?;
+        undo_interrupted_commits(journal)?;
+    }
+    let stages = journal.stages();
+    // Outputs in a source format, such as JPEGs and minified SVGs, must not
+    // be picked up as new sources, whether by this job when it is resumed
+    // or by a later job on the same folder.
+    let generated = Journal::outputs_in(journal.dir())?;
+    let mut files = Vec::new();
+    for (src, format) in discover(root, &generated)? {
+        let done = stages
+            .get(&src)
+            .is_some_and(|stage| *stage != FileStage::Restored);
+        if done {
+            continue;
+        }
+        if let Some(mismatch) = FormatMismatch::check(&src, format) {
+            summary.format_mismatches.push(mismatch);
+        }
+        files.push(src);
+    }
+
+    on_event(JobEvent::JobStarted {
+        root: root.to_path_buf(),
+        backup_dir: backup_dir.clone(),
+        total_files: files.len(),
+    });
+    for mismatch in &summary.format_mismatches {
+        on_event(JobEvent::FormatMismatch(mismatch.clone()));
+    }
+    on_event(JobEvent::PhaseChanged {
+        phase: Phase::Convert,
+    });
+
+    let summary = Mutex::new(summary);
+    let in_flight = Mutex::new(HashSet::new());
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..worker_count().min(files.len()) {
+            scope.spawn(|| {
+                while control.should_continue() {
+                    let Some(src) = files.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                        break;
+                    };
+                    on_event(JobEvent::FileStarted { path: src.clone() });
+                    match convert_one(spec, src, journal, control, &in_flight) {
+                        Ok(None) => {}
+                        Ok(Some(Converted {
+                            outputs,
+                            bytes_in,
+                            metadata_removed,
+                            color,
+                            frames,
+                            png_saved,
+                        })) => {
+                            let bytes_out = outputs.iter().map(|output| output.bytes).sum();
+                            {
+                                let mut summary = summary.lock().unwrap();
+                                summary.converted += 1;
+                                summary.bytes_in += bytes_in;
+                                summary.bytes_out += bytes_out;
+                                for &field in &metadata_removed {
+                                    *summary.metadata_removed.entry(field).or_default() += 1;
+                                }
+                                summary.png_saved += png_saved.unwrap_or(0);
+                                if let Some(source) = &color.source {
+                                    *summary.color_spaces.entry(source.clone()).or_default() += 1;
+                                }
+                            }
+                            on_event(JobEvent::FileConverted {
+                                source: src.clone(),
+                                outputs,
+                                bytes_in,
+                                bytes_out,
+                                metadata_removed: metadata_removed.into_iter().collect(),
+                                color,
+                                frames,
+                                png_saved,
+                            });
+                        }
+                        Err(e) => {
+                            let unsupported = e.is_unsupported();
+                            {
+                                let mut summary = summary.lock().unwrap();
+                                if unsupported {
+                                    summary.unsupported += 1;
+                                } else {
+                                    summary.failed += 1;
+                                }
+                            }
+                            let reason = e.to_string();
+                            let _ = journal.record(
+                                src,
+                                FileStage::Failed {
+                                    reason: reason.clone(),
+                                },
+                            );
+                            let path = src.clone();
+                            on_event(if unsupported {
+                                JobEvent::FileUnsupported { path, reason }
+                            } else {
+                                JobEvent::FileFailed { path, reason }
+                            });
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    // Workers clean up after themselves; this only catches temp files left
+    // by a worker that panicked mid-write.
+    for temp in in_flight.into_inner().unwrap_or_default() {
+        let _ = fs::remove_file(temp);
+    }
+
+    let mut summary = summary.into_inner().unwrap();
+    if !control.is_cancelled() {
+        rename::run(spec, journal, cache, control, on_event, &mut summary)?;
+    }
+    summary.files = results(journal);
+    if spec.snippets.enabled && !control.is_cancelled() {
+        on_event(JobEvent::PhaseChanged {
+            phase: Phase::Export,
+        });
+        let export = snippets::export(spec, &summary.files)?;
+        on_event(JobEvent::SnippetsExported {
+            path: export.combined.clone(),
+            files: export.fragments.len(),
+        });
+        summary.snippets = Some(export);
+    }
+    on_event(JobEvent::JobFinished(summary.clone()));
+    Ok(summary)
+}
+
+/// Final outputs of every file in `journal` whose original was backed up,
+/// sorted by source.
+pub fn results(journal: &Journal) -> Vec<FileResult> {
+    let mut files: Vec<FileResult> = journal
+        .stages()
+        .into_iter()
+        .filter_map(|(source, stage)| match stage {
+            FileStage::BackedUp { outputs } => Some(FileResult {
+                source,
+                outputs,
+                description: None,
+            }),
+            FileStage::Renamed {
+                outputs,
+                description,
+            } => Some(FileResult {
+                source,
+                outputs,
+                description,
+            }),
+            _ => None,
+        })
+        .collect();
+    files.sort_by(|a, b| a.source.cmp(&b.source));
+    files
+}
+
+/// Undoes the commits a previous run journaled but may not have completed:
+/// the claimed outputs are deleted and the originals put back, so the files
+/// are converted again.
+pub fn undo_interrupted_commits(journal: &Journal) -> io::Result<()> {
+    for (src, stage) in journal.stages() {
+        if let FileStage::Staged { backup, outputs } = stage {
+            undo_commit(&src, &backup, &outputs)?;
+            journal.record(&src, FileStage::Restored)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reverses [`commit_outputs`] for `src`, however far it got. Every output
+/// name was claimed by the job, so whatever is found there is deleted; the
+/// original's own name only holds an output once the original is in the
+/// backup folder.
+fn undo_commit(src: &Path, backup: &Path, outputs: &[OutputVariant]) -> io::Result<()> {
+    let backed_up = backup.exists();
+    for output in outputs {
+        if output.path == src && !backed_up {
+            continue;
+        }
+        match fs::remove_file(&output.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    if backed_up {
+        fs::rename(backup, src)?;
+    }
+    Ok(())
+}
+
+/// What [`convert_one`] produced for one source.
+struct Converted {
+    outputs: Vec<OutputVariant>,
+    bytes_in: u64,
+    metadata_removed: BTreeSet<MetadataField>,
+    color: ColorReport,
+    frames: Option<usize>,
+    png_saved: Option<u64>,
+}
+
+/// Converts a single file to every requested format and moves its original
+/// into the backup folder. Returns `None` if the job was cancelled before
+/// the outputs were committed.
+fn convert_one(
+    spec: &JobSpec,
+    src: &Path,
+    journal: &Journal,
+    control: &JobControl,
+    in_flight: &Mutex<HashSet<PathBuf>>,
+) -> Result<Option<Converted>, convert::ConvertError> {
+    let JobSpec { root, options, .. } = spec;
+    let bytes_in = fs::metadata(src)?.len();
+    let convert::Conversion {
+        outputs: encoded,
+        metadata_removed,
+        color,
+        frames,
+        png_saved,
+    } = convert::convert(src, options)?;
+    let dir = src.parent().unwrap_or(root);
+    let stem = src
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+
+    let mut temps = Vec::with_capacity(encoded.len());
+    let mut written = Ok(());
+    for output in &encoded {
+        let suffix = convert::name_suffix(output.format, output.page, output.size.ladder);
+        match convert::write_temp(dir, &stem, &suffix, &output.bytes) {
+            Ok(temp) => {
+                in_flight.lock().unwrap().insert(temp.clone());
+                temps.push((temp, suffix));
+            }
+            Err(e) => {
+                written = Err(e);
+                break;
+            }
+        }
+    }
+    let committed = match written {
+        Err(e) => Err(e),
+        Ok(()) if control.is_cancelled() => Ok(None),
+        Ok(()) => commit_outputs(spec, src, journal, &encoded, &temps, dir, &stem).map(Some),
+    };
+    for (temp, _) in &temps {
+        if !matches!(committed, Ok(Some(_))) {
+            let _ = fs::remove_file(temp);
+        }
+        in_flight.lock().unwrap().remove(temp);
+    }
+    let Some(outputs) = committed? else {
+        return Ok(None);
+    };
+    journal.record(
+        src,
+        FileStage::BackedUp {
+            outputs: outputs.clone(),
+        },
+    )?;
+    Ok(Some(Converted {
+        outputs,
+        bytes_in,
+        metadata_removed,
+        color,
+        frames,
+        png_saved,
+    }))
+}
+
+/// Moves the original into the backup folder, then moves the `temps`
+/// written for `encoded` onto their real names. The original goes first so
+/// that an output with its name, such as the minified copy of an SVG or the
+/// JPEG of a JPEG, takes that name instead of a numbered one.
+///
+/// The names are claimed and journaled together with the backup path
+/// before anything is moved, so [`undo_interrupted_commits`] can undo a
+/// commit that was cut short; one that fails here is undone straight away.
+/// A file already in the backup folder is never overwritten.
+fn commit_outputs(
+    spec: &JobSpec,
+    src: &Path,
+    journal: &Journal,
+    encoded: &[convert::Encoded],
+    temps: &[(PathBuf, String)],
+    dir: &Path,
+    stem: &str,
+) -> io::Result<Vec<OutputVariant>> {
+    let backup = backup_path(&spec.root, &spec.backup_dir, src);
+    if backup.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' is already in the backup folder", backup.display()),
+        ));
+    }
+    let suffixes: Vec<&str> = temps.iter().map(|(_, suffix)| suffix.as_str()).collect();
+    let paths = convert::claim_names(dir, stem, &suffixes, Some(src))?;
+    let outputs: Vec<OutputVariant> = encoded
+        .iter()
+        .zip(paths)
+        .map(|(output, path)| OutputVariant {
+            format: output.format,
+            page: output.page,
+            path,
+            bytes: output.bytes.len() as u64,
+            width: output.size.width,
+            height: output.size.height,
+            ladder: output.size.ladder,
+        })
+        .collect();
+    let intent = FileStage::Staged {
+        backup: backup.clone(),
+        outputs: outputs.clone(),
+    };
+    let moved = journal.record(src, intent).and_then(|()| {
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(src, &backup)?;
+        for ((temp, _), output) in temps.iter().zip(&outputs) {
+            fs::rename(temp, &output.path)?;
+        }
+        Ok(())
+    });
+    match moved {
+        Ok(()) => Ok(outputs),
+        Err(e) => {
+            let _ = undo_commit(src, &backup, &outputs);
+            Err(e)
+        }
+    }
+}
+
+/// Where the original `src` is kept in the backup folder.
+fn backup_path(root: &Path, backup_dir: &Path, src: &Path) -> PathBuf {
+    backup_dir.join(src.strip_prefix(root).unwrap_or(src))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{Encoded, OutputSize};
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    /// A job on a fresh directory holding `photo.jpg`, with JPEG and WebP
+    /// outputs of it written to temp files.
+    struct Fixture {
+        root: PathBuf,
+        spec: JobSpec,
+        journal: Journal,
+        src: PathBuf,
+        encoded: Vec<Encoded>,
+        temps: Vec<(PathBuf, String)>,
+    }
+
+    fn fixture(name: &str) -> Fixture {
+        let root = std::env::temp_dir().join(format!("pipeline-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let src = root.join("photo.jpg");
+        fs::write(&src, "original").unwrap();
+        let spec = JobSpec::new(
+            root.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let journal = Journal::create(&root.join("jobs"), ID, &spec).unwrap();
+        let encoded: Vec<Encoded> = [OutputFormat::Jpeg, OutputFormat::Webp]
+            .into_iter()
+            .map(|format| Encoded {
+                format,
+                page: None,
+                size: OutputSize {
+                    ladder: None,
+                    width: 8,
+                    height: 8,
+                },
+                bytes: format.extension().as_bytes().to_vec(),
+            })
+            .collect();
+        let temps = encoded
+            .iter()
+            .map(|output| {
+                let suffix = convert::name_suffix(output.format, None, None);
+                let temp = convert::write_temp(&root, "photo", &suffix, &output.bytes).unwrap();
+                (temp, suffix)
+            })
+            .collect();
+        Fixture {
+            root,
+            spec,
+            journal,
+            src,
+            encoded,
+            temps,
+        }
+    }
+
+    fn commit(f: &Fixture) -> io::Result<Vec<OutputVariant>> {
+        commit_outputs(
+            &f.spec, &f.src, &f.journal, &f.encoded, &f.temps, &f.root, "photo",
+        )
+    }
+
+    #[test]
+    fn outputs_take_the_originals_name() {
+        let f = fixture("commit");
+        let outputs = commit(&f).unwrap();
+        let paths: Vec<&Path> = outputs.iter().map(|output| output.path.as_path()).collect();
+        assert_eq!(paths, [f.src.clone(), f.root.join("photo.webp")]);
+        assert_eq!(fs::read(&f.src).unwrap(), b"jpg");
+        assert_eq!(fs::read(f.root.join("photo.webp")).unwrap(), b"webp");
+        let backup = f.spec.backup_dir.join("photo.jpg");
+        assert_eq!(fs::read(&backup).unwrap(), b"original");
+        assert_eq!(
+            f.journal.stages()[&f.src],
+            FileStage::Staged { backup, outputs }
+        );
+        fs::remove_dir_all(&f.root).unwrap();
+    }
+
+    #[test]
+    fn undoes_a_commit_cut_short() {
+        let f = fixture("undo");
+        commit(&f).unwrap();
+        // The run died before recording the file as backed up.
+        undo_interrupted_commits(&f.journal).unwrap();
+        assert_eq!(fs::read(&f.src).unwrap(), b"original");
+        assert!(!f.root.join("photo.webp").exists());
+        assert!(!f.spec.backup_dir.join("photo.jpg").exists());
+        assert_eq!(f.journal.stages()[&f.src], FileStage::Restored);
+
+        // Died right after the original was moved, with only the
+        // placeholders in place.
+        let backup = f.spec.backup_dir.join("photo.jpg");
+        fs::rename(&f.src, &backup).unwrap();
+        fs::write(f.root.join("photo.webp"), "").unwrap();
+        let outputs = vec![OutputVariant {
+            format: OutputFormat::Webp,
+            page: None,
+            path: f.root.join("photo.webp"),
+            bytes: 4,
+            width: 8,
+            height: 8,
+            ladder: None,
+        }];
+        f.journal
+            .record(&f.src, FileStage::Staged { backup, outputs })
+            .unwrap();
+        undo_interrupted_commits(&f.journal).unwrap();
+        assert_eq!(fs::read(&f.src).unwrap(), b"original");
+        assert!(!f.root.join("photo.webp").exists());
+        fs::remove_dir_all(&f.root).unwrap();
+    }
+
+    #[test]
+    fn never_overwrites_a_backup() {
+        let f = fixture("backup");
+        let backup = f.spec.backup_dir.join("photo.jpg");
+        fs::create_dir_all(&f.spec.backup_dir).unwrap();
+        fs::write(&backup, "earlier").unwrap();
+        let err = commit(&f).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&backup).unwrap(), b"earlier");
+        assert_eq!(fs::read(&f.src).unwrap(), b"original");
+        assert!(!f.root.join("photo.webp").exists());
+        assert!(f.temps.iter().all(|(temp, _)| temp.exists()));
+        assert!(f.journal.stages().is_empty());
+        fs::remove_dir_all(&f.root).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Dry-run planning.
+//!
+//! Walks the target directory the same way the pipeline does and predicts
+//! what a real run would do to each file, without writing anything. The UI
+//! shows the resulting [`JobPlan`] so the user can approve it before
+//! calling `start_job`.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::cache::{self, DescriptionCache};
+use crate::convert::{self, ConvertOptions, OutputFormat, OutputSize, WEBP_MAX_DIMENSION};
+use crate::input::{self, FormatMismatch, SourceFormat};
+use crate::pipeline::{self, JobSpec};
+use crate::svg;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "action",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum PlannedAction {
+    Convert {
+        /// One output per size and format: every format of the first size,
+        /// then every format of the next, for each page in turn.
+        outputs: Vec<PathBuf>,
+        /// Where the original will be moved to.
+        backup: PathBuf,
+        formats: Vec<OutputFormat>,
+        /// The full-size output followed by each ladder width below it, for
+        /// the first page. For an SVG, its own size followed by the widths
+        /// of its raster copies.
+        sizes: Vec<OutputSize>,
+        /// Number of pages converted; more than one only for multi-page
+        /// TIFFs when all pages are requested.
+        pages: usize,
+        /// Final paths after AI renaming, if a cached description exists.
+        ai_names: Option<Vec<PathBuf>>,
+        /// Set when the natural output name was taken and a numbered one
+        /// will be used instead.
+        collision: Option<String>,
+    },
+    Skip {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedFile {
+    pub source: PathBuf,
+    /// Set when the extension names a different format than the contents.
+    pub mismatch: Option<FormatMismatch>,
+    #[serde(flatten)]
+    pub action: PlannedAction,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobPlan {
+    pub root: PathBuf,
+    pub backup_dir: PathBuf,
+    pub files: Vec<PlannedFile>,
+    pub to_convert: usize,
+    pub to_skip: usize,
+    pub collisions: usize,
+    pub mismatches: usize,
+}
+
+/// Predicts the outcome of running `spec`. Names are assigned in discovery
+/// order, matching the order the pipeline dispatches files in. `generated`
+/// are the outputs of earlier jobs, which are not converted again.
+pub fn plan(
+    spec: &JobSpec,
+    cache: &DescriptionCache,
+    generated: &HashSet<PathBuf>,
+) -> io::Result<JobPlan> {
+    let mut claimed = HashSet::new();
+    let mut files = Vec::new();
+    for (source, format) in pipeline::discover(&spec.root, generated)? {
+        let outputs = match format {
+            SourceFormat::Svg => svg::planned_outputs(&source, &spec.options.svg)
+                .map(|outputs| {
+                    outputs
+                        .into_iter()
+                        .map(|(format, size)| (format, None, size))
+                        .collect()
+                })
+                .map_err(|e| e.to_string()),
+            _ => check_decodable(&source, &spec.options)
+                .map(|pages| raster_outputs(pages, &spec.options.formats)),
+        };
+        let action = match outputs {
+            Err(reason) => PlannedAction::Skip { reason },
+            Ok(outputs) => plan_convert(spec, &source, outputs, cache, &mut claimed),
+        };
+        files.push(PlannedFile {
+            mismatch: FormatMismatch::check(&source, format),
+            source,
+            action,
+        });
+    }
+
+    let mut plan = JobPlan {
+        root: spec.root.clone(),
+        backup_dir: spec.backup_dir.clone(),
+        files: Vec::new(),
+        to_convert: 0,
+        to_skip: 0,
+        collisions: 0,
+        mismatches: 0,
+    };
+    for file in &files {
+        plan.mismatches += usize::from(file.mismatch.is_some());
+        match &file.action {
+            PlannedAction::Convert { collision, .. } => {
+                plan.to_convert += 1;
+                plan.collisions += usize::from(collision.is_some());
+            }
+            PlannedAction::Skip { .. } => plan.to_skip += 1,
+        }
+    }
+    plan.files = files;
+    Ok(plan)
+}
+
+/// Reads just the image header to catch files the pipeline would fail on,
+/// and returns the sizes each page would be written in.
+fn check_decodable(
+    source: &Path,
+    options: &ConvertOptions,
+) -> Result<Vec<Vec<OutputSize>>, String> {
+    let pages = input::upright_dimensions(source, options.tiff_pages).map_err(|e| match e {
+        convert::ConvertError::Decode(e) => format!("cannot be decoded: {e}"),
+        e => e.to_string(),
+    })?;
+    let webp = options.formats.contains(&OutputFormat::Webp);
+    let mut sizes = Vec::with_capacity(pages.len());
+    for (width, height) in pages {
+        let page = convert::output_sizes(width, height, options);
+        let OutputSize { width, height, .. } = page[0];
+        if webp && (width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION) {
+            return Err(format!(
+                "{width}x{height} is larger than WebP allows ({WEBP_MAX_DIMENSION}px)"
+            ));
+        }
+        sizes.push(page);
+    }
+    Ok(sizes)
+}
+
+/// An output's format, page number and size.
+type PlannedOutput = (OutputFormat, Option<u32>, OutputSize);
+
+/// The outputs of a raster source with these sizes per page, in the order
+/// [`convert::convert`] writes them.
+fn raster_outputs(pages: Vec<Vec<OutputSize>>, formats: &[OutputFormat]) -> Vec<PlannedOutput> {
+    let mut outputs = Vec::new();
+    for (i, sizes) in pages.into_iter().enumerate() {
+        let page = (i > 0).then_some(i as u32 + 1);
+        for size in sizes {
+            for &format in formats {
+                outputs.push((format, page, size));
+            }
+        }
+    }
+    outputs
+}
+
+fn plan_convert(
+    spec: &JobSpec,
+    source: &Path,
+    planned: Vec<PlannedOutput>,
+    cache: &DescriptionCache,
+    claimed: &mut HashSet<PathBuf>,
+) -> PlannedAction {
+    let dir = source.parent().unwrap_or(Path::new(""));
+    let stem = source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".to_string());
+    let mut formats = Vec::new();
+    let mut sizes = Vec::new();
+    let mut pages = 1;
+    let mut suffixes = Vec::new();
+    for (format, page, size) in planned {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+        match page {
+            None if !sizes.contains(&size) => sizes.push(size),
+            None => {}
+            Some(page) => pages = pages.max(page as usize),
+        }
+        suffixes.push(convert::name_suffix(format, page, size.ladder));
+    }
+    let (outputs, collision) = claim(dir, &stem, &suffixes, source, claimed);
+
+    let ai_names = if cache.is_empty() {
+        None
+    } else {
+        cache::content_hash(source)
+            .ok()
+            .and_then(|hash| cache.get(&hash))
+            .map(|cached| claim(dir, &cached.slug, &suffixes, source, claimed).0)
+    };
+
+    let rel = source.strip_prefix(&spec.root).unwrap_or(source);
+    PlannedAction::Convert {
+        outputs,
+        backup: spec.backup_dir.join(rel),
+        formats,
+        sizes,
+        pages,
+        ai_names,
+        collision,
+    }
+}
+
+/// Picks the names [`convert::claim_names`] would choose for `stem` with
+/// each of `suffixes` in `dir`, given the files on disk and the names already
+/// claimed by this plan. `source` does not count as taken: it is moved to
+/// the backup folder before its outputs are committed. Returns the paths
+/// and, if they had to be numbered, why.
+fn claim(
+    dir: &Path,
+    stem: &str,
+    suffixes: &[String],
+    source: &Path,
+    claimed: &mut HashSet<PathBuf>,
+) -> (Vec<PathBuf>, Option<String>) {
+    let mut counter = 0;
+    let mut collision = None;
+    loop {
+        let paths: Vec<PathBuf> = suffixes
+            .iter()
+            .map(|suffix| dir.join(convert::numbered_name(stem, suffix, counter)))
+            .collect();
+        if let Some(path) = paths.iter().find(|path| *path != source && path.exists()) {
+            collision.get_or_insert_with(|| format!("'{}' already exists", path.display()));
+        } else if let Some(path) = paths.iter().find(|path| claimed.contains(*path)) {
+            collision.get_or_insert_with(|| {
+                format!("'{}' is also the output of another file", path.display())
+            });
+        } else {
+            claimed.extend(paths.iter().cloned());
+            return (paths, collision);
+        }
+        counter += 1;
+    }
+}
@@ -0,0 +1,496 @@
+//! Camera RAW previews.
+//!
+//! Rendering sensor data takes demosaicing, white balance and a colour
+//! profile for every camera model. For web output and for the vision model
+//! the JPEG the camera already rendered and embedded in the file is good
+//! enough: it is what the camera showed on its screen.
+//!
+//! CR2, NEF, ARW and DNG are all TIFF structures. Their IFDs and sub-IFDs
+//! are walked for JPEG streams, and the largest one that an ordinary JPEG
+//! decoder can read is used. The lossless JPEG some of them store the
+//! sensor data in is skipped.
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+use image::metadata::Orientation;
+
+use crate::exif::{self, ByteOrder, SHORT};
+
+const COMPRESSION: u16 = 0x0103;
+const MAKE: u16 = 0x010F;
+const STRIP_OFFSETS: u16 = 0x0111;
+const ORIENTATION: u16 = 0x0112;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const SUB_IFDS: u16 = 0x014A;
+const JPEG_OFFSET: u16 = 0x0201;
+const JPEG_LENGTH: u16 = 0x0202;
+const DNG_VERSION: u16 = 0xC612;
+
+const LONG: u16 = 4;
+const IFD: u16 = 13;
+
+/// Old-style and new-style JPEG compression.
+const JPEG_COMPRESSION: [u32; 2] = [6, 7];
+
+/// More IFDs than this only occur in malicious files.
+const MAX_IFDS: usize = 64;
+
+/// The embedded preview of a RAW file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preview {
+    /// Where the JPEG stream is in the file.
+    pub range: Range<usize>,
+    pub width: u32,
+    pub height: u32,
+    /// The orientation recorded for the RAW file, which applies to its
+    /// previews too.
+    pub orientation: Orientation,
+}
+
+/// Finds the largest decodable JPEG preview in the RAW file `data`, or
+/// `None` if it is not a TIFF-based RAW file or has no such preview.
+pub fn preview(data: &[u8]) -> Option<Preview> {
+    let order = ByteOrder::detect(data)?;
+    let mut orientation = Orientation::NoTransforms;
+    let mut best: Option<(Range<usize>, u32, u32)> = None;
+    let mut pending = vec![(order.u32(data.get(4..8)?) as usize, true)];
+    let mut visited = HashSet::new();
+    while let Some((offset, is_ifd0)) = pending.pop() {
+        if offset == 0 || visited.len() >= MAX_IFDS || !visited.insert(offset) {
+            continue;
+        }
+        let Some((entries, next)) = read_ifd(data, order, offset) else {
+            continue;
+        };
+        pending.push((next, false));
+        let values = |tag: u16| {
+            entries
+                .iter()
+                .find(|entry| entry.tag == tag)
+                .map(|entry| entry.values(data, order))
+                .unwrap_or_default()
+        };
+        if is_ifd0 {
+            if let Some(value) = values(ORIENTATION).first() {
+                orientation = Orientation::from_exif(*value as u8).unwrap_or(orientation);
+            }
+        }
+        pending.extend(
+            values(SUB_IFDS)
+                .iter()
+                .map(|&offset| (offset as usize, false)),
+        );
+
+        let mut streams = Vec::new();
+        if let ([offset], [length]) = (&values(JPEG_OFFSET)[..], &values(JPEG_LENGTH)[..]) {
+            streams.push((*offset, *length));
+        }
+        let compressed = values(COMPRESSION)
+            .first()
+            .is_some_and(|compression| JPEG_COMPRESSION.contains(compression));
+        if let (true, [offset], [length]) = (
+            compressed,
+            &values(STRIP_OFFSETS)[..],
+            &values(STRIP_BYTE_COUNTS)[..],
+        ) {
+            streams.push((*offset, *length));
+        }
+        for (offset, length) in streams {
+            let start = offset as usize;
+            let Some(range) = start
+                .checked_add(length as usize)
+                .filter(|&end| end <= data.len())
+                .map(|end| start..end)
+            else {
+                continue;
+            };
+            let Some((width, height)) = jpeg_dimensions(&data[range.clone()]) else {
+                continue;
+            };
+            let area = |width: u32, height: u32| u64::from(width) * u64::from(height);
+            if best
+                .as_ref()
+                .is_none_or(|(_, w, h)| area(width, height) > area(*w, *h))
+            {
+                best = Some((range, width, height));
+            }
+        }
+    }
+    best.map(|(range, width, height)| Preview {
+        range,
+        width,
+        height,
+        orientation,
+    })
+}
+
+/// Returns true if `header`, the start of a file, is a RAW file rather
+/// than an ordinary TIFF: a CR2, whose header carries a `CR` marker, a DNG,
+/// or a file whose first IFD names a camera make and has sub-IFDs, as NEF
+/// and ARW files do. Files whose first IFD lies beyond `header` are not
+/// recognised.
+pub fn is_raw(header: &[u8]) -> bool {
+    let Some(order) = ByteOrder::detect(header) else {
+        return false;
+    };
+    if header.get(8..10) == Some(b"CR") {
+        return true;
+    }
+    let Some(offset) = header.get(4..8).map(|offset| order.u32(offset) as usize) else {
+        return false;
+    };
+    let Some((entries, _)) = read_ifd(header, order, offset) else {
+        return false;
+    };
+    let has = |tag: u16| entries.iter().any(|entry| entry.tag == tag);
+    has(DNG_VERSION) || (has(MAKE) && has(SUB_IFDS))
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// The value, or the offset of the value if it does not fit.
+    raw: [u8; 4],
+}
+
+impl Entry {
+    /// The values of a SHORT, LONG or IFD entry; empty for other types.
+    fn values(&self, data: &[u8], order: ByteOrder) -> Vec<u32> {
+        let Some(size) = exif::type_size(self.kind) else {
+            return Vec::new();
+        };
+        let Some(len) = size.checked_mul(self.count as usize) else {
+            return Vec::new();
+        };
+        let bytes = if len <= 4 {
+            &self.raw[..len]
+        } else {
+            let start = order.u32(&self.raw) as usize;
+            match start.checked_add(len).and_then(|end| data.get(start..end)) {
+                Some(bytes) => bytes,
+                None => return Vec::new(),
+            }
+        };
+        match self.kind {
+            SHORT => bytes
+                .chunks_exact(2)
+                .map(|value| u32::from(order.u16(value)))
+                .collect(),
+            LONG | IFD => bytes
+                .chunks_exact(4)
+                .map(|value| order.u32(value))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The entries of the IFD at `offset` and the offset of the next IFD.
+fn read_ifd(data: &[u8], order: ByteOrder, offset: usize) -> Option<(Vec<Entry>, usize)> {
+    let count = usize::from(order.u16(data.get(offset..offset.checked_add(2)?)?));
+    let end = offset + 2 + count * 12;
+    let entries = data
+        .get(offset + 2..end)?
+        .chunks_exact(12)
+        .map(|raw| Entry {
+            tag: order.u16(&raw[0..2]),
+            kind: order.u16(&raw[2..4]),
+            count: order.u32(&raw[4..8]),
+            raw: [raw[8], raw[9], raw[10], raw[11]],
+        })
+        .collect();
+    let next = data
+        .get(end..end + 4)
+        .map_or(0, |next| order.u32(next) as usize);
+    Some((entries, next))
+}
+
+/// The dimensions of a baseline or progressive Huffman-coded JPEG, read
+/// from its frame header. Other JPEG processes, such as the lossless one
+/// used for sensor data, return `None`.
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u32, u32)> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        if *jpeg.get(pos)? != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF bytes may pad the space before a marker.
+        while *jpeg.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        let marker = jpeg[pos + 1];
+        pos += 2;
+        match marker {
+            0x01 | 0xD0..=0xD7 => continue,
+            0xC0..=0xC2 => {
+                let frame = jpeg.get(pos + 3..pos + 7)?;
+                let height = u16::from_be_bytes([frame[0], frame[1]]);
+                let width = u16::from_be_bytes([frame[2], frame[3]]);
+                return (width > 0 && height > 0).then_some((width.into(), height.into()));
+            }
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xD9 | 0xDA => return None,
+            _ => {
+                let length = jpeg.get(pos..pos + 2)?;
+                pos += usize::from(u16::from_be_bytes([length[0], length[1]]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: u16 = 2;
+
+    /// The start of a JPEG up to its frame header, which is all the walk
+    /// reads of a stream.
+    fn jpeg(sof: u8, width: u16, height: u16) -> Vec<u8> {
+        let mut jpeg = vec![
+            0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0, 0xFF, 0xFF, sof, 0, 11, 8,
+        ];
+        jpeg.extend_from_slice(&height.to_be_bytes());
+        jpeg.extend_from_slice(&width.to_be_bytes());
+        jpeg.extend_from_slice(&[1, 1, 0x11, 0, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    /// Builds a TIFF structure bottom-up: blobs and child IFDs are added
+    /// first, so their offsets are known when the IFDs pointing to them are
+    /// written.
+    struct Tiff {
+        order: ByteOrder,
+        data: Vec<u8>,
+    }
+
+    impl Tiff {
+        fn new(order: ByteOrder) -> Self {
+            let header = match order {
+                ByteOrder::Little => b"II*\0\0\0\0\0",
+                ByteOrder::Big => b"MM\0*\0\0\0\0",
+            };
+            Self {
+                order,
+                data: header.to_vec(),
+            }
+        }
+
+        /// An empty buffer in the same byte order.
+        fn scratch(&self) -> Self {
+            Self {
+                order: self.order,
+                data: Vec::new(),
+            }
+        }
+
+        fn put_u16(&mut self, value: u16) {
+            self.data.extend_from_slice(&match self.order {
+                ByteOrder::Little => value.to_le_bytes(),
+                ByteOrder::Big => value.to_be_bytes(),
+            });
+        }
+
+        fn put_u32(&mut self, value: u32) {
+            self.data.extend_from_slice(&match self.order {
+                ByteOrder::Little => value.to_le_bytes(),
+                ByteOrder::Big => value.to_be_bytes(),
+            });
+        }
+
+        fn blob(&mut self, bytes: &[u8]) -> u32 {
+            let offset = self.data.len() as u32;
+            self.data.extend_from_slice(bytes);
+            offset
+        }
+
+        /// Adds an IFD of SHORT, LONG, IFD or ASCII entries, followed by
+        /// the values that do not fit in their entry.
+        fn ifd(&mut self, entries: &[(u16, u16, &[u32])], next: u32) -> u32 {
+            let start = self.data.len();
+            let mut extra = start + 2 + entries.len() * 12 + 4;
+            let mut values = self.scratch();
+            self.put_u16(entries.len() as u16);
+            for &(tag, kind, items) in entries {
+                self.put_u16(tag);
+                self.put_u16(kind);
+                self.put_u32(items.len() as u32);
+                let mut value = self.scratch();
+                for &item in items {
+                    match kind {
+                        SHORT => value.put_u16(item as u16),
+                        ASCII => value.data.push(item as u8),
+                        _ => value.put_u32(item),
+                    }
+                }
+                if value.data.len() <= 4 {
+                    value.data.resize(4, 0);
+                    self.data.extend_from_slice(&value.data);
+                } else {
+                    self.put_u32(extra as u32);
+                    extra += value.data.len();
+                    values.data.extend_from_slice(&value.data);
+                }
+            }
+            self.put_u32(next);
+            self.data.extend_from_slice(&values.data);
+            start as u32
+        }
+
+        fn finish(mut self, ifd0: u32) -> Vec<u8> {
+            let mut offset = self.scratch();
+            offset.put_u32(ifd0);
+            self.data[4..8].copy_from_slice(&offset.data);
+            self.data
+        }
+    }
+
+    fn stream(tiff: &mut Tiff, jpeg: &[u8]) -> (u32, Range<usize>) {
+        let offset = tiff.blob(jpeg);
+        (offset, offset as usize..offset as usize + jpeg.len())
+    }
+
+    /// A NEF-like file: a thumbnail in IFD0, a larger preview and the
+    /// lossless sensor data in sub-IFDs, and a second top-level IFD.
+    fn sample(order: ByteOrder) -> (Vec<u8>, Range<usize>) {
+        let mut tiff = Tiff::new(order);
+        let (thumb, thumb_range) = stream(&mut tiff, &jpeg(0xC0, 160, 120));
+        let (preview, preview_range) = stream(&mut tiff, &jpeg(0xC0, 1024, 768));
+        let (sensor, sensor_range) = stream(&mut tiff, &jpeg(0xC3, 6000, 4000));
+        let (ifd1_jpeg, ifd1_range) = stream(&mut tiff, &jpeg(0xC2, 640, 480));
+        let len = |range: &Range<usize>| range.len() as u32;
+        let preview_ifd = tiff.ifd(
+            &[
+                (COMPRESSION, SHORT, &[6]),
+                (STRIP_OFFSETS, LONG, &[preview]),
+                (STRIP_BYTE_COUNTS, LONG, &[len(&preview_range)]),
+            ],
+            0,
+        );
+        let sensor_ifd = tiff.ifd(
+            &[
+                (COMPRESSION, SHORT, &[7]),
+                (STRIP_OFFSETS, LONG, &[sensor]),
+                (STRIP_BYTE_COUNTS, LONG, &[len(&sensor_range)]),
+            ],
+            0,
+        );
+        let ifd1 = tiff.ifd(
+            &[
+                (ORIENTATION, SHORT, &[3]),
+                (JPEG_OFFSET, LONG, &[ifd1_jpeg]),
+                (JPEG_LENGTH, LONG, &[len(&ifd1_range)]),
+            ],
+            0,
+        );
+        let make = b"NIKON\0".map(u32::from);
+        let ifd0 = tiff.ifd(
+            &[
+                (MAKE, ASCII, &make),
+                (ORIENTATION, SHORT, &[6]),
+                (SUB_IFDS, IFD, &[preview_ifd, sensor_ifd]),
+                (JPEG_OFFSET, LONG, &[thumb]),
+                (JPEG_LENGTH, LONG, &[len(&thumb_range)]),
+            ],
+            ifd1,
+        );
+        (tiff.finish(ifd0), preview_range)
+    }
+
+    #[test]
+    fn finds_largest_decodable_preview() {
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let (data, range) = sample(order);
+            assert!(is_raw(&data));
+            assert_eq!(
+                preview(&data),
+                Some(Preview {
+                    range,
+                    width: 1024,
+                    height: 768,
+                    // Only IFD0's orientation counts.
+                    orientation: Orientation::Rotate90,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn skips_streams_out_of_bounds() {
+        let mut tiff = Tiff::new(ByteOrder::Little);
+        let (offset, range) = stream(&mut tiff, &jpeg(0xC0, 100, 100));
+        let ifd0 = tiff.ifd(
+            &[
+                (JPEG_OFFSET, LONG, &[offset]),
+                (JPEG_LENGTH, LONG, &[range.len() as u32 + 1000]),
+            ],
+            0,
+        );
+        assert_eq!(preview(&tiff.finish(ifd0)), None);
+
+        // Uncompressed strips are sensor or image data, not a preview.
+        let mut tiff = Tiff::new(ByteOrder::Little);
+        let (offset, range) = stream(&mut tiff, &jpeg(0xC0, 100, 100));
+        let ifd0 = tiff.ifd(
+            &[
+                (COMPRESSION, SHORT, &[1]),
+                (STRIP_OFFSETS, LONG, &[offset]),
+                (STRIP_BYTE_COUNTS, LONG, &[range.len() as u32]),
+            ],
+            0,
+        );
+        assert_eq!(preview(&tiff.finish(ifd0)), None);
+    }
+
+    #[test]
+    fn survives_ifd_loops() {
+        let mut tiff = Tiff::new(ByteOrder::Big);
+        let (offset, range) = stream(&mut tiff, &jpeg(0xC1, 300, 200));
+        // IFD0 will be written at the current end, and points to itself as
+        // both its next IFD and its sub-IFD.
+        let ifd0 = tiff.data.len() as u32;
+        tiff.ifd(
+            &[
+                (SUB_IFDS, LONG, &[ifd0]),
+                (JPEG_OFFSET, LONG, &[offset]),
+                (JPEG_LENGTH, LONG, &[range.len() as u32]),
+            ],
+            ifd0,
+        );
+        let found = preview(&tiff.finish(ifd0)).unwrap();
+        assert_eq!((found.range, found.width, found.height), (range, 300, 200));
+        assert_eq!(found.orientation, Orientation::NoTransforms);
+    }
+
+    #[test]
+    fn tells_raw_from_tiff() {
+        assert!(is_raw(b"II*\0\x10\0\0\0CR\x02\0\0\0\0\0"));
+        assert!(!is_raw(b"\xFF\xD8\xFF\xE0"));
+
+        let with = |entries: &[(u16, u16, &[u32])]| {
+            let mut tiff = Tiff::new(ByteOrder::Little);
+            let ifd0 = tiff.ifd(entries, 0);
+            is_raw(&tiff.finish(ifd0))
+        };
+        let make = b"SONY\0".map(u32::from);
+        assert!(with(&[(MAKE, ASCII, &make), (SUB_IFDS, LONG, &[0])]));
+        assert!(with(&[(DNG_VERSION, 1, &[])]));
+        // A scan from a scanner that records its make.
+        assert!(!with(&[(MAKE, ASCII, &make)]));
+        assert!(!with(&[]));
+    }
+
+    #[test]
+    fn reads_frame_header() {
+        assert_eq!(jpeg_dimensions(&jpeg(0xC0, 64, 48)), Some((64, 48)));
+        assert_eq!(jpeg_dimensions(&jpeg(0xC2, 64, 48)), Some((64, 48)));
+        assert_eq!(jpeg_dimensions(&jpeg(0xC3, 64, 48)), None);
+        assert_eq!(jpeg_dimensions(&jpeg(0xC0, 0, 48)), None);
+        assert_eq!(jpeg_dimensions(&jpeg(0xC0, 64, 48)[..12]), None);
+        assert_eq!(jpeg_dimensions(b"II*\0"), None);
+    }
+}
@@ -0,0 +1,418 @@
+//! Lossless PNG output.
+//!
+//! Does what `oxipng`/`zopflipng` do for the Python optimizer, in-process:
+//! the image is stored in the smallest colour type and bit depth that
+//! holds it exactly (grayscale, a palette of up to 256 colours, no alpha
+//! channel if it is fully opaque, 8 instead of 16 bits), then encoded with
+//! several filter and deflate settings, keeping the smallest result. The
+//! pixels always decode to the same values as the input.
+
+use std::collections::{HashMap, HashSet};
+
+use ::png::{BitDepth, ColorType, DeflateCompression, Filter};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::convert::{ConvertError, OutputFormat};
+
+/// PNG encoder settings.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PngOptions {
+    /// Effort, 1-100 (`quality.png` in `config.yaml`). PNG is lossless, so
+    /// higher values only try more encoder settings to find a smaller file.
+    pub quality: u8,
+}
+
+impl Default for PngOptions {
+    fn default() -> Self {
+        Self { quality: 90 }
+    }
+}
+
+impl PngOptions {
+    /// The filter and deflate level combinations tried.
+    fn trials(&self) -> Vec<(Filter, u8)> {
+        match self.quality {
+            0..=39 => vec![(Filter::Adaptive, 6)],
+            40..=79 => vec![(Filter::Adaptive, 9), (Filter::NoFilter, 9)],
+            _ => [
+                Filter::Adaptive,
+                Filter::NoFilter,
+                Filter::Sub,
+                Filter::Up,
+                Filter::Avg,
+                Filter::Paeth,
+            ]
+            .into_iter()
+            .map(|filter| (filter, 9))
+            .collect(),
+        }
+    }
+}
+
+/// Encodes `img` as the smallest PNG this module can produce. With
+/// `rgb_profile` set the PNG is going to carry an RGB ICC profile, which
+/// grayscale PNGs cannot, so it is never reduced to grayscale.
+pub fn encode(
+    img: &DynamicImage,
+    options: &PngOptions,
+    rgb_profile: bool,
+) -> Result<Vec<u8>, ConvertError> {
+    // Floating-point images have no PNG colour type.
+    let img = match img {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            DynamicImage::ImageRgba16(img.to_rgba16())
+        }
+        _ => img.clone(),
+    };
+    let reduced = reduce(&img, !rgb_profile);
+    let mut best: Option<Vec<u8>> = None;
+    for (filter, level) in options.trials() {
+        let bytes = reduced
+            .write(img.width(), img.height(), filter, level)
+            .map_err(|e| ConvertError::Encode {
+                format: OutputFormat::Png,
+                reason: e.to_string(),
+            })?;
+        if best.as_ref().is_none_or(|best| bytes.len() < best.len()) {
+            best = Some(bytes);
+        }
+    }
+    Ok(best.expect("at least one trial"))
+}
+
+/// Image data in its reduced form, ready for the encoder.
+struct Reduced {
+    color: ColorType,
+    depth: BitDepth,
+    palette: Option<Vec<u8>>,
+    trns: Option<Vec<u8>>,
+    /// Rows packed at `depth`, without filter bytes.
+    data: Vec<u8>,
+}
+
+impl Reduced {
+    fn write(
+        &self,
+        width: u32,
+        height: u32,
+        filter: Filter,
+        level: u8,
+    ) -> Result<Vec<u8>, ::png::EncodingError> {
+        let mut out = Vec::new();
+        let mut encoder = ::png::Encoder::new(&mut out, width, height);
+        encoder.set_color(self.color);
+        encoder.set_depth(self.depth);
+        if let Some(palette) = &self.palette {
+            encoder.set_palette(palette.as_slice());
+        }
+        if let Some(trns) = &self.trns {
+            encoder.set_trns(trns.as_slice());
+        }
+        encoder.set_deflate_compression(DeflateCompression::Level(level));
+        encoder.set_filter(filter);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()?;
+        Ok(out)
+    }
+}
+
+/// Finds the smallest lossless representation of `img`.
+fn reduce(img: &DynamicImage, allow_gray: bool) -> Reduced {
+    let rgba16 = img.to_rgba16();
+    let opaque = rgba16.pixels().all(|px| px[3] == u16::MAX);
+    let gray = allow_gray && rgba16.pixels().all(|px| px[0] == px[1] && px[1] == px[2]);
+    // 8-bit values are stored in 16 bits as `v * 257`.
+    let fits_8_bit = rgba16.as_raw().iter().all(|&v| v % 257 == 0);
+    let width = img.width() as usize;
+
+    if !fits_8_bit {
+        let (color, channels): (ColorType, &[usize]) = match (gray, opaque) {
+            (true, true) => (ColorType::Grayscale, &[0]),
+            (true, false) => (ColorType::GrayscaleAlpha, &[0, 3]),
+            (false, true) => (ColorType::Rgb, &[0, 1, 2]),
+            (false, false) => (ColorType::Rgba, &[0, 1, 2, 3]),
+        };
+        let data = rgba16
+            .pixels()
+            .flat_map(|px| channels.iter().flat_map(move |&c| px[c].to_be_bytes()))
+            .collect();
+        return Reduced {
+            color,
+            depth: BitDepth::Sixteen,
+            palette: None,
+            trns: None,
+            data,
+        };
+    }
+
+    let rgba = img.to_rgba8();
+    if gray && opaque {
+        let depth = gray_depth(rgba.pixels().map(|px| px[0]));
+        let palette = palette_of(&rgba);
+        // A handful of arbitrary gray levels packs tighter as a palette.
+        if let Some((palette, indices)) =
+            palette.filter(|(palette, _)| index_depth(palette.len() / 4) < depth)
+        {
+            return palette_image(palette, &indices, width);
+        }
+        let scale = 255 / ((1u16 << depth) - 1) as u8;
+        let values: Vec<u8> = rgba.pixels().map(|px| px[0] / scale).collect();
+        return Reduced {
+            color: ColorType::Grayscale,
+            depth: bit_depth(depth),
+            palette: None,
+            trns: None,
+            data: pack(&values, depth, width),
+        };
+    }
+    if let Some((palette, indices)) = palette_of(&rgba) {
+        return palette_image(palette, &indices, width);
+    }
+    let (color, channels): (ColorType, &[usize]) = match (gray, opaque) {
+        (true, false) => (ColorType::GrayscaleAlpha, &[0, 3]),
+        (false, true) => (ColorType::Rgb, &[0, 1, 2]),
+        _ => (ColorType::Rgba, &[0, 1, 2, 3]),
+    };
+    let data = rgba
+        .pixels()
+        .flat_map(|px| channels.iter().map(move |&c| px[c]))
+        .collect();
+    Reduced {
+        color,
+        depth: BitDepth::Eight,
+        palette: None,
+        trns: None,
+        data,
+    }
+}
+
+/// The RGBA palette of `rgba` and each pixel's index into it, or `None`
+/// if it has more than 256 colours. Translucent entries come first, so the
+/// `tRNS` chunk can stop after the last of them.
+fn palette_of(rgba: &image::RgbaImage) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut colors: Vec<[u8; 4]> = Vec::new();
+    let mut seen = HashSet::new();
+    for px in rgba.pixels() {
+        if seen.insert(px.0) {
+            if colors.len() == 256 {
+                return None;
+            }
+            colors.push(px.0);
+        }
+    }
+    colors.sort_by_key(|color| color[3] == u8::MAX);
+    let index: HashMap<[u8; 4], u8> = colors
+        .iter()
+        .enumerate()
+        .map(|(i, &color)| (color, i as u8))
+        .collect();
+    let indices = rgba.pixels().map(|px| index[&px.0]).collect();
+    Some((colors.concat(), indices))
+}
+
+fn palette_image(palette: Vec<u8>, indices: &[u8], width: usize) -> Reduced {
+    let colors: Vec<&[u8]> = palette.chunks(4).collect();
+    let depth = index_depth(colors.len());
+    let translucent = colors
+        .iter()
+        .take_while(|color| color[3] != u8::MAX)
+        .count();
+    Reduced {
+        color: ColorType::Indexed,
+        depth: bit_depth(depth),
+        palette: Some(
+            colors
+                .iter()
+                .flat_map(|color| &color[..3])
+                .copied()
+                .collect(),
+        ),
+        trns: (translucent > 0)
+            .then(|| colors[..translucent].iter().map(|color| color[3]).collect()),
+        data: pack(indices, depth, width),
+    }
+}
+
+/// Bits per index for a palette of `len` colours.
+fn index_depth(len: usize) -> u8 {
+    match len {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+/// The fewest bits per sample that represent every gray level exactly:
+/// a 1-bit image only holds 0 and 255, a 2-bit one multiples of 85, and a
+/// 4-bit one multiples of 17.
+fn gray_depth(levels: impl Iterator<Item = u8>) -> u8 {
+    let mut depth = 1;
+    for level in levels {
+        while depth < 8 && level % (255 / ((1u16 << depth) - 1) as u8) != 0 {
+            depth *= 2;
+        }
+        if depth == 8 {
+            break;
+        }
+    }
+    depth
+}
+
+fn bit_depth(depth: u8) -> BitDepth {
+    match depth {
+        1 => BitDepth::One,
+        2 => BitDepth::Two,
+        4 => BitDepth::Four,
+        _ => BitDepth::Eight,
+    }
+}
+
+/// Packs `values` of `depth` bits into rows `width` values long, most
+/// significant bits first, each row starting on a new byte.
+fn pack(values: &[u8], depth: u8, width: usize) -> Vec<u8> {
+    if depth == 8 {
+        return values.to_vec();
+    }
+    let per_byte = usize::from(8 / depth);
+    let mut data = Vec::with_capacity(values.len() / per_byte + values.len() / width.max(1) + 1);
+    for row in values.chunks(width.max(1)) {
+        for group in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (i, &value) in group.iter().enumerate() {
+                byte |= value << (8 - depth as usize * (i + 1));
+            }
+            data.push(byte);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, ImageBuffer, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    /// Encodes `img` and checks it decodes to the same pixels. Returns the
+    /// PNG's colour type and bit depth.
+    fn round_trip(img: &DynamicImage, rgb_profile: bool) -> (ColorType, BitDepth) {
+        let bytes = encode(img, &PngOptions::default(), rgb_profile).unwrap();
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(decoded.to_rgba16(), img.to_rgba16());
+        let reader = ::png::Decoder::new(Cursor::new(&bytes))
+            .read_info()
+            .unwrap();
+        let info = reader.info();
+        (info.color_type, info.bit_depth)
+    }
+
+    #[test]
+    fn few_colours_become_a_palette() {
+        let colours = [Rgb([200, 30, 30]), Rgb([30, 200, 30]), Rgb([30, 30, 200])];
+        let img = RgbImage::from_fn(13, 7, |x, y| colours[((x + y) % 3) as usize]);
+        assert_eq!(
+            round_trip(&DynamicImage::ImageRgb8(img), false),
+            (ColorType::Indexed, BitDepth::Two)
+        );
+
+        // Translucent colours are kept through the tRNS chunk.
+        let img = RgbaImage::from_fn(9, 9, |x, _| {
+            Rgba([255, 0, 0, [0, 128, 255][x as usize % 3]])
+        });
+        assert_eq!(
+            round_trip(&DynamicImage::ImageRgba8(img), false),
+            (ColorType::Indexed, BitDepth::Two)
+        );
+    }
+
+    #[test]
+    fn many_colours_stay_true_colour() {
+        let img = RgbImage::from_fn(40, 40, |x, y| Rgb([x as u8 * 6, y as u8 * 6, 77]));
+        assert_eq!(
+            round_trip(&DynamicImage::ImageRgb8(img), false),
+            (ColorType::Rgb, BitDepth::Eight)
+        );
+        let img = RgbaImage::from_fn(40, 40, |x, y| Rgba([x as u8 * 6, y as u8 * 6, 77, 100]));
+        assert_eq!(
+            round_trip(&DynamicImage::ImageRgba8(img), false),
+            (ColorType::Rgba, BitDepth::Eight)
+        );
+    }
+
+    #[test]
+    fn gray_levels_get_the_fewest_bits() {
+        let bilevel =
+            GrayImage::from_fn(11, 5, |x, y| Luma([if (x + y) % 2 == 0 { 0 } else { 255 }]));
+        assert_eq!(
+            round_trip(&DynamicImage::ImageLuma8(bilevel), false),
+            (ColorType::Grayscale, BitDepth::One)
+        );
+        // Sixteen multiples of 17 fit in 4 bits, which beats a 16-colour
+        // palette.
+        let levels = GrayImage::from_fn(16, 3, |x, _| Luma([x as u8 * 17]));
+        assert_eq!(
+            round_trip(&DynamicImage::ImageLuma8(levels), false),
+            (ColorType::Grayscale, BitDepth::Four)
+        );
+        // Three arbitrary levels pack tighter as a palette than as 8 bits.
+        let odd = GrayImage::from_fn(9, 3, |x, _| Luma([[3, 100, 201][x as usize % 3]]));
+        assert_eq!(
+            round_trip(&DynamicImage::ImageLuma8(odd), false),
+            (ColorType::Indexed, BitDepth::Two)
+        );
+    }
+
+    #[test]
+    fn gray_with_an_rgb_profile_stays_rgb() {
+        let gray = RgbImage::from_fn(40, 40, |x, y| {
+            let v = (x * 6 + y) as u8;
+            Rgb([v, v, v])
+        });
+        let (color, _) = round_trip(&DynamicImage::ImageRgb8(gray), true);
+        assert_ne!(color, ColorType::Grayscale);
+    }
+
+    #[test]
+    fn sixteen_bit_images() {
+        // 8-bit values widened to 16 bits lose nothing when narrowed again.
+        let widened: ImageBuffer<Rgb<u16>, Vec<u16>> = ImageBuffer::from_fn(30, 30, |x, y| {
+            Rgb([x as u16 * 257 * 8, y as u16 * 257 * 8, 0])
+        });
+        assert_eq!(
+            round_trip(&DynamicImage::ImageRgb16(widened), false),
+            (ColorType::Rgb, BitDepth::Eight)
+        );
+        let deep: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_fn(30, 30, |x, y| {
+            Rgba([x as u16 * 1000 + 1, y as u16, 7, 65535])
+        });
+        assert_eq!(
+            round_trip(&DynamicImage::ImageRgba16(deep), false),
+            (ColorType::Rgb, BitDepth::Sixteen)
+        );
+    }
+
+    #[test]
+    fn packs_rows_from_the_high_bits() {
+        assert_eq!(
+            pack(&[1, 0, 1, 1, 0, 0, 0, 1, 1], 1, 9),
+            [0b1011_0001, 0b1000_0000]
+        );
+        // Each row starts on a new byte.
+        assert_eq!(pack(&[3, 2, 1, 0, 1, 2], 2, 3), [0b1110_0100, 0b0001_1000]);
+        assert_eq!(pack(&[0xA, 0x5, 0xF], 4, 3), [0xA5, 0xF0]);
+        assert_eq!(pack(&[7, 8, 9], 8, 3), [7, 8, 9]);
+        assert_eq!(
+            (
+                gray_depth([0, 255].into_iter()),
+                gray_depth([85].into_iter())
+            ),
+            (1, 2)
+        );
+        assert_eq!(gray_depth([17, 34].into_iter()), 4);
+        assert_eq!(gray_depth([1].into_iter()), 8);
+    }
+}
@@ -0,0 +1,535 @@
+//! Phase 2 of the pipeline: AI renaming.
+//!
+//! Native replacement for `rename_image` in `seo_image_processor.sh`. Each
+//! converted file is shown to the Ollama vision model, which answers with
+//! keywords, a title, alt text and a caption as JSON. The keywords become the
+//! new file name; the rest is kept with the file's result for the exports.
+//! Answers are stored in the [`DescriptionCache`] under the hash of the
+//! original, so converting the same image again never asks the model twice.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use serde::Deserialize;
+
+use crate::cache::{self, CachedDescription, DescriptionCache};
+use crate::convert::{self, ConvertError};
+use crate::events::{JobEvent, Phase};
+use crate::jobs::JobControl;
+use crate::journal::{FileStage, Journal};
+use crate::metadata::{self, MetadataError};
+use crate::ollama::{OllamaClient, OllamaConfig, OllamaError};
+use crate::pipeline::{ConversionSummary, JobSpec, OutputVariant};
+
+const PROMPT: &str = r#"Describe this image for a web page. Respond with a JSON object with exactly these fields:
+"keywords": an array of exactly 10 SEO-optimized keywords in lowercase,
+"title": a short title of at most 8 words,
+"alt": one sentence describing the image for screen reader users, not starting with "image of" or "picture of",
+"caption": one or two sentences suitable as a caption below the image."#;
+
+/// Upper bounds for the text fields. Anything longer means the model
+/// ignored the instructions, and the answer is rejected.
+const MAX_TITLE_LEN: usize = 120;
+const MAX_ALT_LEN: usize = 250;
+const MAX_CAPTION_LEN: usize = 500;
+
+/// Longest file name, without extension, that a description is cut down to
+/// (`seo.max_filename_length` in `config.yaml`).
+const MAX_SLUG_LEN: usize = 120;
+
+/// Longest side of the preview sent to the model. Keywords do not need full
+/// resolution, and a small image keeps requests fast.
+const PREVIEW_SIZE: u32 = 1024;
+
+/// Ollama generates one answer at a time by default, so more parallel
+/// requests would only queue up and eat into each other's timeout.
+const WORKERS: usize = 2;
+
+#[derive(Debug, thiserror::Error)]
+enum RenameError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Convert(#[from] ConvertError),
+    #[error(transparent)]
+    Ai(#[from] OllamaError),
+    #[error("could not write metadata: {0}")]
+    Metadata(#[from] MetadataError),
+}
+
+/// Renames the outputs of every file the journal lists as backed up but not
+/// yet renamed, adding the results to `summary`.
+///
+/// If Ollama is unreachable or the model is not installed, this is reported
+/// once and only files with a cached description are renamed; the rest keep
+/// their original names.
+pub fn run(
+    spec: &JobSpec,
+    journal: &Journal,
+    cache: &mut DescriptionCache,
+    control: &JobControl,
+    on_event: &(dyn Fn(JobEvent) + Sync),
+    summary: &mut ConversionSummary,
+) -> io::Result<()> {
+    finish_interrupted(journal)?;
+    let mut files: Vec<(PathBuf, Vec<OutputVariant>)> = journal
+        .stages()
+        .into_iter()
+        .filter_map(|(src, stage)| match stage {
+            FileStage::BackedUp { outputs } => Some((src, outputs)),
+            _ => None,
+        })
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    on_event(JobEvent::PhaseChanged {
+        phase: Phase::Rename,
+    });
+    let client = match connect(&spec.ollama) {
+        Ok(client) => Some(client),
+        Err(error) => {
+            on_event(JobEvent::AiUnavailable { error });
+            None
+        }
+    };
+
+    let cache_lock = Mutex::new(std::mem::take(cache));
+    let summary_lock = Mutex::new(std::mem::take(summary));
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..WORKERS.min(files.len()) {
+            scope.spawn(|| {
+                while control.should_continue() {
+                    let Some((src, outputs)) = files.get(next.fetch_add(1, Ordering::Relaxed))
+                    else {
+                        break;
+                    };
+                    let result =
+                        rename_one(spec, src, outputs, journal, &cache_lock, client.as_ref());
+                    let event = match result {
+                        Ok(None) => continue,
+                        Ok(Some(to)) => {
+                            summary_lock.lock().unwrap().renamed += 1;
+                            JobEvent::FileRenamed {
+                                from: outputs.iter().map(|output| output.path.clone()).collect(),
+                                to: to.into_iter().map(|output| output.path).collect(),
+                            }
+                        }
+                        Err(RenameError::Ai(error)) => {
+                            summary_lock.lock().unwrap().rename_failed += 1;
+                            JobEvent::RenameFailed {
+                                path: src.clone(),
+                                error,
+                            }
+                        }
+                        Err(
+                            e @ (RenameError::Io(_)
+                            | RenameError::Convert(_)
+                            | RenameError::Metadata(_)),
+                        ) => {
+                            summary_lock.lock().unwrap().rename_failed += 1;
+                            JobEvent::FileFailed {
+                                path: src.clone(),
+                                reason: e.to_string(),
+                            }
+                        }
+                    };
+                    on_event(event);
+                }
+            });
+        }
+    });
+
+    *cache = cache_lock.into_inner().unwrap();
+    *summary = summary_lock.into_inner().unwrap();
+    cache.save()
+}
+
+/// Checks that Ollama is up and has the configured model before any file
+/// is sent to it.
+fn connect(config: &OllamaConfig) -> Result<OllamaClient, OllamaError> {
+    let client = OllamaClient::new(config.clone());
+    let installed = client
+        .tags()?
+        .iter()
+        .any(|model| model.is_named(&config.model));
+    if !installed {
+        return Err(OllamaError::ModelNotFound {
+            model: config.model.clone(),
+        });
+    }
+    Ok(client)
+}
+
+/// Moves the outputs of `src` to its AI-generated name. Returns the new
+/// paths, or `None` if there is no cached description and Ollama is
+/// unavailable.
+fn rename_one(
+    spec: &JobSpec,
+    src: &Path,
+    outputs: &[OutputVariant],
+    journal: &Journal,
+    cache: &Mutex<DescriptionCache>,
+    client: Option<&OllamaClient>,
+) -> Result<Option<Vec<OutputVariant>>, RenameError> {
+    let rel = src.strip_prefix(&spec.root).unwrap_or(src);
+    let original = spec.backup_dir.join(rel);
+    let hash = cache::content_hash(&original).ok();
+    let cached = hash
+        .as_ref()
+        .and_then(|hash| cache.lock().unwrap().get(hash).cloned());
+
+    let description = match (cached, client) {
+        (Some(cached), _) => cached,
+        (None, None) => return Ok(None),
+        (None, Some(client)) => {
+            let preview = convert::preview_jpeg(&original, PREVIEW_SIZE)?;
+            let description = validate(client.generate_json(PROMPT, &preview)?)?;
+            if let Some(hash) = hash {
+                cache.lock().unwrap().insert(hash, description.clone());
+            }
+            description
+        }
+    };
+    let slug = &description.slug;
+
+    // Embedded before the move: if the app dies in between, the resumed
+    // run embeds the same blocks again and ends up with the same files.
+    let mut outputs = outputs.to_vec();
+    if spec.options.metadata.embed_description {
+        for output in &mut outputs {
+            output.bytes = metadata::describe_file(&output.path, &description)?;
+        }
+    }
+
+    let Some(first) = outputs.first() else {
+        return Ok(None);
+    };
+    let renamed = if first.path.file_stem().and_then(|stem| stem.to_str()) == Some(slug.as_str()) {
+        outputs.clone()
+    } else {
+        let suffixes: Vec<String> = outputs.iter().map(OutputVariant::suffix).collect();
+        let suffixes: Vec<&str> = suffixes.iter().map(String::as_str).collect();
+        let dir = first.path.parent().unwrap_or(&spec.root);
+        let paths = convert::claim_names(dir, slug, &suffixes, None)?;
+        let renamed: Vec<OutputVariant> = outputs
+            .iter()
+            .zip(&paths)
+            .map(|(output, path)| OutputVariant {
+                path: path.clone(),
+                ..output.clone()
+            })
+            .collect();
+        // The claimed names are journaled before anything is moved, so a
+        // run that dies halfway through the moves can finish them.
+        let intent = FileStage::Renaming {
+            outputs: outputs.clone(),
+            renamed: renamed.clone(),
+            description: Some(description.clone()),
+        };
+        if let Err(e) = journal.record(src, intent) {
+            for path in &paths {
+                let _ = fs::remove_file(path);
+            }
+            return Err(e.into());
+        }
+        move_outputs(&outputs, &renamed)?;
+        renamed
+    };
+    journal.record(
+        src,
+        FileStage::Renamed {
+            outputs: renamed.clone(),
+            description: Some(description.clone()),
+        },
+    )?;
+    Ok(Some(renamed))
+}
+
+/// Finishes the renames a previous run journaled but may not have completed,
+/// recording the files as renamed.
+pub fn finish_interrupted(journal: &Journal) -> io::Result<()> {
+    for (src, stage) in journal.stages() {
+        if let FileStage::Renaming {
+            outputs,
+            renamed,
+            description,
+        } = stage
+        {
+            move_outputs(&outputs, &renamed)?;
+            journal.record(
+                &src,
+                FileStage::Renamed {
+                    outputs: renamed,
+                    description,
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves each of `outputs` onto the claimed name at the same index in
+/// `renamed`. Outputs that are already gone were moved by an earlier run.
+fn move_outputs(outputs: &[OutputVariant], renamed: &[OutputVariant]) -> io::Result<()> {
+    for (output, target) in outputs.iter().zip(renamed) {
+        match fs::rename(&output.path, &target.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// The model's answer as requested by [`PROMPT`].
+#[derive(Deserialize)]
+struct Answer {
+    keywords: Keywords,
+    title: String,
+    alt: String,
+    caption: String,
+}
+
+/// Models occasionally answer with a single hyphenated string, as the old
+/// prompt asked for, instead of an array.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Keywords {
+    List(Vec<String>),
+    Joined(String),
+}
+
+/// Checks the model's answer and turns it into a description: every field
+/// must be present, non-empty and of reasonable length, and the keywords
+/// must leave something to name the file after.
+fn validate(answer: Answer) -> Result<CachedDescription, OllamaError> {
+    let keywords: Vec<String> = match answer.keywords {
+        Keywords::List(list) => list,
+        Keywords::Joined(joined) => joined.split(['-', ',']).map(str::to_string).collect(),
+    }
+    .iter()
+    .map(|keyword| normalize(keyword).to_lowercase())
+    .filter(|keyword| !keyword.is_empty())
+    .collect();
+    let slug = seo_slug(&keywords.join("-"));
+    if slug.is_empty() {
+        return Err(invalid("no usable keywords"));
+    }
+    Ok(CachedDescription {
+        slug,
+        keywords,
+        title: text_field("title", &answer.title, MAX_TITLE_LEN)?,
+        alt: text_field("alt", &answer.alt, MAX_ALT_LEN)?,
+        caption: text_field("caption", &answer.caption, MAX_CAPTION_LEN)?,
+    })
+}
+
+fn text_field(name: &str, value: &str, max_len: usize) -> Result<String, OllamaError> {
+    let value = normalize(value);
+    match value.chars().count() {
+        0 => Err(invalid(&format!("'{name}' is empty"))),
+        len if len > max_len => Err(invalid(&format!(
+            "'{name}' is {len} characters long, more than {max_len}"
+        ))),
+        _ => Ok(value),
+    }
+}
+
+/// Trims `text` and collapses runs of whitespace, including newlines, into
+/// single spaces.
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn invalid(message: &str) -> OllamaError {
+    OllamaError::InvalidResponse {
+        message: message.to_string(),
+    }
+}
+
+/// Turns the model's answer into a file name: lowercase ASCII letters and
+/// digits separated by single hyphens, like the `tr`/`sed` chain in the
+/// shell script.
+fn seo_slug(answer: &str) -> String {
+    let mut slug = String::new();
+    for c in answer.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_LEN);
+    slug.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(keywords: &str, title: &str, alt: &str, caption: &str) -> String {
+        serde_json::json!({
+            "keywords": serde_json::from_str::<serde_json::Value>(keywords).unwrap(),
+            "title": title,
+            "alt": alt,
+            "caption": caption,
+        })
+        .to_string()
+    }
+
+    fn parse(json: &str) -> Result<CachedDescription, OllamaError> {
+        validate(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn accepts_keyword_lists_and_joined_strings() {
+        let list = answer(
+            r#"["Blue Sky", " clouds ", "", "Sea"]"#,
+            "Sky",
+            "A sky",
+            "A sky.",
+        );
+        let joined = answer(r#""blue-sky, clouds-sea""#, "Sky", "A sky", "A sky.");
+        let list = parse(&list).unwrap();
+        assert_eq!(list.keywords, ["blue sky", "clouds", "sea"]);
+        assert_eq!(list.slug, "blue-sky-clouds-sea");
+        let joined = parse(&joined).unwrap();
+        assert_eq!(joined.keywords, ["blue", "sky", "clouds", "sea"]);
+        assert_eq!(joined.slug, "blue-sky-clouds-sea");
+    }
+
+    #[test]
+    fn normalizes_text_fields() {
+        let description = parse(&answer(
+            r#"["sky"]"#,
+            "  Blue\n sky ",
+            "A clear\t\tsky",
+            "One.\n\nTwo.",
+        ))
+        .unwrap();
+        assert_eq!(description.title, "Blue sky");
+        assert_eq!(description.alt, "A clear sky");
+        assert_eq!(description.caption, "One. Two.");
+    }
+
+    #[test]
+    fn caps_field_lengths() {
+        let at = |len: usize| "é".repeat(len);
+        let fits = answer(
+            r#"["sky"]"#,
+            &at(MAX_TITLE_LEN),
+            &at(MAX_ALT_LEN),
+            &at(MAX_CAPTION_LEN),
+        );
+        assert!(parse(&fits).is_ok());
+        for (title, alt, caption) in [
+            (MAX_TITLE_LEN + 1, 1, 1),
+            (1, MAX_ALT_LEN + 1, 1),
+            (1, 1, MAX_CAPTION_LEN + 1),
+        ] {
+            let long = answer(r#"["sky"]"#, &at(title), &at(alt), &at(caption));
+            assert!(
+                matches!(parse(&long), Err(OllamaError::InvalidResponse { .. })),
+                "{title} {alt} {caption}"
+            );
+        }
+        let empty = answer(r#"["sky"]"#, " ", "A sky", "A sky.");
+        assert!(parse(&empty).is_err());
+    }
+
+    #[test]
+    fn rejects_answers_without_usable_keywords() {
+        assert!(parse(&answer(r#"["日本", "!!"]"#, "Sky", "A sky", "A sky.")).is_err());
+        assert!(parse(&answer("[]", "Sky", "A sky", "A sky.")).is_err());
+        let missing = r#"{"keywords": ["sky"], "title": "Sky", "alt": "A sky"}"#;
+        assert!(serde_json::from_str::<Answer>(missing).is_err());
+    }
+
+    #[test]
+    fn slugs_are_ascii_and_capped() {
+        assert_eq!(seo_slug("  Café au lait, 2 cups!  "), "caf-au-lait-2-cups");
+        assert_eq!(seo_slug("--a--b--"), "a-b");
+        let long = seo_slug(&"word ".repeat(100));
+        assert!(long.len() <= MAX_SLUG_LEN);
+        assert!(!long.ends_with('-'));
+        assert_eq!(long.len(), 119);
+        assert_eq!(seo_slug(&"a".repeat(200)).len(), MAX_SLUG_LEN);
+    }
+
+    #[test]
+    fn finishes_interrupted_renames() {
+        let dir = std::env::temp_dir().join(format!("rename-{}-resume", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let variant = |name: &str, format| OutputVariant {
+            format,
+            page: None,
+            path: dir.join(name),
+            bytes: 4,
+            width: 8,
+            height: 8,
+            ladder: None,
+        };
+        let outputs = vec![
+            variant("photo.webp", convert::OutputFormat::Webp),
+            variant("photo.jpg", convert::OutputFormat::Jpeg),
+        ];
+        let renamed = vec![
+            variant("blue-sky.webp", convert::OutputFormat::Webp),
+            variant("blue-sky.jpg", convert::OutputFormat::Jpeg),
+        ];
+        // The run died after moving the WebP onto its claimed name.
+        fs::write(dir.join("blue-sky.webp"), "webp").unwrap();
+        fs::write(dir.join("blue-sky.jpg"), "").unwrap();
+        fs::write(dir.join("photo.jpg"), "jpeg").unwrap();
+
+        let spec = JobSpec::new(
+            dir.clone(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let journal = Journal::create(
+            &dir.join("jobs"),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            &spec,
+        )
+        .unwrap();
+        let src = dir.join("photo.png");
+        journal
+            .record(
+                &src,
+                FileStage::Renaming {
+                    outputs,
+                    renamed: renamed.clone(),
+                    description: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            Journal::outputs_in(&dir.join("jobs")).unwrap().len(),
+            4,
+            "both the old and the claimed names count as generated"
+        );
+
+        finish_interrupted(&journal).unwrap();
+        assert!(!dir.join("photo.jpg").exists());
+        assert_eq!(fs::read(dir.join("blue-sky.webp")).unwrap(), b"webp");
+        assert_eq!(fs::read(dir.join("blue-sky.jpg")).unwrap(), b"jpeg");
+        assert_eq!(
+            journal.stages()[&src],
+            FileStage::Renamed {
+                outputs: renamed,
+                description: None,
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+//! Undoing a job.
+//!
+//! Walks a job's journal, moves each original from the backup folder back to
+//! its relative path and deletes the files generated from it. Nothing is ever
+//! overwritten: if something now occupies an original's path, that file is
+//! left alone and reported as a conflict.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::journal::{FileStage, Journal};
+use crate::pipeline::{self, JobSpec, OutputVariant};
+use crate::snippets;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RollbackConflict {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RollbackReport {
+    /// Originals moved back to where they came from.
+    pub restored: Vec<PathBuf>,
+    /// Generated files that were deleted.
+    pub removed: Vec<PathBuf>,
+    pub conflicts: Vec<RollbackConflict>,
+}
+
+impl RollbackReport {
+    fn conflict(&mut self, path: &Path, reason: impl Into<String>) {
+        self.conflicts.push(RollbackConflict {
+            path: path.to_path_buf(),
+            reason: reason.into(),
+        });
+    }
+}
+
+/// Reverses every file recorded in `journal`. If no conflicts were found,
+/// the journal is marked rolled back and the emptied backup folder removed.
+pub fn rollback(spec: &JobSpec, journal: &Journal) -> io::Result<RollbackReport> {
+    pipeline::undo_interrupted_commits(journal)?;
+    let mut report = RollbackReport::default();
+    let mut sources: Vec<_> = journal.stages().into_iter().collect();
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (source, stage) in sources {
+        if matches!(stage, FileStage::Failed { .. }) {
+            continue;
+        }
+        if !restore_original(spec, &source, stage.outputs(), &mut report) {
+            continue;
+        }
+        let fragment = snippets::fragment_path(stage.outputs());
+        let generated = stage.generated().filter(|path| *path != source);
+        for output in generated.chain(fragment.as_deref()) {
+            remove_generated(output, &mut report);
+        }
+    }
+
+    if report.conflicts.is_empty() {
+        remove_generated(&spec.snippets_file(), &mut report);
+        remove_empty_dirs(&spec.backup_dir)?;
+        journal.mark_rolled_back()?;
+    }
+    Ok(report)
+}
+
+/// Deletes a file the job generated, if it still exists. Returns false if
+/// it could not be deleted.
+fn remove_generated(path: &Path, report: &mut RollbackReport) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => report.removed.push(path.to_path_buf()),
+        // Already gone, e.g. from an earlier rollback that hit conflicts,
+        // or never written, like the snippets of a job without an export.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            report.conflict(path, e.to_string());
+            return false;
+        }
+    }
+    true
+}
+
+/// Puts `source` back from the backup folder. An output of the source that
+/// took its name, like the JPEG of a JPEG, is deleted first. Returns false
+/// if the original could not be restored, in which case the generated
+/// files must be kept.
+fn restore_original(
+    spec: &JobSpec,
+    source: &Path,
+    outputs: &[OutputVariant],
+    report: &mut RollbackReport,
+) -> bool {
+    let rel = source.strip_prefix(&spec.root).unwrap_or(source);
+    let backup = spec.backup_dir.join(rel);
+    if !backup.exists() {
+        if source.exists() {
+            // Never moved, e.g. the job stopped between converting and
+            // backing up this file.
+            return true;
+        }
+        report.conflict(&backup, "original is missing from the backup folder");
+        return false;
+    }
+    if source.exists() {
+        if !outputs.iter().any(|output| output.path == source) {
+            report.conflict(source, "another file now exists at the original path");
+            return false;
+        }
+        if !remove_generated(source, report) {
+            return false;
+        }
+    }
+    let result = source
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::rename(&backup, source));
+    match result {
+        Ok(()) => {
+            report.restored.push(source.to_path_buf());
+            true
+        }
+        Err(e) => {
+            report.conflict(source, e.to_string());
+            false
+        }
+    }
+}
+
+/// Removes `dir` and any subdirectories that are now empty. Directories that
+/// still hold files are left in place.
+fn remove_empty_dirs(dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
+        }
+    }
+    match fs::remove_dir(dir) {
+        Ok(()) => Ok(()),
+        Err(_) if fs::read_dir(dir)?.next().is_some() => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::OutputFormat;
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rollback-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn output(path: PathBuf, format: OutputFormat) -> OutputVariant {
+        OutputVariant {
+            format,
+            page: None,
+            path,
+            bytes: 4,
+            width: 8,
+            height: 8,
+            ladder: None,
+        }
+    }
+
+    /// A job on `root` that converted `name` to `outputs`, with the
+    /// original in the backup folder.
+    fn converted(root: &Path, name: &str, outputs: &[(&str, OutputFormat)]) -> (JobSpec, Journal) {
+        let spec = JobSpec::new(
+            root.to_path_buf(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let journal = Journal::create(&root.join("jobs"), ID, &spec).unwrap();
+        fs::create_dir_all(&spec.backup_dir).unwrap();
+        fs::write(spec.backup_dir.join(name), "original").unwrap();
+        let outputs = outputs
+            .iter()
+            .map(|&(output_name, format)| {
+                fs::write(root.join(output_name), format.extension()).unwrap();
+                output(root.join(output_name), format)
+            })
+            .collect();
+        journal
+            .record(&root.join(name), FileStage::BackedUp { outputs })
+            .unwrap();
+        (spec, journal)
+    }
+
+    #[test]
+    fn restores_over_an_output_with_the_originals_name() {
+        let root = test_dir("same-format");
+        let (spec, journal) = converted(
+            &root,
+            "photo.jpg",
+            &[
+                ("photo.jpg", OutputFormat::Jpeg),
+                ("photo.webp", OutputFormat::Webp),
+            ],
+        );
+        let report = rollback(&spec, &journal).unwrap();
+        assert!(report.conflicts.is_empty(), "{:?}", report.conflicts);
+        assert_eq!(report.restored, [root.join("photo.jpg")]);
+        assert_eq!(
+            report.removed,
+            [root.join("photo.jpg"), root.join("photo.webp")]
+        );
+        assert_eq!(fs::read(root.join("photo.jpg")).unwrap(), b"original");
+        assert!(!root.join("photo.webp").exists());
+        assert!(!spec.backup_dir.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keeps_files_that_took_the_originals_place() {
+        let root = test_dir("conflict");
+        let (spec, journal) = converted(&root, "photo.png", &[("photo.webp", OutputFormat::Webp)]);
+        fs::write(root.join("photo.png"), "someone else's").unwrap();
+        let report = rollback(&spec, &journal).unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].path, root.join("photo.png"));
+        assert!(report.restored.is_empty() && report.removed.is_empty());
+        assert_eq!(fs::read(root.join("photo.png")).unwrap(), b"someone else's");
+        assert!(root.join("photo.webp").exists());
+        assert!(spec.backup_dir.join("photo.png").exists());
+        // Not marked rolled back, so it can be tried again.
+        drop(journal);
+        assert!(Journal::open_for_rollback(&root.join("jobs"), ID).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+//! HTML export.
+//!
+//! Writes ready-to-paste `<picture>` markup for every converted image: one
+//! `<source>` per modern format, the most widely supported format as the
+//! `<img>` fallback, `srcset` built from the width ladder and intrinsic
+//! `width`/`height` so the browser can reserve space before the image
+//! loads. Each image gets a fragment next to its outputs, and the whole job
+//! gets one combined file in the target directory.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CachedDescription;
+use crate::convert::OutputFormat;
+use crate::pipeline::{FileResult, JobSpec, OutputVariant};
+
+/// Appended to an image's base name for its fragment, e.g.
+/// `blue-sky.picture.html`. The extra suffix keeps fragments from ever
+/// replacing an HTML page that shares the image's name.
+const FRAGMENT_SUFFIX: &str = ".picture.html";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SnippetOptions {
+    pub enabled: bool,
+    /// Value of the `sizes` attribute.
+    pub sizes: String,
+    /// Prefix for image URLs, e.g. `/images/`. Paths are relative to the
+    /// target directory; without a prefix they are used as-is.
+    pub base_url: String,
+    /// Format of the `<img>` fallback, e.g. JPEG for newsletters. Defaults
+    /// to the most widely supported format written.
+    pub fallback: Option<OutputFormat>,
+}
+
+impl Default for SnippetOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sizes: "100vw".to_string(),
+            base_url: String::new(),
+            fallback: None,
+        }
+    }
+}
+
+/// Files written by [`export`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnippetExport {
+    pub combined: PathBuf,
+    pub fragments: Vec<PathBuf>,
+}
+
+/// Writes a fragment for every file in `files` and the combined file for
+/// the job. Existing snippets from an earlier attempt at the same job are
+/// replaced.
+pub fn export(spec: &JobSpec, files: &[FileResult]) -> io::Result<SnippetExport> {
+    let mut combined = String::new();
+    let mut fragments = Vec::new();
+    for file in files {
+        let (Some(path), Some(html)) = (fragment_path(&file.outputs), picture(spec, file)) else {
+            continue;
+        };
+        fs::write(&path, &html)?;
+        let rel = file.source.strip_prefix(&spec.root).unwrap_or(&file.source);
+        let _ = writeln!(
+            combined,
+            "<!-- {} -->\n{html}",
+            escape(&rel.to_string_lossy())
+        );
+        fragments.push(path);
+    }
+    let path = spec.snippets_file();
+    fs::write(&path, combined)?;
+    Ok(SnippetExport {
+        combined: path,
+        fragments,
+    })
+}
+
+/// Where the fragment for a file with these outputs is written: next to
+/// the full-size outputs, sharing their base name.
+pub fn fragment_path(outputs: &[OutputVariant]) -> Option<PathBuf> {
+    let full_size = outputs
+        .iter()
+        .find(|output| output.page.is_none() && output.ladder.is_none())?;
+    let stem = full_size.path.file_stem()?.to_string_lossy();
+    Some(
+        full_size
+            .path
+            .with_file_name(format!("{stem}{FRAGMENT_SUFFIX}")),
+    )
+}
+
+/// Order of `<source>` elements: the smallest files first, as browsers
+/// pick the first type they support.
+fn preference(format: OutputFormat) -> u8 {
+    match format {
+        OutputFormat::Avif => 0,
+        OutputFormat::Webp => 1,
+        OutputFormat::Jpeg => 2,
+        OutputFormat::Png => 3,
+        // Never listed with other formats; see `picture`.
+        OutputFormat::Svg => 4,
+    }
+}
+
+/// The `<picture>` element for one source, or `None` if it has no outputs.
+/// Only the first page of a multi-page TIFF is shown, and only the vector
+/// of an SVG: its raster copies are for social previews.
+fn picture(spec: &JobSpec, file: &FileResult) -> Option<String> {
+    let mut formats: Vec<OutputFormat> = Vec::new();
+    for output in &file.outputs {
+        if !formats.contains(&output.format) {
+            formats.push(output.format);
+        }
+    }
+    if formats.contains(&OutputFormat::Svg) {
+        formats = vec![OutputFormat::Svg];
+    }
+    formats.sort_by_key(|&format| preference(format));
+    let fallback = match spec.snippets.fallback {
+        Some(fallback) if formats.contains(&fallback) => {
+            formats.retain(|&format| format != fallback);
+            fallback
+        }
+        _ => formats.pop()?,
+    };
+    let full_size = file.outputs.iter().find(|output| {
+        output.format == fallback && output.page.is_none() && output.ladder.is_none()
+    })?;
+    let sizes = escape(&spec.snippets.sizes);
+    let alt = file
+        .description
+        .as_ref()
+        .map(CachedDescription::alt_text)
+        .unwrap_or_default();
+
+    let mut html = String::from("<picture>\n");
+    for format in formats {
+        let _ = writeln!(
+            html,
+            "  <source type=\"{}\" srcset=\"{}\" sizes=\"{sizes}\">",
+            format.mime_type(),
+            srcset(spec, &file.outputs, format)
+        );
+    }
+    let _ = writeln!(
+        html,
+        "  <img src=\"{}\" srcset=\"{}\" sizes=\"{sizes}\" width=\"{}\" height=\"{}\" alt=\"{}\" loading=\"lazy\" decoding=\"async\">",
+        url(spec, &full_size.path),
+        srcset(spec, &file.outputs, fallback),
+        full_size.width,
+        full_size.height,
+        escape(&alt)
+    );
+    html.push_str("</picture>\n");
+    Some(html)
+}
+
+/// `srcset` candidates for every size of `format`, narrowest first.
+fn srcset(spec: &JobSpec, outputs: &[OutputVariant], format: OutputFormat) -> String {
+    let mut variants: Vec<&OutputVariant> = outputs
+        .iter()
+        .filter(|output| output.format == format && output.page.is_none())
+        .collect();
+    variants.sort_by_key(|output| output.width);
+    variants
+        .iter()
+        .map(|output| format!("{} {}w", url(spec, &output.path), output.width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// URL of `path` relative to the target directory, percent-encoded and
+/// escaped for use in an attribute.
+fn url(spec: &JobSpec, path: &Path) -> String {
+    let rel = path.strip_prefix(&spec.root).unwrap_or(path);
+    let mut url = spec.snippets.base_url.clone();
+    if !url.is_empty() && !url.ends_with('/') {
+        url.push('/');
+    }
+    let segments: Vec<String> = rel
+        .components()
+        .map(|segment| percent_encode(&segment.as_os_str().to_string_lossy()))
+        .collect();
+    url.push_str(&segments.join("/"));
+    escape(&url)
+}
+
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(snippets: SnippetOptions) -> JobSpec {
+        JobSpec {
+            snippets,
+            ..JobSpec::new(
+                PathBuf::from("/site"),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            )
+        }
+    }
+
+    fn variant(format: OutputFormat, name: &str, width: u32, ladder: Option<u32>) -> OutputVariant {
+        OutputVariant {
+            format,
+            page: None,
+            path: Path::new("/site/img").join(name),
+            bytes: 1000,
+            width,
+            height: width / 2,
+            ladder,
+        }
+    }
+
+    fn file(outputs: Vec<OutputVariant>) -> FileResult {
+        FileResult {
+            source: PathBuf::from("/site/img/sky.jpg"),
+            outputs,
+            description: Some(CachedDescription {
+                slug: "blue-sky".into(),
+                keywords: Vec::new(),
+                title: String::new(),
+                alt: "A \"blue\" sky & sea".into(),
+                caption: String::new(),
+            }),
+        }
+    }
+
+    /// Outputs as the pipeline writes them: every format of the full size,
+    /// then every format of each ladder width.
+    fn outputs() -> Vec<OutputVariant> {
+        let mut outputs = Vec::new();
+        for (width, ladder) in [(1200, None), (320, Some(320)), (640, Some(640))] {
+            for (format, ext) in [
+                (OutputFormat::Jpeg, "jpg"),
+                (OutputFormat::Webp, "webp"),
+                (OutputFormat::Avif, "avif"),
+            ] {
+                let suffix = ladder.map(|w| format!("-{w}w")).unwrap_or_default();
+                outputs.push(variant(
+                    format,
+                    &format!("blue sky{suffix}.{ext}"),
+                    width,
+                    ladder,
+                ));
+            }
+        }
+        outputs
+    }
+
+    #[test]
+    fn lists_smallest_formats_first() {
+        let html = picture(&spec(SnippetOptions::default()), &file(outputs())).unwrap();
+        let lines: Vec<&str> = html.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "<picture>");
+        assert!(lines[1].starts_with("  <source type=\"image/avif\""));
+        assert!(lines[2].starts_with("  <source type=\"image/webp\""));
+        // JPEG is the most widely supported, so it is the fallback.
+        assert!(lines[3].starts_with("  <img src=\"img/blue%20sky.jpg\""));
+        assert!(lines[3].contains(" width=\"1200\" height=\"600\" "));
+        assert!(lines[3].contains(" alt=\"A &quot;blue&quot; sky &amp; sea\" "));
+        assert_eq!(lines[4], "</picture>");
+    }
+
+    #[test]
+    fn srcset_is_narrowest_first() {
+        let spec = spec(SnippetOptions::default());
+        assert_eq!(
+            srcset(&spec, &outputs(), OutputFormat::Webp),
+            "img/blue%20sky-320w.webp 320w, img/blue%20sky-640w.webp 640w, img/blue%20sky.webp 1200w"
+        );
+    }
+
+    #[test]
+    fn honours_the_chosen_fallback() {
+        let cdn = spec(SnippetOptions {
+            fallback: Some(OutputFormat::Webp),
+            base_url: "https://cdn.example/".into(),
+            sizes: "(min-width: 60em) 50vw, 100vw".into(),
+            ..SnippetOptions::default()
+        });
+        let html = picture(&cdn, &file(outputs())).unwrap();
+        let types: Vec<&str> = html
+            .lines()
+            .filter_map(|line| line.split("type=\"").nth(1))
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        assert_eq!(types, ["image/avif", "image/jpeg"]);
+        assert!(html.contains("<img src=\"https://cdn.example/img/blue%20sky.webp\""));
+        assert!(html.contains("sizes=\"(min-width: 60em) 50vw, 100vw\""));
+
+        // A fallback that was not written is ignored.
+        let png = spec(SnippetOptions {
+            fallback: Some(OutputFormat::Png),
+            ..SnippetOptions::default()
+        });
+        let html = picture(&png, &file(outputs())).unwrap();
+        assert!(html.contains("<img src=\"img/blue%20sky.jpg\""));
+    }
+
+    #[test]
+    fn shows_only_the_vector_of_an_svg() {
+        let outputs = vec![
+            variant(OutputFormat::Svg, "logo.svg", 300, None),
+            variant(OutputFormat::Png, "logo-1200w.png", 1200, Some(1200)),
+        ];
+        let html = picture(&spec(SnippetOptions::default()), &file(outputs)).unwrap();
+        assert!(!html.contains("<source"));
+        assert!(html.contains("<img src=\"img/logo.svg\" srcset=\"img/logo.svg 300w\""));
+        assert!(picture(&spec(SnippetOptions::default()), &file(Vec::new())).is_none());
+    }
+
+    #[test]
+    fn names_fragments_after_the_full_size_output() {
+        assert_eq!(
+            fragment_path(&outputs()),
+            Some(PathBuf::from("/site/img/blue sky.picture.html"))
+        );
+        assert_eq!(fragment_path(&outputs()[3..]), None);
+    }
+
+    #[test]
+    fn encodes_and_escapes() {
+        assert_eq!(percent_encode("Blue_sky-1.v2~"), "Blue_sky-1.v2~");
+        assert_eq!(percent_encode("a b&c#?"), "a%20b%26c%23%3F");
+        assert_eq!(percent_encode("café"), "caf%C3%A9");
+        assert_eq!(
+            escape(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
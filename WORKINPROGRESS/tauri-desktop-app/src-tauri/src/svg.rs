@@ -0,0 +1,895 @@
+//! SVG sources.
+//!
+//! Logos and icons sit next to photos in the asset folders. Vector art is
+//! already as sharp and usually as small as it gets, so instead of being
+//! re-encoded it is written back as SVG, minified if the job asks for it.
+//! Bitmaps are only rendered, with resvg, where one is needed: the WebP
+//! and PNG copies at `svg.widths` for social previews, and the copy shown
+//! to the vision model.
+//!
+//! Minification strips what editors leave behind (comments, `<metadata>`
+//! and the Inkscape, Sodipodi, Illustrator and Sketch elements and
+//! attributes), unwraps groups that do nothing, and rounds coordinates to
+//! `svg.precision` decimals. The result is parsed again before it is used;
+//! if that fails, the original markup is written instead.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{self, fontdb, roxmltree};
+use serde::{Deserialize, Serialize};
+
+use crate::color::ColorReport;
+use crate::convert::{
+    self, Conversion, ConvertError, ConvertOptions, Encoded, OutputFormat, OutputSize,
+};
+use crate::metadata::{MetadataField, MetadataOptions};
+use crate::recompress;
+
+/// Longest side an SVG is rendered at when it is decoded as a whole, e.g.
+/// for the vision model. Vector art has no natural pixel size, and icons
+/// drawn at 24px would be too small to describe.
+pub const DECODE_SIZE: u32 = 1024;
+
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+
+/// Namespaces only the editor that wrote the file reads. Illustrator's
+/// private data alone can be larger than the drawing.
+const EDITOR_NAMESPACES: &[&str] = &[
+    "http://www.inkscape.org/namespaces/inkscape",
+    "http://sodipodi.sourceforge.net/DTD/sodipodi-0.dtd",
+    "http://ns.adobe.com/AdobeIllustrator/10.0/",
+    "http://ns.adobe.com/AdobeSVGViewerExtensions/3.0/",
+    "http://ns.adobe.com/Extensibility/1.0/",
+    "http://ns.adobe.com/Flows/1.0/",
+    "http://ns.adobe.com/GenericCustomNamespace/1.0/",
+    "http://ns.adobe.com/Graphs/1.0/",
+    "http://ns.adobe.com/ImageReplacement/1.0/",
+    "http://ns.adobe.com/SaveForWeb/1.0/",
+    "http://ns.adobe.com/Variables/1.0/",
+    "http://www.bohemiancoding.com/sketch/ns",
+    "http://www.serif.com/",
+];
+
+/// Elements whose whitespace is part of their content.
+const TEXT_ELEMENTS: &[&str] = &["text", "style", "script", "title", "desc", "foreignObject"];
+
+/// Attributes holding plain numbers or lists of them, which are rounded.
+const NUMERIC_ATTRIBUTES: &[&str] = &[
+    "x",
+    "y",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "fx",
+    "fy",
+    "dx",
+    "dy",
+    "width",
+    "height",
+    "points",
+    "viewBox",
+    "transform",
+    "gradientTransform",
+    "patternTransform",
+    "stroke-width",
+    "stroke-dasharray",
+    "stroke-dashoffset",
+];
+
+/// Attributes a group passes on to its children. They can be moved onto
+/// the only child of a group, where the child's own value still wins.
+const INHERITED_ATTRIBUTES: &[&str] = &[
+    "clip-rule",
+    "color",
+    "fill",
+    "fill-opacity",
+    "fill-rule",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-weight",
+    "stroke",
+    "stroke-dasharray",
+    "stroke-dashoffset",
+    "stroke-linecap",
+    "stroke-linejoin",
+    "stroke-miterlimit",
+    "stroke-opacity",
+    "stroke-width",
+    "text-anchor",
+    "visibility",
+];
+
+/// Elements a group's attributes can be moved onto.
+const GRAPHICS_ELEMENTS: &[&str] = &[
+    "circle", "ellipse", "g", "image", "line", "path", "polygon", "polyline", "rect", "text", "use",
+];
+
+/// Properties in `<metadata>` that name the rights holder.
+const RIGHTS_PROPERTIES: &[&str] = &["creator", "license", "rights", "UsageTerms", "WebStatement"];
+
+/// SVG settings, per job (`svg` in `config.yaml`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SvgOptions {
+    /// Write a minified copy instead of the original markup. The job's
+    /// metadata policy only applies to minified copies.
+    pub minify: bool,
+    /// Decimal places coordinates are rounded to when minifying.
+    pub precision: u8,
+    /// Widths raster copies are rendered at, written as `name-1200w.png`;
+    /// none by default. Unlike photos, SVGs are scaled up as well as down.
+    pub widths: Vec<u32>,
+    /// Formats of the raster copies: WebP, PNG or both.
+    pub formats: Vec<OutputFormat>,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            minify: true,
+            precision: 3,
+            widths: Vec::new(),
+            formats: vec![OutputFormat::Webp, OutputFormat::Png],
+        }
+    }
+}
+
+/// Returns true if `header`, the start of a file, is SVG markup: its first
+/// element is `<svg>`, after any XML declaration, doctype and comments, and
+/// it declares the SVG namespace.
+pub fn is_svg(header: &[u8]) -> bool {
+    let header = header.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(header);
+    if header
+        .first()
+        .is_some_and(|&byte| byte != b'<' && !byte.is_ascii_whitespace())
+        || !header
+            .windows(SVG_NAMESPACE.len())
+            .any(|window| window == SVG_NAMESPACE.as_bytes())
+    {
+        return false;
+    }
+    let mut rest = header;
+    while let Some(start) = rest.iter().position(|&byte| byte == b'<') {
+        rest = &rest[start + 1..];
+        match rest.first() {
+            Some(b'?' | b'!') => continue,
+            _ => {
+                let name = rest.strip_prefix(b"svg:").unwrap_or(rest);
+                return name.starts_with(b"svg")
+                    && name.get(3).is_none_or(|&byte| {
+                        byte == b'>' || byte == b'/' || byte.is_ascii_whitespace()
+                    });
+            }
+        }
+    }
+    false
+}
+
+/// Fonts for `<text>` elements. Loading them takes a while, so it is done
+/// once, when the first SVG is parsed.
+fn fonts() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = fontdb::Database::new();
+            fonts.load_system_fonts();
+            Arc::new(fonts)
+        })
+        .clone()
+}
+
+/// Parses the SVG `data` read from `path`. Images it links to are looked up
+/// next to it.
+fn parse(data: &[u8], path: &Path) -> Result<usvg::Tree, ConvertError> {
+    let options = usvg::Options {
+        resources_dir: path.parent().map(Path::to_path_buf),
+        fontdb: fonts(),
+        ..usvg::Options::default()
+    };
+    usvg::Tree::from_data(data, &options)
+        .map_err(|e| ConvertError::Render(format!("not a valid SVG: {e}")))
+}
+
+/// The size the SVG is drawn at, in whole pixels.
+fn intrinsic_size(tree: &usvg::Tree) -> (u32, u32) {
+    let size = tree.size();
+    let round = |n: f32| (n.round() as u32).max(1);
+    (round(size.width()), round(size.height()))
+}
+
+/// Renders `tree` stretched to `width`x`height`.
+fn render(tree: &usvg::Tree, width: u32, height: u32) -> Result<RgbaImage, ConvertError> {
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| ConvertError::Render(format!("{width}x{height} is too large")))?;
+    let size = tree.size();
+    let transform =
+        Transform::from_scale(width as f32 / size.width(), height as f32 / size.height());
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+    let mut image = RgbaImage::new(width, height);
+    for (pixel, rendered) in image.pixels_mut().zip(pixmap.pixels()) {
+        let color = rendered.demultiply();
+        *pixel = Rgba([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+    Ok(image)
+}
+
+/// Renders the SVG `data` read from `path` with its longest side at
+/// [`DECODE_SIZE`].
+pub fn decode(data: &[u8], path: &Path) -> Result<DynamicImage, ConvertError> {
+    let tree = parse(data, path)?;
+    let (width, height) = scale_to_fit(intrinsic_size(&tree), DECODE_SIZE);
+    Ok(DynamicImage::ImageRgba8(render(&tree, width, height)?))
+}
+
+/// The intrinsic size of the SVG at `path`.
+pub fn dimensions(path: &Path) -> Result<(u32, u32), ConvertError> {
+    Ok(intrinsic_size(&parse(&fs::read(path)?, path)?))
+}
+
+fn scale_to_fit((width, height): (u32, u32), longest: u32) -> (u32, u32) {
+    let ratio = f64::from(longest) / f64::from(width.max(height));
+    let scale = |n: u32| ((f64::from(n) * ratio).round() as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// The outputs written for an SVG of `width`x`height`: the SVG itself,
+/// then every raster format at each width in ascending order.
+fn output_sizes(
+    (width, height): (u32, u32),
+    options: &SvgOptions,
+) -> Vec<(OutputFormat, OutputSize)> {
+    let mut outputs = vec![(
+        OutputFormat::Svg,
+        OutputSize {
+            ladder: None,
+            width,
+            height,
+        },
+    )];
+    let mut widths = options.widths.clone();
+    widths.sort_unstable();
+    widths.dedup();
+    for ladder in widths.into_iter().filter(|&w| w > 0) {
+        let size = OutputSize {
+            ladder: Some(ladder),
+            width: ladder,
+            height: ((f64::from(ladder) * f64::from(height) / f64::from(width)).round() as u32)
+                .max(1),
+        };
+        for &format in &options.formats {
+            outputs.push((format, size));
+        }
+    }
+    outputs
+}
+
+/// The outputs [`convert`] would write for the SVG at `path`.
+pub fn planned_outputs(
+    path: &Path,
+    options: &SvgOptions,
+) -> Result<Vec<(OutputFormat, OutputSize)>, ConvertError> {
+    Ok(output_sizes(dimensions(path)?, options))
+}
+
+/// Writes the SVG at `src` back as SVG, minified if `options.svg` asks for
+/// it, and renders its raster copies.
+pub fn convert(src: &Path, options: &ConvertOptions) -> Result<Conversion, ConvertError> {
+    let data = fs::read(src)?;
+    let tree = parse(&data, src)?;
+    let mut metadata_removed = BTreeSet::new();
+    let mut outputs = Vec::new();
+    for (format, size) in output_sizes(intrinsic_size(&tree), &options.svg) {
+        let bytes = match format {
+            OutputFormat::Svg if options.svg.minify => {
+                match minify(&data, options.svg.precision, &options.metadata) {
+                    Some((minified, removed)) if parse(minified.as_bytes(), src).is_ok() => {
+                        metadata_removed = removed;
+                        minified.into_bytes()
+                    }
+                    _ => data.clone(),
+                }
+            }
+            OutputFormat::Svg => data.clone(),
+            OutputFormat::Png => {
+                let img = DynamicImage::ImageRgba8(render(&tree, size.width, size.height)?);
+                recompress::encode(&img, &options.png, false)?
+            }
+            format => {
+                let img = DynamicImage::ImageRgba8(render(&tree, size.width, size.height)?);
+                convert::encode(&img, format, options)?
+            }
+        };
+        outputs.push(Encoded {
+            format,
+            page: None,
+            size,
+            bytes,
+        });
+    }
+    Ok(Conversion {
+        outputs,
+        metadata_removed,
+        color: ColorReport::default(),
+        frames: None,
+        png_saved: None,
+    })
+}
+
+/// Minifies the SVG `data`, rounding coordinates to `precision` decimals,
+/// and returns the markup and the metadata fields `policy` had removed.
+/// `None` if it is not well-formed XML, e.g. a compressed SVGZ.
+fn minify(
+    data: &[u8],
+    precision: u8,
+    policy: &MetadataOptions,
+) -> Option<(String, BTreeSet<MetadataField>)> {
+    let text = std::str::from_utf8(data).ok()?;
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..roxmltree::ParsingOptions::default()
+    };
+    let document = roxmltree::Document::parse_with_options(text, options).ok()?;
+    let mut minifier = Minifier {
+        precision: precision.min(8),
+        policy,
+        // Selectors like `g > path` would match differently once groups
+        // are unwrapped.
+        collapse_groups: !document
+            .descendants()
+            .any(|node| is_svg_element(node, "style")),
+        removed: BTreeSet::new(),
+        out: String::with_capacity(text.len()),
+    };
+    minifier.element(document.root_element(), Vec::new());
+    Some((minifier.out, minifier.removed))
+}
+
+fn is_svg_element(node: roxmltree::Node, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some(SVG_NAMESPACE)
+        && node.tag_name().name() == name
+}
+
+fn is_editor_namespace(namespace: Option<&str>) -> bool {
+    namespace.is_some_and(|namespace| EDITOR_NAMESPACES.contains(&namespace))
+}
+
+struct Minifier<'a> {
+    precision: u8,
+    policy: &'a MetadataOptions,
+    collapse_groups: bool,
+    removed: BTreeSet<MetadataField>,
+    out: String,
+}
+
+impl Minifier<'_> {
+    /// Writes `node`, with `inherited` attributes taken over from groups
+    /// around it that were unwrapped.
+    fn element(&mut self, node: roxmltree::Node, inherited: Vec<(String, String)>) {
+        if is_editor_namespace(node.tag_name().namespace()) || !self.keep_metadata(node) {
+            return;
+        }
+        let attributes = self.attributes(node, inherited);
+        let children: Vec<roxmltree::Node> = node
+            .children()
+            .filter(|child| child.is_element() || (child.is_text() && !self.drops_text(*child)))
+            .collect();
+
+        if self.collapse_groups && is_svg_element(node, "g") {
+            if attributes.is_empty() {
+                for child in children {
+                    self.child(child);
+                }
+                return;
+            }
+            if let [child] = children[..] {
+                if can_take_attributes(child)
+                    && attributes.iter().all(|(name, _)| {
+                        name == "transform" || INHERITED_ATTRIBUTES.contains(&name.as_str())
+                    })
+                {
+                    self.element(child, attributes);
+                    return;
+                }
+            }
+        }
+
+        let name = qualified_name(
+            node,
+            node.tag_name().namespace(),
+            node.tag_name().name(),
+            false,
+        );
+        self.out.push('<');
+        self.out.push_str(&name);
+        let parent_namespaces: Vec<(Option<&str>, &str)> = node
+            .parent_element()
+            .map(|parent| {
+                parent
+                    .namespaces()
+                    .map(|ns| (ns.name(), ns.uri()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for namespace in node.namespaces() {
+            if parent_namespaces.contains(&(namespace.name(), namespace.uri()))
+                || is_editor_namespace(Some(namespace.uri()))
+                || namespace.uri() == roxmltree::NS_XML_URI
+            {
+                continue;
+            }
+            match namespace.name() {
+                Some(prefix) => self.attribute(&format!("xmlns:{prefix}"), namespace.uri()),
+                None => self.attribute("xmlns", namespace.uri()),
+            }
+        }
+        for (name, value) in &attributes {
+            self.attribute(name, value);
+        }
+        if children.is_empty() {
+            self.out.push_str("/>");
+            return;
+        }
+        self.out.push('>');
+        for child in children {
+            self.child(child);
+        }
+        self.out.push_str("</");
+        self.out.push_str(&name);
+        self.out.push('>');
+    }
+
+    fn child(&mut self, node: roxmltree::Node) {
+        if node.is_element() {
+            self.element(node, Vec::new());
+        } else if let Some(text) = node.text() {
+            self.out.push_str(&escape(text, false));
+        }
+    }
+
+    /// The attributes written for `node`: its own, minus editor ones and
+    /// with numbers rounded, merged with `inherited`.
+    fn attributes(
+        &self,
+        node: roxmltree::Node,
+        inherited: Vec<(String, String)>,
+    ) -> Vec<(String, String)> {
+        let mut attributes: Vec<(String, String)> = node
+            .attributes()
+            .filter(|attribute| !is_editor_namespace(attribute.namespace()))
+            .map(|attribute| {
+                let name = qualified_name(node, attribute.namespace(), attribute.name(), true);
+                let value = match (attribute.namespace(), attribute.name()) {
+                    (None, "d") => minify_path(attribute.value(), self.precision)
+                        .unwrap_or_else(|| attribute.value().to_string()),
+                    (None, name) if NUMERIC_ATTRIBUTES.contains(&name) => {
+                        round_numbers(attribute.value(), self.precision)
+                    }
+                    _ => attribute.value().to_string(),
+                };
+                (name, value)
+            })
+            .collect();
+        for (name, value) in inherited {
+            match attributes.iter_mut().find(|(own, _)| *own == name) {
+                // The group's transform applies before the child's own.
+                Some((_, own)) if name == "transform" => *own = format!("{value} {own}"),
+                Some(_) => {}
+                None => attributes.push((name, value)),
+            }
+        }
+        attributes
+    }
+
+    fn attribute(&mut self, name: &str, value: &str) {
+        self.out.push(' ');
+        self.out.push_str(name);
+        self.out.push_str("=\"");
+        self.out.push_str(&escape(value, true));
+        self.out.push('"');
+    }
+
+    /// Whitespace between elements is dropped, except where it is part of
+    /// text content.
+    fn drops_text(&self, node: roxmltree::Node) -> bool {
+        let whitespace = node.text().is_some_and(|text| text.trim().is_empty());
+        whitespace
+            && !node.ancestors().any(|ancestor| {
+                TEXT_ELEMENTS
+                    .iter()
+                    .any(|name| is_svg_element(ancestor, name))
+                    || ancestor.attribute((roxmltree::NS_XML_URI, "space")) == Some("preserve")
+            })
+    }
+
+    /// `<metadata>` is editor output unless it names the rights holder; then
+    /// it is kept if the policy keeps copyright.
+    fn keep_metadata(&mut self, node: roxmltree::Node) -> bool {
+        if !is_svg_element(node, "metadata") {
+            return true;
+        }
+        let has_rights = node
+            .descendants()
+            .any(|node| node.is_element() && RIGHTS_PROPERTIES.contains(&node.tag_name().name()));
+        if !has_rights {
+            return false;
+        }
+        let keep = self.policy.keeps(MetadataField::Copyright);
+        if !keep {
+            self.removed.insert(MetadataField::Copyright);
+        }
+        keep
+    }
+}
+
+/// A group's attributes can be moved onto `child` if it is drawn directly
+/// and nothing refers to it, since a `<use>` of it would pick them up.
+fn can_take_attributes(child: roxmltree::Node) -> bool {
+    child.tag_name().namespace() == Some(SVG_NAMESPACE)
+        && GRAPHICS_ELEMENTS.contains(&child.tag_name().name())
+        && child.attribute("id").is_none()
+}
+
+/// `name` in `namespace` as written in `node`'s scope. Attributes cannot
+/// use the default namespace, so they get a declared prefix.
+fn qualified_name(
+    node: roxmltree::Node,
+    namespace: Option<&str>,
+    name: &str,
+    attribute: bool,
+) -> String {
+    let Some(uri) = namespace else {
+        return name.to_string();
+    };
+    let prefix = if attribute {
+        if uri == roxmltree::NS_XML_URI {
+            Some("xml")
+        } else {
+            node.namespaces()
+                .find(|ns| ns.uri() == uri && ns.name().is_some())
+                .and_then(|ns| ns.name())
+        }
+    } else {
+        node.lookup_prefix(uri)
+    };
+    match prefix {
+        Some(prefix) => format!("{prefix}:{name}"),
+        None => name.to_string(),
+    }
+}
+
+fn escape(text: &str, attribute: bool) -> String {
+    let text = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    if attribute {
+        text.replace('"', "&quot;")
+    } else {
+        text
+    }
+}
+
+/// `value` rounded to `precision` decimals, without trailing zeros or a
+/// leading zero before the point.
+fn format_number(value: f64, precision: u8) -> String {
+    let factor = 10f64.powi(i32::from(precision));
+    let rounded = (value * factor).round() / factor;
+    let mut text = format!("{rounded:.*}", usize::from(precision));
+    if text.contains('.') {
+        text.truncate(text.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    if text == "-0" {
+        text = "0".to_string();
+    }
+    if let Some(fraction) = text.strip_prefix("0.") {
+        text = format!(".{fraction}");
+    } else if let Some(fraction) = text.strip_prefix("-0.") {
+        text = format!("-.{fraction}");
+    }
+    text
+}
+
+/// Reads a number at `pos`: an optional sign, digits with an optional
+/// point, and an optional exponent. Returns it and the position after it.
+fn read_number(bytes: &[u8], mut pos: usize) -> Option<(f64, usize)> {
+    let start = pos;
+    if matches!(bytes.get(pos), Some(b'+' | b'-')) {
+        pos += 1;
+    }
+    let digits = |pos: &mut usize| {
+        let from = *pos;
+        while bytes.get(*pos).is_some_and(u8::is_ascii_digit) {
+            *pos += 1;
+        }
+        *pos > from
+    };
+    let mut mantissa = digits(&mut pos);
+    if bytes.get(pos) == Some(&b'.') {
+        pos += 1;
+        mantissa |= digits(&mut pos);
+    }
+    if !mantissa {
+        return None;
+    }
+    if matches!(bytes.get(pos), Some(b'e' | b'E')) {
+        let mut exponent = pos + 1;
+        if matches!(bytes.get(exponent), Some(b'+' | b'-')) {
+            exponent += 1;
+        }
+        if digits(&mut exponent) {
+            pos = exponent;
+        }
+    }
+    let number = std::str::from_utf8(&bytes[start..pos]).ok()?.parse().ok()?;
+    Some((number, pos))
+}
+
+/// Rounds every number in `value`, keeping everything around them, such
+/// as units and function names.
+fn round_numbers(value: &str, precision: u8) -> String {
+    let bytes = value.as_bytes();
+    let mut out = String::with_capacity(value.len());
+    let mut pos = 0;
+    let mut after_number = false;
+    while pos < bytes.len() {
+        let starts_number = match bytes[pos] {
+            b'0'..=b'9' | b'.' | b'+' | b'-' => {
+                // Letters followed by digits, e.g. in `url(#a1)`, are names.
+                !out.ends_with(|c: char| c.is_ascii_alphabetic() || c == '#' || c == '_')
+            }
+            _ => false,
+        };
+        if let Some((number, end)) = starts_number.then(|| read_number(bytes, pos)).flatten() {
+            let text = format_number(number, precision);
+            // Numbers can run into each other, e.g. `1.5.5`; rounding could
+            // merge them.
+            if after_number && !text.starts_with('-') {
+                out.push(' ');
+            }
+            out.push_str(&text);
+            after_number = true;
+            pos = end;
+        } else {
+            let c = value[pos..].chars().next().unwrap_or_default();
+            out.push(c);
+            after_number = false;
+            pos += c.len_utf8();
+        }
+    }
+    out
+}
+
+/// Path data with every coordinate rounded and the whitespace reduced, or
+/// `None` if it cannot be parsed. Arc flags are single digits that may run
+/// into the next number, which is why path data gets a parser of its own.
+///
+/// Relative coordinates are rounded as the absolute positions they lead
+/// to, less the rounded position they start from, so rounding errors do
+/// not add up along the path.
+fn minify_path(d: &str, precision: u8) -> Option<String> {
+    let bytes = d.as_bytes();
+    let mut out = String::with_capacity(d.len());
+    let mut pos = 0;
+    let mut command = None;
+    let skip_separators = |pos: &mut usize| {
+        while bytes
+            .get(*pos)
+            .is_some_and(|&byte| byte.is_ascii_whitespace() || byte == b',')
+        {
+            *pos += 1;
+        }
+    };
+    let rounded = |value: f64| {
+        let text = format_number(value, precision);
+        let value = text.parse().unwrap_or(value);
+        (text, value)
+    };
+    // The current point and the start of the subpath, as given and as
+    // written.
+    let mut current = ([0.0; 2], [0.0; 2]);
+    let mut subpath = current;
+    let mut last = String::new();
+    loop {
+        skip_separators(&mut pos);
+        let Some(&byte) = bytes.get(pos) else {
+            break;
+        };
+        let explicit = byte.is_ascii_alphabetic();
+        if explicit {
+            command = Some(byte);
+            out.push(byte as char);
+            last.clear();
+            pos += 1;
+        }
+        let command = command?;
+        let relative = command.is_ascii_lowercase();
+        let lower = command.to_ascii_lowercase();
+        let arguments = match lower {
+            b'z' => 0,
+            b'h' | b'v' => 1,
+            b'm' | b'l' | b't' => 2,
+            b's' | b'q' => 4,
+            b'c' => 6,
+            b'a' => 7,
+            _ => return None,
+        };
+        if arguments == 0 {
+            // `z` takes no arguments, so it cannot be repeated.
+            if !explicit {
+                return None;
+            }
+            current = subpath;
+            continue;
+        }
+        // Every coordinate of a segment is relative to where it starts.
+        let (start, start_written) = current;
+        for argument in 0..arguments {
+            skip_separators(&mut pos);
+            let axis = match (lower, argument) {
+                (b'a', 3 | 4) => {
+                    let flag = *bytes
+                        .get(pos)
+                        .filter(|&&flag| flag == b'0' || flag == b'1')?;
+                    pos += 1;
+                    let text = (flag as char).to_string();
+                    push_path_number(&mut out, &last, &text);
+                    last = text;
+                    continue;
+                }
+                (b'a', 5 | 6) => Some(argument - 5),
+                (b'a', _) => None,
+                (b'h', _) => Some(0),
+                (b'v', _) => Some(1),
+                _ => Some(argument % 2),
+            };
+            let (number, end) = read_number(bytes, pos)?;
+            pos = end;
+            let text = match axis {
+                Some(axis) if relative => {
+                    let target = start[axis] + number;
+                    let (_, written) = rounded(target);
+                    let (text, delta) = rounded(written - start_written[axis]);
+                    current.0[axis] = target;
+                    current.1[axis] = start_written[axis] + delta;
+                    text
+                }
+                Some(axis) => {
+                    let (text, written) = rounded(number);
+                    current.0[axis] = number;
+                    current.1[axis] = written;
+                    text
+                }
+                None => format_number(number, precision),
+            };
+            push_path_number(&mut out, &last, &text);
+            last = text;
+        }
+        if explicit && lower == b'm' {
+            subpath = current;
+        }
+    }
+    Some(out)
+}
+
+/// Appends a path argument, separated from the `last` one only where they
+/// would otherwise run together.
+fn push_path_number(out: &mut String, last: &str, text: &str) {
+    let joins = last.is_empty()
+        || text.starts_with('-')
+        || (text.starts_with('.') && last.contains('.') && !last.contains(['e', 'E']));
+    if !joins {
+        out.push(' ');
+    }
+    out.push_str(text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The numbers in `d`, in order.
+    fn numbers(d: &str) -> Vec<f64> {
+        let bytes = d.as_bytes();
+        let mut numbers = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            match read_number(bytes, pos) {
+                Some((number, end)) => {
+                    numbers.push(number);
+                    pos = end;
+                }
+                None => pos += 1,
+            }
+        }
+        numbers
+    }
+
+    #[test]
+    fn formats_numbers() {
+        assert_eq!(format_number(10.0, 3), "10");
+        assert_eq!(format_number(0.5, 3), ".5");
+        assert_eq!(format_number(-0.25, 3), "-.25");
+        assert_eq!(format_number(1.23456, 3), "1.235");
+        assert_eq!(format_number(-0.0004, 3), "0");
+        assert_eq!(format_number(1e-3, 3), ".001");
+        assert_eq!(format_number(12.5, 0), "13");
+    }
+
+    #[test]
+    fn rounds_numbers_in_attributes() {
+        let cases = [
+            ("translate(10.123456 -0.0004)", "translate(10.12 0)"),
+            ("matrix(1,0,0,1,0.5,-3.999)", "matrix(1,0,0,1,.5,-4)"),
+            ("0.50px", ".5px"),
+            ("1e-3 2E2", "0 200"),
+            ("url(#a1)", "url(#a1)"),
+            ("font_2.33", "font_2.33"),
+            // Numbers that ran together stay apart.
+            ("1.5.5", "1.5 .5"),
+            ("1.5-2", "1.5-2"),
+            ("√2", "√2"),
+        ];
+        for (value, rounded) in cases {
+            assert_eq!(round_numbers(value, 2), rounded, "{value}");
+        }
+    }
+
+    #[test]
+    fn minifies_path_data() {
+        let cases = [
+            ("M 10.0001,20 L 30 , 40 Z", "M10 20L30 40Z"),
+            ("M0,0 L-1.5,-2.5 .5,.5", "M0 0L-1.5-2.5.5.5"),
+            ("M0 0H10.004V-.001", "M0 0H10V0"),
+            // Compact arc flags run into the next coordinate.
+            ("M0 0a5 5 0 1010 10", "M0 0a5 5 0 1 0 10 10"),
+            ("M0 0A5.123 5 30.004 0,1 1e1 10", "M0 0A5.12 5 30 0 1 10 10"),
+            ("M0 0c1 1 2 2 3 3s4 4 5 5", "M0 0c1 1 2 2 3 3s4 4 5 5"),
+        ];
+        for (d, minified) in cases {
+            assert_eq!(minify_path(d, 2).as_deref(), Some(minified), "{d}");
+        }
+        for d in ["M0 0Z 1", "X1", "M0", "M0 0a5 5 0 2 0 10 10", "1 2"] {
+            assert_eq!(minify_path(d, 2), None, "{d}");
+        }
+    }
+
+    #[test]
+    fn relative_rounding_does_not_accumulate() {
+        // Each step rounds to nothing on its own.
+        let d = format!("m0 0{}", "l.004 .004".repeat(1000));
+        let minified = minify_path(&d, 2).unwrap();
+        let sum: f64 = numbers(&minified).iter().step_by(2).sum();
+        assert!((sum - 4.0).abs() < 0.005, "{sum}: {minified}");
+
+        // Control points are relative to the start of their segment, and
+        // `z` returns to the start of the subpath.
+        let minified = minify_path("m.3 .3c.3 .3 .3 .3 .3 .3h.3zl.3 .3", 0).unwrap();
+        assert_eq!(minified, "m0 0c1 1 1 1 1 1h0zl1 1");
+    }
+
+    #[test]
+    fn recognises_svg_files() {
+        let namespace = r#"xmlns="http://www.w3.org/2000/svg""#;
+        assert!(is_svg(format!("<svg {namespace}/>").as_bytes()));
+        assert!(is_svg(
+            format!("\u{feff}<?xml version=\"1.0\"?>\n<!-- x --><svg {namespace}>").as_bytes()
+        ));
+        assert!(!is_svg(b"<svg>"));
+        assert!(!is_svg(format!("<html {namespace}><svg>").as_bytes()));
+        assert!(!is_svg(format!("<svgfoo {namespace}>").as_bytes()));
+        assert!(!is_svg(format!("text <svg {namespace}>").as_bytes()));
+    }
+}